@@ -0,0 +1,188 @@
+use super::config::AuthConfig;
+use super::error::{AuthError, SessionError};
+use super::session::{self, Viewer};
+use actix_web::http::HeaderValue;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+// What a request is allowed to do. Keys are given the scopes they need, eg. the bot gets
+// write:haikus while the website only gets read.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "write:haikus")]
+    WriteHaikus,
+    // Allowed to do anything
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::WriteHaikus => write!(f, "write:haikus"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+pub fn allows(scopes: &[Scope], scope: Scope) -> bool {
+    scopes.contains(&Scope::Admin) || scopes.contains(&scope)
+}
+
+// Keys are only stored as the hex encoded SHA-256 of the key, so the config doesn't hold anything
+// which could be used to make requests. Keys are long and random, so they don't need salting or
+// a slow hash like passwords do.
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Who made a request and what they're allowed to do
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub scopes: Vec<Scope>,
+    // Set when the request was made with a Discord session rather than an API key
+    pub viewer: Option<Viewer>,
+}
+
+// Finds the caller from the request's Authorization header, which should hold either a bearer API
+// key or a session token from logging in with Discord. Requests without the header get the
+// anonymous scopes.
+pub fn authenticate(
+    config: &AuthConfig,
+    header: Option<&HeaderValue>,
+) -> Result<Caller, AuthError> {
+    let header = match header {
+        Some(header) => header.to_str().map_err(|_| AuthError::MalformedHeader)?,
+        None => {
+            return Ok(Caller {
+                scopes: config.anonymous_scopes.clone(),
+                viewer: None,
+            })
+        }
+    };
+    let key = header
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .ok_or(AuthError::MalformedHeader)?;
+    if session::is_token(key) {
+        let discord = config
+            .discord
+            .as_ref()
+            .ok_or(AuthError::Session(SessionError::Disabled))?;
+        let viewer = session::verify(&discord.session_secret, key, Utc::now().timestamp())
+            .map_err(AuthError::Session)?;
+        // Viewers can read anything, apart from servers they aren't a member of
+        return Ok(Caller {
+            scopes: vec![Scope::Read],
+            viewer: Some(viewer),
+        });
+    }
+    let hash = hash_key(key);
+    config
+        .api_keys
+        .iter()
+        .find(|api_key| api_key.hash.eq_ignore_ascii_case(&hash))
+        .map(|api_key| {
+            debug!("Authenticated with API key {}", api_key.name);
+            Caller {
+                scopes: api_key.scopes.clone(),
+                viewer: None,
+            }
+        })
+        .ok_or(AuthError::UnknownKey)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::config::{ApiKeyConfig, DiscordConfig};
+    use super::*;
+    use rstest::rstest;
+
+    const SESSION_SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn auth_config() -> AuthConfig {
+        AuthConfig {
+            anonymous_scopes: vec![Scope::Read],
+            api_keys: vec![ApiKeyConfig {
+                name: "bot".to_owned(),
+                hash: hash_key("bot-key"),
+                scopes: vec![Scope::Read, Scope::WriteHaikus],
+            }],
+            discord: Some(DiscordConfig {
+                session_secret: SESSION_SECRET.to_owned(),
+                ..DiscordConfig::default()
+            }),
+        }
+    }
+
+    fn viewer() -> Viewer {
+        Viewer {
+            discord_snowflake: "3".to_owned(),
+            guilds: vec!["1".to_owned()],
+        }
+    }
+
+    #[test]
+    fn hash_keys() {
+        assert_eq!(
+            hash_key("bot-key"),
+            "3a1fb6628e3632ba5935f8317570b197c8b3d576dd619f1c6fd917efc3fa6366"
+        );
+    }
+
+    #[rstest(
+        header,
+        expected,
+        case(None, Ok(vec![Scope::Read])),
+        case(Some("Bearer bot-key"), Ok(vec![Scope::Read, Scope::WriteHaikus])),
+        case(Some("Bearer other-key"), Err(AuthError::UnknownKey)),
+        case(Some("Basic Ym90LWtleQ=="), Err(AuthError::MalformedHeader)),
+        case(Some("Bearer "), Err(AuthError::MalformedHeader)),
+        case(Some("Bearer session.e30.e30"), Err(AuthError::Session(SessionError::InvalidSignature)))
+    )]
+    fn authenticate_requests(header: Option<&str>, expected: Result<Vec<Scope>, AuthError>) {
+        let header = header.map(|header| HeaderValue::from_str(header).unwrap());
+        assert_eq!(
+            authenticate(&auth_config(), header.as_ref()).map(|caller| caller.scopes),
+            expected
+        );
+    }
+
+    #[test]
+    fn authenticate_sessions() {
+        let token = session::issue(SESSION_SECRET, &viewer(), Utc::now().timestamp() + 60);
+        let header = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+        assert_eq!(
+            authenticate(&auth_config(), Some(&header)),
+            Ok(Caller {
+                scopes: vec![Scope::Read],
+                viewer: Some(viewer()),
+            })
+        );
+        let config = AuthConfig {
+            discord: None,
+            ..auth_config()
+        };
+        assert_eq!(
+            authenticate(&config, Some(&header)),
+            Err(AuthError::Session(SessionError::Disabled))
+        );
+    }
+
+    #[test]
+    fn check_scopes() {
+        assert!(allows(&[Scope::Read], Scope::Read));
+        assert!(!allows(&[Scope::Read], Scope::WriteHaikus));
+        assert!(allows(&[Scope::Admin], Scope::WriteHaikus));
+        assert!(!allows(&[], Scope::Read));
+    }
+}
@@ -0,0 +1,608 @@
+use super::auth::Scope;
+use super::error::ConfigError;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_LOG_FILTER: &str = "actix_web=info,haikubot_rs_api";
+
+// Command line flags, each of which can also be given as an environment variable. These override
+// the config file, which in turn overrides the defaults.
+#[derive(Debug, Default, StructOpt)]
+#[structopt(
+    name = "haikubot-rs-api",
+    about = "GraphQL API for haikus found by haikubot"
+)]
+pub struct Options {
+    /// TOML file to read configuration from
+    #[structopt(long, env = "HAIKUBOT_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[structopt(long, env = "HAIKUBOT_HOST")]
+    pub host: Option<String>,
+    /// Port to listen on
+    #[structopt(long, env = "HAIKUBOT_PORT")]
+    pub port: Option<u16>,
+    /// URL the API is reached at, used by GraphiQL
+    #[structopt(long, env = "HAIKUBOT_PUBLIC_URL")]
+    pub public_url: Option<String>,
+    /// Storage backend, either dgraph or memory
+    #[structopt(long, env = "HAIKUBOT_STORAGE")]
+    pub storage: Option<StorageBackend>,
+    /// RDF file to seed memory storage from
+    #[structopt(long, env = "HAIKUBOT_SEED_DATA", parse(from_os_str))]
+    pub seed_data: Option<PathBuf>,
+    /// Comma separated Dgraph alpha gRPC endpoints, eg. 127.0.0.1:9080
+    #[structopt(long, env = "HAIKUBOT_DGRAPH_ENDPOINTS", use_delimiter = true)]
+    pub dgraph_endpoints: Vec<String>,
+    /// Log filter, eg. actix_web=info,haikubot_rs_api=debug
+    #[structopt(long, env = "HAIKUBOT_LOG_FILTER")]
+    pub log_filter: Option<String>,
+    /// Whether to serve GraphiQL at /graphiql
+    #[structopt(long, env = "HAIKUBOT_GRAPHIQL")]
+    pub graphiql: Option<bool>,
+    /// Whether to allow mutations such as addHaiku
+    #[structopt(long, env = "HAIKUBOT_MUTATIONS")]
+    pub mutations: Option<bool>,
+    /// JSON file mapping the SHA-256 hashes of persisted queries to their text
+    #[structopt(long, env = "HAIKUBOT_PERSISTED_QUERIES", parse(from_os_str))]
+    pub persisted_queries: Option<PathBuf>,
+    /// Whether to reject queries which aren't in the persisted queries file
+    #[structopt(long, env = "HAIKUBOT_ONLY_PERSISTED_QUERIES")]
+    pub only_persisted_queries: Option<bool>,
+    /// Print the resulting configuration as TOML and exit
+    #[structopt(long)]
+    pub print_config: bool,
+    /// Read an API key from stdin and print the hash to put in the config, then exit
+    #[structopt(long)]
+    pub hash_api_key: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Dgraph,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "dgraph" => Ok(StorageBackend::Dgraph),
+            "memory" => Ok(StorageBackend::Memory),
+            other => Err(format!("Unknown storage backend: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    // Defaults to http://host:port
+    pub public_url: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_owned(),
+            port: 4000,
+            public_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub seed_data: Option<PathBuf>,
+    // Requests are spread across the alphas in turn, moving on to the next one when retrying
+    pub dgraph_endpoints: Vec<String>,
+    // The deadline for each request to an alpha
+    pub dgraph_timeout_ms: u64,
+    // How many times a query is retried after a transient failure, with the backoff doubling
+    // between each attempt. Mutations are never retried as they may have been applied.
+    pub dgraph_retries: u32,
+    pub dgraph_retry_backoff_ms: u64,
+    // How many requests in a row must fail before the circuit breaker opens, and how long it
+    // stays open before letting requests through again
+    pub dgraph_breaker_failures: u32,
+    pub dgraph_breaker_cooldown_ms: u64,
+    // How many Dgraph query results are kept, dropping the least recently used, and for how long.
+    // Results are also dropped when a haiku is added which could change them. 0 disables caching.
+    pub cache_entries: usize,
+    pub cache_ttl_ms: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Dgraph,
+            seed_data: None,
+            dgraph_endpoints: vec!["127.0.0.1:9080".to_owned()],
+            dgraph_timeout_ms: 5000,
+            dgraph_retries: 3,
+            dgraph_retry_backoff_ms: 100,
+            dgraph_breaker_failures: 5,
+            dgraph_breaker_cooldown_ms: 10_000,
+            cache_entries: 1000,
+            cache_ttl_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub filter: String,
+}
+
+impl Default for LogConfig {
+    // RUST_LOG is still respected when no filter is configured
+    fn default() -> Self {
+        Self {
+            filter: std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub graphiql: bool,
+    pub mutations: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            graphiql: true,
+            mutations: true,
+        }
+    }
+}
+
+// Limits on how much work a query can make Dgraph do, as fields such as channel.haikus and
+// haiku.channel can be nested into arbitrarily large traversals
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // How deeply fields can be nested, counting the top level field
+    pub max_depth: u32,
+    // How many fields a request could fetch in total, with fields under lists counted once for
+    // each item the list could hold
+    pub max_cost: u64,
+    // How many requests can be sent together in one batch, which share the cost limit
+    pub max_batch_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_depth: 10,
+            max_cost: 10_000,
+            max_batch_size: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistedQueriesConfig {
+    // JSON object mapping the hex encoded SHA-256 hash of each query to its text, eg. as generated
+    // from the queries the website ships
+    pub manifest: Option<PathBuf>,
+    // Whether clients can register queries with automatic persisted queries, and how many of
+    // them are kept, dropping the least recently used
+    pub automatic: bool,
+    pub max_automatic: usize,
+    // Rejects any query not in the manifest, which also disables automatic persisted queries
+    pub only_persisted: bool,
+}
+
+impl Default for PersistedQueriesConfig {
+    fn default() -> Self {
+        Self {
+            manifest: None,
+            automatic: true,
+            max_automatic: 1000,
+            only_persisted: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Scopes given to requests without an API key, which is none unless the API is meant to be
+    // open, eg. read for local development
+    pub anonymous_scopes: Vec<Scope>,
+    pub api_keys: Vec<ApiKeyConfig>,
+    // Logging in with Discord, which is disabled unless configured
+    pub discord: Option<DiscordConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    // Who the key belongs to, used in logs
+    pub name: String,
+    // Hex encoded SHA-256 of the key, as printed by --hash-api-key
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub client_id: String,
+    // Secrets are left out when printing the config, so they can't end up in logs
+    #[serde(skip_serializing)]
+    pub client_secret: String,
+    // Where Discord sends users back to with an authorization code, which should be a page of the
+    // website that passes the code on to /auth/discord/session
+    pub redirect_uri: String,
+    // Discord's endpoints, which can be pointed at a stub when testing locally
+    pub authorize_url: String,
+    pub token_url: String,
+    pub api_url: String,
+    // Key for signing session tokens, which must be kept secret as anyone with it can make them
+    #[serde(skip_serializing)]
+    pub session_secret: String,
+    pub session_ttl_secs: u64,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_uri: String::new(),
+            authorize_url: "https://discord.com/api/oauth2/authorize".to_owned(),
+            token_url: "https://discord.com/api/oauth2/token".to_owned(),
+            api_url: "https://discord.com/api".to_owned(),
+            session_secret: String::new(),
+            session_ttl_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+    pub features: FeatureConfig,
+    pub limits: LimitsConfig,
+    pub persisted_queries: PersistedQueriesConfig,
+    pub auth: AuthConfig,
+}
+
+impl Config {
+    // Builds the config from the defaults, the config file if one is given, and the options
+    pub fn load(options: Options) -> Result<Self, ConfigError> {
+        let config = match &options.config {
+            Some(path) => {
+                let toml = std::fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Io(path.clone(), err))?;
+                Self::from_toml(&toml)?
+            }
+            None => Self::default(),
+        };
+        config.apply(options).validate()
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        toml::from_str(toml).map_err(ConfigError::Parse)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+
+    fn apply(mut self, options: Options) -> Self {
+        let Options {
+            host,
+            port,
+            public_url,
+            storage,
+            seed_data,
+            dgraph_endpoints,
+            log_filter,
+            graphiql,
+            mutations,
+            persisted_queries,
+            only_persisted_queries,
+            ..
+        } = options;
+        self.server.host = host.unwrap_or(self.server.host);
+        self.server.port = port.unwrap_or(self.server.port);
+        self.server.public_url = public_url.or(self.server.public_url);
+        self.storage.backend = storage.unwrap_or(self.storage.backend);
+        self.storage.seed_data = seed_data.or(self.storage.seed_data);
+        if !dgraph_endpoints.is_empty() {
+            self.storage.dgraph_endpoints = dgraph_endpoints;
+        }
+        self.log.filter = log_filter.unwrap_or(self.log.filter);
+        self.features.graphiql = graphiql.unwrap_or(self.features.graphiql);
+        self.features.mutations = mutations.unwrap_or(self.features.mutations);
+        self.persisted_queries.manifest = persisted_queries.or(self.persisted_queries.manifest);
+        self.persisted_queries.only_persisted =
+            only_persisted_queries.unwrap_or(self.persisted_queries.only_persisted);
+        self
+    }
+
+    // Checks the config is usable, filling in the public URL if it wasn't given
+    fn validate(mut self) -> Result<Self, ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_owned()));
+        if self.server.host.is_empty() {
+            return invalid("server.host must not be empty");
+        }
+        if self.server.port == 0 {
+            return invalid("server.port must not be 0");
+        }
+        let public_url = self
+            .server
+            .public_url
+            .take()
+            .unwrap_or_else(|| format!("http://{}:{}", self.server.host, self.server.port));
+        if !valid_url(&public_url) {
+            return invalid("server.public_url must be an http:// or https:// URL");
+        }
+        self.server.public_url = Some(public_url.trim_end_matches('/').to_owned());
+        match self.storage.backend {
+            StorageBackend::Dgraph if self.storage.seed_data.is_some() => {
+                return invalid("storage.seed_data can only be used with memory storage")
+            }
+            StorageBackend::Dgraph if self.storage.dgraph_endpoints.is_empty() => {
+                return invalid("storage.dgraph_endpoints must not be empty")
+            }
+            _ => (),
+        }
+        if self
+            .storage
+            .dgraph_endpoints
+            .iter()
+            .any(|endpoint| !valid_endpoint(endpoint))
+        {
+            return invalid("storage.dgraph_endpoints must be of the form host:port");
+        }
+        if self.storage.dgraph_timeout_ms == 0 {
+            return invalid("storage.dgraph_timeout_ms must not be 0");
+        }
+        if self.storage.dgraph_breaker_failures == 0 {
+            return invalid("storage.dgraph_breaker_failures must not be 0");
+        }
+        if self.storage.cache_entries > 0 && self.storage.cache_ttl_ms == 0 {
+            return invalid("storage.cache_ttl_ms must not be 0 unless caching is disabled");
+        }
+        if self.log.filter.trim().is_empty() {
+            return invalid("log.filter must not be empty");
+        }
+        if EnvFilter::try_new(&self.log.filter).is_err() {
+            return invalid("log.filter is not a valid filter");
+        }
+        if self.limits.max_depth == 0 || self.limits.max_cost == 0 {
+            return invalid("limits.max_depth and limits.max_cost must not be 0");
+        }
+        if self.limits.max_batch_size == 0 {
+            return invalid("limits.max_batch_size must not be 0");
+        }
+        if self.persisted_queries.only_persisted && self.persisted_queries.manifest.is_none() {
+            return invalid("persisted_queries.only_persisted needs a manifest");
+        }
+        if self.persisted_queries.automatic && self.persisted_queries.max_automatic == 0 {
+            return invalid("persisted_queries.max_automatic must not be 0");
+        }
+        for (i, api_key) in self.auth.api_keys.iter().enumerate() {
+            if api_key.name.is_empty() {
+                return invalid("auth.api_keys names must not be empty");
+            }
+            if self.auth.api_keys[..i]
+                .iter()
+                .any(|other| other.name == api_key.name)
+            {
+                return invalid("auth.api_keys names must be unique");
+            }
+            if !valid_key_hash(&api_key.hash) {
+                return invalid("auth.api_keys hashes must be hex encoded SHA-256 hashes");
+            }
+        }
+        if let Some(discord) = &mut self.auth.discord {
+            if discord.client_id.is_empty() || discord.client_secret.is_empty() {
+                return invalid("auth.discord client_id and client_secret must not be empty");
+            }
+            if ![
+                &discord.redirect_uri,
+                &discord.authorize_url,
+                &discord.token_url,
+                &discord.api_url,
+            ]
+            .iter()
+            .all(|url| valid_url(url))
+            {
+                return invalid("auth.discord URLs must be http:// or https:// URLs");
+            }
+            discord.api_url = discord.api_url.trim_end_matches('/').to_owned();
+            if discord.session_secret.len() < 32 {
+                return invalid("auth.discord.session_secret must be at least 32 characters");
+            }
+            if discord.session_ttl_secs == 0 {
+                return invalid("auth.discord.session_ttl_secs must not be 0");
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn public_url(&self) -> &str {
+        self.server.public_url.as_deref().unwrap_or_default()
+    }
+}
+
+fn valid_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn valid_key_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn valid_endpoint(endpoint: &str) -> bool {
+    match endpoint.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    fn options(args: &[&str]) -> Options {
+        Options::from_iter_safe(std::iter::once("haikubot-rs-api").chain(args.iter().cloned()))
+            .unwrap()
+    }
+
+    #[test]
+    fn layer_config() {
+        let config = Config::from_toml(
+            r#"
+            [server]
+            host = "0.0.0.0"
+            port = 8080
+
+            [storage]
+            dgraph_endpoints = ["alpha1:9080", "alpha2:9080"]
+
+            [features]
+            graphiql = false
+
+            [limits]
+            max_depth = 6
+
+            [auth]
+            anonymous_scopes = ["read"]
+
+            [auth.discord]
+            client_id = "1234"
+            client_secret = "client-secret"
+            redirect_uri = "https://haiku.example.com/login"
+            api_url = "http://localhost:8000/api/"
+            session_secret = "0123456789abcdef0123456789abcdef"
+
+            [[auth.api_keys]]
+            name = "bot"
+            hash = "3a1fb6628e3632ba5935f8317570b197c8b3d576dd619f1c6fd917efc3fa6366"
+            scopes = ["read", "write:haikus"]
+            "#,
+        )
+        .unwrap()
+        .apply(options(&[
+            "--port",
+            "9000",
+            "--dgraph-endpoints",
+            "alpha3:9080,alpha4:9080",
+            "--mutations",
+            "false",
+        ]))
+        .validate()
+        .unwrap();
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.public_url(), "http://0.0.0.0:9000");
+        assert_eq!(config.storage.backend, StorageBackend::Dgraph);
+        assert_eq!(
+            config.storage.dgraph_endpoints,
+            vec!["alpha3:9080".to_owned(), "alpha4:9080".to_owned()]
+        );
+        assert_eq!(
+            config.features,
+            FeatureConfig {
+                graphiql: false,
+                mutations: false,
+            }
+        );
+        assert_eq!(
+            config.limits,
+            LimitsConfig {
+                max_depth: 6,
+                max_cost: 10_000,
+                max_batch_size: 10,
+            }
+        );
+        assert_eq!(config.auth.anonymous_scopes, vec![Scope::Read]);
+        let discord = config.auth.discord.unwrap();
+        assert_eq!(discord.api_url, "http://localhost:8000/api");
+        assert_eq!(discord.token_url, "https://discord.com/api/oauth2/token");
+        assert_eq!(
+            config.auth.api_keys[0].scopes,
+            vec![Scope::Read, Scope::WriteHaikus]
+        );
+    }
+
+    #[test]
+    fn print_config() {
+        let mut config = Config::default()
+            .apply(options(&["--public-url", "https://haiku.example.com/"]))
+            .validate()
+            .unwrap();
+        assert_eq!(config.public_url(), "https://haiku.example.com");
+        config.auth.api_keys.push(ApiKeyConfig {
+            name: "website".to_owned(),
+            hash: "0".repeat(64),
+            scopes: vec![Scope::Read],
+        });
+        let discord = DiscordConfig {
+            client_id: "1234".to_owned(),
+            redirect_uri: "https://haiku.example.com/login".to_owned(),
+            ..DiscordConfig::default()
+        };
+        config.auth.discord = Some(DiscordConfig {
+            client_secret: "client-secret".to_owned(),
+            session_secret: "0123456789abcdef0123456789abcdef".to_owned(),
+            ..discord.clone()
+        });
+        let toml = config.to_toml();
+        assert!(!toml.contains("client-secret"));
+        assert!(!toml.contains("0123456789abcdef"));
+        config.auth.discord = Some(discord);
+        assert_eq!(Config::from_toml(&toml).unwrap(), config);
+    }
+
+    #[rstest(toml, args,
+        case("[server]\nport = 0", &[]),
+        case("[server]\nhostname = \"localhost\"", &[]),
+        case("", &["--public-url", "haiku.example.com"]),
+        case("", &["--seed-data", "sample_data.dgraph"]),
+        case("[storage]\ndgraph_endpoints = []", &[]),
+        case("", &["--dgraph-endpoints", "alpha1"]),
+        case("[storage]\ndgraph_timeout_ms = 0", &[]),
+        case("[storage]\ndgraph_breaker_failures = 0", &[]),
+        case("[storage]\ncache_ttl_ms = 0", &[]),
+        case("[log]\nfilter = \"\"", &[]),
+        case("", &["--log-filter", "haikubot_rs_api=loud"]),
+        case("[limits]\nmax_cost = 0", &[]),
+        case("[limits]\nmax_batch_size = 0", &[]),
+        case("", &["--only-persisted-queries", "true"]),
+        case("[persisted_queries]\nmax_automatic = 0", &[]),
+        case("[auth]\nanonymous_scopes = [\"write\"]", &[]),
+        case("[[auth.api_keys]]\nname = \"bot\"\nhash = \"bot-key\"\nscopes = []", &[]),
+        case("[auth.discord]\nclient_id = \"1234\"\nclient_secret = \"secret\"\nredirect_uri = \"https://haiku.example.com/login\"\nsession_secret = \"too short\"", &[]),
+        case("[auth.discord]\nclient_id = \"1234\"\nclient_secret = \"secret\"\nredirect_uri = \"haiku.example.com\"\nsession_secret = \"0123456789abcdef0123456789abcdef\"", &[]),
+        case("[[auth.api_keys]]\nname = \"\"\nhash = \"3a1fb6628e3632ba5935f8317570b197c8b3d576dd619f1c6fd917efc3fa6366\"\nscopes = []", &[]),
+    )]
+    fn reject_invalid_config(toml: &str, args: &[&str]) {
+        let config =
+            Config::from_toml(toml).and_then(|config| config.apply(options(args)).validate());
+        assert!(config.is_err());
+    }
+}
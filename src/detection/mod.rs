@@ -0,0 +1,7 @@
+// Decides whether a piece of text is a haiku. Text is a haiku when its words can be split into
+// lines with the syllable counts of a 5-7-5 pattern, with syllables counted by the rule set for
+// the haiku's rulesVersion, so haikus can always be re-checked using the rules they were found by.
+mod rules;
+mod syllables;
+
+pub use rules::{Rejection, Rules};
@@ -0,0 +1,222 @@
+use super::syllables::{count_syllables, estimate_syllables, spell_number};
+
+// A rule set for detecting haikus. Haikus store the version of the rules they were found with, so
+// once released a version's rules must never change - new behaviour gets a new version instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rules {
+    pub version: i32,
+    // The syllables in each line
+    pub pattern: &'static [u32],
+    // Whether words are looked up in the dictionary before falling back to the heuristic
+    pub use_dictionary: bool,
+    // Whether numbers written as digits count as their spoken words, otherwise they can't be part
+    // of a haiku
+    pub spell_out_numbers: bool,
+}
+
+const RULES: &[Rules] = &[
+    Rules {
+        version: 0,
+        pattern: &[5, 7, 5],
+        use_dictionary: false,
+        spell_out_numbers: false,
+    },
+    Rules {
+        version: 1,
+        pattern: &[5, 7, 5],
+        use_dictionary: true,
+        spell_out_numbers: true,
+    },
+];
+
+impl Rules {
+    pub fn for_version(version: i32) -> Option<&'static Rules> {
+        RULES.iter().find(|rules| rules.version == version)
+    }
+
+    // The rules new haikus are detected with
+    pub fn latest() -> &'static Rules {
+        RULES.last().unwrap()
+    }
+
+    fn word_syllables(&self, word: &str) -> u32 {
+        if self.use_dictionary {
+            count_syllables(word)
+        } else {
+            estimate_syllables(word)
+        }
+    }
+
+    // Counts the syllables in a whitespace separated token, or None if it can't be spoken. Tokens
+    // made up only of punctuation have no syllables.
+    pub fn token_syllables(&self, token: &str) -> Option<u32> {
+        let word = token
+            .trim_matches(|c: char| !c.is_alphanumeric())
+            .replace(&['\'', '’'][..], "");
+        if word.chars().any(|c| c.is_ascii_digit()) {
+            return match word.parse() {
+                Ok(number) if self.spell_out_numbers => spell_number(number)
+                    .map(|words| words.iter().map(|word| self.word_syllables(word)).sum()),
+                _ => None,
+            };
+        }
+        if word.chars().any(|c| !c.is_alphabetic() && c != '-') {
+            return None;
+        }
+        // Hyphenated words are counted a part at a time
+        Some(
+            word.split('-')
+                .filter(|part| !part.is_empty())
+                .map(|part| self.word_syllables(part))
+                .sum(),
+        )
+    }
+
+    // Counts the syllables in each word of the text and tries to split it into lines matching the
+    // pattern, breaking only between words. Any existing line breaks in the text are ignored.
+    pub fn check(&self, text: &str) -> Check {
+        let words = text
+            .split_whitespace()
+            .map(|word| (word.to_owned(), self.token_syllables(word)))
+            .collect::<Vec<_>>();
+        Check {
+            lines: self.split(&words),
+            words,
+        }
+    }
+
+    fn split(&self, words: &[(String, Option<u32>)]) -> Result<Vec<String>, Rejection> {
+        let syllables = words
+            .iter()
+            .map(|(_, syllables)| syllables.ok_or(Rejection::UncountableWord))
+            .sum::<Result<u32, _>>()?;
+        let expected: u32 = self.pattern.iter().sum();
+        if syllables == 0 {
+            return Err(Rejection::Empty);
+        } else if syllables < expected {
+            return Err(Rejection::TooFewSyllables);
+        } else if syllables > expected {
+            return Err(Rejection::TooManySyllables);
+        }
+        let mut lines: Vec<Vec<&str>> = vec![Vec::new()];
+        let mut line_syllables = 0;
+        for (word, syllables) in words {
+            let syllables = syllables.unwrap_or_default();
+            // Punctuation after a finished line stays on that line
+            if line_syllables == self.pattern[lines.len() - 1] && syllables > 0 {
+                lines.push(Vec::new());
+                line_syllables = 0;
+            }
+            line_syllables += syllables;
+            if line_syllables > self.pattern[lines.len() - 1] {
+                return Err(Rejection::NoLineBreak);
+            }
+            lines.last_mut().unwrap().push(word);
+        }
+        Ok(lines.into_iter().map(|words| words.join(" ")).collect())
+    }
+}
+
+// The outcome of checking whether some text is a haiku
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    // Each word of the text with its syllables, or None if they couldn't be counted
+    pub words: Vec<(String, Option<u32>)>,
+    pub lines: Result<Vec<String>, Rejection>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    // There are no words with any syllables
+    Empty,
+    // A word such as "mp3" can't be spoken in a countable way
+    UncountableWord,
+    TooFewSyllables,
+    TooManySyllables,
+    // The syllables add up, but a word crosses the end of a line
+    NoLineBreak,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        version,
+        token,
+        syllables,
+        case(1, "pond", Some(1)),
+        case(1, "Haiku...", Some(3)),
+        case(0, "Haiku...", Some(2)),
+        case(1, "don't", Some(1)),
+        case(1, "well-known", Some(2)),
+        case(1, "—", Some(0)),
+        case(1, "42", Some(3)),
+        case(0, "42", None),
+        case(1, "mp3", None),
+        case(1, "1,000", None)
+    )]
+    fn count_token_syllables(version: i32, token: &str, syllables: Option<u32>) {
+        let rules = Rules::for_version(version).unwrap();
+        assert_eq!(rules.token_syllables(token), syllables);
+    }
+
+    #[rstest(version, text, expected,
+        case(
+            1,
+            "An old silent pond... A frog jumps into the pond, splash! Silence again.",
+            Ok(vec!["An old silent pond...", "A frog jumps into the pond,", "splash! Silence again."])
+        ),
+        case(
+            1,
+            "An old silent pond\nA frog jumps into the pond\nsplash! Silence again. —",
+            Ok(vec!["An old silent pond", "A frog jumps into the pond", "splash! Silence again. —"])
+        ),
+        case(1, " ... ", Err(Rejection::Empty)),
+        case(1, "An old silent pond", Err(Rejection::TooFewSyllables)),
+        case(1, "An old silent pond... A frog jumps into the pond, splash! Silence again. Ribbit", Err(Rejection::TooManySyllables)),
+        case(1, "An old silent quiet frog jumps into the pond, splash! Silence again.", Err(Rejection::NoLineBreak)),
+        case(1, "An old silent pond... A frog jumps into the mp3", Err(Rejection::UncountableWord)),
+        case(
+            1,
+            "A quiet old pond, a frog jumps into the pond, splash! Silence again.",
+            Ok(vec!["A quiet old pond,", "a frog jumps into the pond,", "splash! Silence again."])
+        ),
+        case(0, "A quiet old pond, a frog jumps into the pond, splash! Silence again.", Err(Rejection::TooFewSyllables)),
+        case(
+            1,
+            "Written at 12 the poem is all that I have to show for my day",
+            Ok(vec!["Written at 12 the", "poem is all that I have", "to show for my day"])
+        ),
+        case(0, "Written at 12 the poem is all that I have to show for my day", Err(Rejection::UncountableWord)),
+    )]
+    fn check_haikus(version: i32, text: &str, expected: Result<Vec<&str>, Rejection>) {
+        let rules = Rules::for_version(version).unwrap();
+        assert_eq!(
+            rules.check(text).lines,
+            expected.map(|lines| lines.into_iter().map(str::to_owned).collect())
+        );
+    }
+
+    #[test]
+    fn count_words() {
+        let check = Rules::latest().check("A frog, 2 mp3s");
+        assert_eq!(
+            check.words,
+            vec![
+                ("A".to_owned(), Some(1)),
+                ("frog,".to_owned(), Some(1)),
+                ("2".to_owned(), Some(1)),
+                ("mp3s".to_owned(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn find_rules() {
+        assert_eq!(Rules::for_version(0).map(|rules| rules.version), Some(0));
+        assert_eq!(Rules::for_version(2), None);
+        assert_eq!(Rules::latest().version, 1);
+    }
+}
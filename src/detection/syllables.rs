@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref DICTIONARY: HashMap<&'static str, u32> = include_str!("dictionary.txt")
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?, parts.next()?.parse().ok()?))
+        })
+        .collect();
+}
+
+// Counts the syllables in a word of letters, using the dictionary if it has the word
+pub fn count_syllables(word: &str) -> u32 {
+    DICTIONARY
+        .get(word.to_lowercase().as_str())
+        .cloned()
+        .unwrap_or_else(|| estimate_syllables(word))
+}
+
+// Estimates the syllables in a word of letters by counting its groups of vowels, allowing for
+// common silent endings. Every word has at least one syllable.
+pub fn estimate_syllables(word: &str) -> u32 {
+    let letters = word
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .flat_map(char::to_lowercase)
+        .collect::<Vec<_>>();
+    // A leading y is a consonant, as in "yes"
+    let is_vowel = |index: usize| match letters[index] {
+        'a' | 'e' | 'i' | 'o' | 'u' => true,
+        'y' => index > 0,
+        _ => false,
+    };
+    let mut count = 0;
+    for index in 0..letters.len() {
+        if is_vowel(index) && (index == 0 || !is_vowel(index - 1)) {
+            count += 1;
+        }
+    }
+    let len = letters.len();
+    let ends_with = |ending: &str| {
+        len > ending.len()
+            && letters[len - ending.len()..]
+                .iter()
+                .copied()
+                .eq(ending.chars())
+    };
+    let silent_ending = if ends_with("e") {
+        // "make" but not "free", and "whale" but not "table"
+        match letters[len - 2] {
+            'l' => len > 2 && is_vowel(len - 3),
+            _ => !is_vowel(len - 2),
+        }
+    } else if ends_with("ed") {
+        // "jumped" but not "wanted"
+        !is_vowel(len - 3) && !matches!(letters[len - 3], 't' | 'd')
+    } else if ends_with("es") {
+        // "makes" but not "wishes" or "tables"
+        match letters[len - 3] {
+            's' | 'x' | 'z' | 'c' | 'g' | 'h' => false,
+            'l' => len > 3 && is_vowel(len - 4),
+            _ => !is_vowel(len - 3),
+        }
+    } else {
+        false
+    };
+    if silent_ending && count > 1 {
+        count -= 1;
+    }
+    count.max(1)
+}
+
+const ONES: &[&str] = &[
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const TENS: &[&str] = &[
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+// Spells out a number below a million as it would be read aloud, eg. 342 is "three hundred
+// forty two"
+pub fn spell_number(number: u32) -> Option<Vec<&'static str>> {
+    let mut words = Vec::new();
+    match number {
+        0..=19 => words.push(ONES[number as usize]),
+        20..=99 => {
+            words.push(TENS[(number / 10) as usize]);
+            match number % 10 {
+                0 => (),
+                ones => words.push(ONES[ones as usize]),
+            }
+        }
+        100..=999 => {
+            words.extend(spell_number(number / 100)?);
+            words.push("hundred");
+            match number % 100 {
+                0 => (),
+                rest => words.extend(spell_number(rest)?),
+            }
+        }
+        1000..=999_999 => {
+            words.extend(spell_number(number / 1000)?);
+            words.push("thousand");
+            match number % 1000 {
+                0 => (),
+                rest => words.extend(spell_number(rest)?),
+            }
+        }
+        _ => return None,
+    }
+    Some(words)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        word,
+        syllables,
+        case("a", 1),
+        case("the", 1),
+        case("pond", 1),
+        case("yes", 1),
+        case("free", 1),
+        case("make", 1),
+        case("makes", 1),
+        case("whale", 1),
+        case("jumped", 1),
+        case("table", 2),
+        case("silent", 2),
+        case("wanted", 2),
+        case("wishes", 2),
+        case("happy", 2),
+        case("Beautiful", 3),
+        case("hmm", 1)
+    )]
+    fn estimate_word_syllables(word: &str, syllables: u32) {
+        assert_eq!(estimate_syllables(word), syllables);
+    }
+
+    #[rstest(
+        word,
+        syllables,
+        case("haiku", 3),
+        case("Poem", 2),
+        case("quiet", 2),
+        case("pond", 1)
+    )]
+    fn count_word_syllables(word: &str, syllables: u32) {
+        assert_eq!(count_syllables(word), syllables);
+    }
+
+    #[rstest(
+        number,
+        expected,
+        case(0, Some("zero")),
+        case(17, Some("seventeen")),
+        case(40, Some("forty")),
+        case(342, Some("three hundred forty two")),
+        case(2020, Some("two thousand twenty")),
+        case(1_000_000, None)
+    )]
+    fn spell_numbers(number: u32, expected: Option<&str>) {
+        assert_eq!(
+            spell_number(number).map(|words| words.join(" ")),
+            expected.map(str::to_owned)
+        );
+    }
+}
@@ -0,0 +1,380 @@
+// A typed representation of the subset of DQL (Dgraph's query language) that the API generates.
+// Queries are built up from these types and only turned into text by `render`, which moves every
+// user supplied value into a query variable, so no input ever ends up spliced into the query.
+mod render;
+
+pub use render::render;
+
+use chrono::DateTime;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub name: String,
+    pub blocks: Vec<Block>,
+}
+
+impl Query {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn block(mut self, block: Block) -> Self {
+        self.blocks.push(block);
+        self
+    }
+}
+
+// A top level query block, eg. `haiku(func: uid($v0)) @filter(type(Haiku)) { ... }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub name: String,
+    // The variable a var block's nodes are bound to, eg. `server as var(func: ...)` in an upsert
+    pub variable: Option<String>,
+    pub func: Function,
+    pub filter: Option<Filter>,
+    pub pagination: Pagination,
+    pub fields: Vec<Field>,
+}
+
+impl Block {
+    pub fn new(name: &str, func: Function) -> Self {
+        Self {
+            name: name.to_owned(),
+            variable: None,
+            func,
+            filter: None,
+            pagination: Pagination::default(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn var(variable: &str, func: Function) -> Self {
+        Self {
+            variable: Some(variable.to_owned()),
+            ..Self::new("var", func)
+        }
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn pagination(mut self, pagination: Pagination) -> Self {
+        self.pagination = pagination;
+        self
+    }
+
+    pub fn fields(mut self, fields: Vec<Field>) -> Self {
+        self.fields = fields;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    // The node's uid, which is always aliased as uid is not a valid GraphQL field name
+    Uid {
+        alias: String,
+    },
+    Value {
+        alias: Option<String>,
+        predicate: String,
+    },
+    Edge(Edge),
+    // The number of nodes along a uid predicate, eg. `haikuCount: count(~author @filter(...))`
+    Count {
+        alias: String,
+        predicate: Predicate,
+        filter: Option<Filter>,
+    },
+    // The number of nodes matched by a top level block, eg. `count: count(uid)`
+    CountUid {
+        alias: String,
+    },
+}
+
+impl Field {
+    pub fn uid(alias: &str) -> Self {
+        Field::Uid {
+            alias: alias.to_owned(),
+        }
+    }
+
+    pub fn value(predicate: &str) -> Self {
+        Field::Value {
+            alias: None,
+            predicate: predicate.to_owned(),
+        }
+    }
+
+    pub fn count_uid(alias: &str) -> Self {
+        Field::CountUid {
+            alias: alias.to_owned(),
+        }
+    }
+
+    pub fn count(alias: &str, predicate: Predicate, filter: Filter) -> Self {
+        Field::Count {
+            alias: alias.to_owned(),
+            predicate,
+            filter: Some(filter),
+        }
+    }
+}
+
+impl From<Edge> for Field {
+    fn from(edge: Edge) -> Self {
+        Field::Edge(edge)
+    }
+}
+
+// A nested block following a uid predicate, eg. `authors: author @filter(type(DiscordUser)) { ... }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub alias: Option<String>,
+    pub predicate: Predicate,
+    pub filter: Option<Filter>,
+    pub pagination: Pagination,
+    pub fields: Vec<Field>,
+}
+
+impl Edge {
+    pub fn new(predicate: Predicate) -> Self {
+        Self {
+            alias: None,
+            predicate,
+            filter: None,
+            pagination: Pagination::default(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.alias = Some(alias.to_owned());
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn pagination(mut self, pagination: Pagination) -> Self {
+        self.pagination = pagination;
+        self
+    }
+
+    pub fn fields(mut self, fields: Vec<Field>) -> Self {
+        self.fields = fields;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Forward(String),
+    Reverse(String),
+}
+
+impl Predicate {
+    pub fn forward(name: &str) -> Self {
+        Predicate::Forward(name.to_owned())
+    }
+
+    pub fn reverse(name: &str) -> Self {
+        Predicate::Reverse(name.to_owned())
+    }
+}
+
+// A negative `first` takes nodes from the end of the list rather than the start. `after` skips
+// nodes up to and including a uid, and can only be used on lists in uid order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pagination {
+    pub order: Option<Order>,
+    pub first: Option<i64>,
+    pub offset: Option<i64>,
+    pub after: Option<u64>,
+}
+
+// Sorts nodes by a value predicate, eg. `orderdesc: timestamp`. Without one, nodes come back in
+// uid order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub predicate: String,
+    pub descending: bool,
+}
+
+impl Order {
+    // Compares two nodes' values for the predicate, with nodes which don't have it coming last
+    pub fn compare(
+        &self,
+        a: Option<&serde_json::Value>,
+        b: Option<&serde_json::Value>,
+    ) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) if self.descending => compare_values(a, b).reverse(),
+            (Some(a), Some(b)) => compare_values(a, b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
+// Orders two values as Dgraph does when sorting or comparing with ge/le
+pub fn compare_values(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+    match (a, b) {
+        (serde_json::Value::Number(a), serde_json::Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        // Datetimes are compared as instants, as they may be written with different offsets
+        (serde_json::Value::String(a), serde_json::Value::String(b)) => {
+            match (
+                DateTime::parse_from_rfc3339(a),
+                DateTime::parse_from_rfc3339(b),
+            ) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            }
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Type(String),
+    Function(Function),
+    And(Vec<Filter>),
+    // Nodes with a path of edges along the predicates to a node matched by the function and
+    // filter. As DQL can't filter on the far end of an edge, this is rendered as a var block
+    // following the path in reverse, eg. `var(func: eq(discordSnowflake, $v0)) { f0 as ~author }`,
+    // and a `uid(f0)` filter.
+    Edge {
+        path: Vec<String>,
+        func: Function,
+        filter: Box<Filter>,
+    },
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    // Nodes matched by another block, rendered as a var block and a `uid(f0)` filter. This allows
+    // filtering on what only pagination can express, eg. `f0 as var(func: type(Haiku), after: 0x5)`
+    // for uids greater than 0x5. On an edge, the block's function and filter are applied to the
+    // nodes along the same edges instead, so only those are paged through.
+    Var(Box<Block>),
+}
+
+impl Filter {
+    pub fn type_of(type_name: &str) -> Self {
+        Filter::Type(type_name.to_owned())
+    }
+
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Filter::Or(mut filters) => {
+                filters.push(other);
+                Filter::Or(filters)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    pub fn not(self) -> Self {
+        Filter::Not(Box::new(self))
+    }
+
+    pub fn edge(predicate: &str, func: Function, filter: Filter) -> Self {
+        Self::path(&[predicate], func, filter)
+    }
+
+    pub fn path(path: &[&str], func: Function, filter: Filter) -> Self {
+        Filter::Edge {
+            path: path
+                .iter()
+                .map(|predicate| (*predicate).to_owned())
+                .collect(),
+            func,
+            filter: Box::new(filter),
+        }
+    }
+}
+
+impl From<Function> for Filter {
+    fn from(function: Function) -> Self {
+        Filter::Function(function)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Function {
+    Uid(Value),
+    Type(String),
+    Eq(String, Value),
+    Gt(String, Value),
+    Ge(String, Value),
+    Lt(String, Value),
+    Le(String, Value),
+    AnyOfTerms(String, Value),
+    AllOfTerms(String, Value),
+    // Needs a fulltext index, matching words after stemming and stop word removal
+    AllOfText(String, Value),
+}
+
+// A value supplied to a function, always passed to Dgraph as a query variable
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    // Matches any of the values, eg. `eq(discordSnowflake, $v0, $v1)`
+    List(Vec<Value>),
+}
+
+impl Value {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::String(value) => serde_json::Value::from(value.as_str()),
+            Value::Int(value) => serde_json::Value::from(*value),
+            Value::List(values) => values.iter().map(Value::to_json).collect(),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<Vec<String>> for Value {
+    fn from(values: Vec<String>) -> Self {
+        Value::List(values.into_iter().map(Value::from).collect())
+    }
+}
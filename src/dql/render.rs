@@ -0,0 +1,494 @@
+use super::{Block, Edge, Field, Filter, Function, Pagination, Predicate, Query, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const INDENT: &str = "  ";
+
+struct Renderer {
+    // Variable declarations in the order they were introduced, eg. ("$v0", "string")
+    declarations: Vec<(String, &'static str)>,
+    vars: HashMap<String, String>,
+    // Var blocks introduced by edge filters, which are rendered ahead of the query's own blocks
+    var_blocks: Vec<String>,
+    // The function of the block being rendered and the edges followed from it to the filter being
+    // rendered, which scope var filters to the same edges
+    root: Option<Function>,
+    path: Vec<Predicate>,
+}
+
+impl Renderer {
+    fn variable(&mut self, value: &Value) -> String {
+        let name = format!("$v{}", self.declarations.len());
+        let (var_type, value) = match value {
+            Value::String(value) => ("string", value.clone()),
+            Value::Int(value) => ("int", value.to_string()),
+            Value::List(values) => {
+                let values = values
+                    .iter()
+                    .map(|value| self.variable(value))
+                    .collect::<Vec<_>>();
+                // Functions take several values as separate arguments, as Dgraph doesn't allow
+                // list variables, eg. `eq(discordSnowflake, $v0, $v1)`
+                return values.join(", ");
+            }
+        };
+        self.declarations.push((name.clone(), var_type));
+        self.vars.insert(name.clone(), value);
+        name
+    }
+
+    fn function(&mut self, function: &Function) -> String {
+        match function {
+            Function::Uid(value) => format!("uid({})", self.variable(value)),
+            Function::Type(type_name) => format!("type({})", type_name),
+            Function::Eq(predicate, value) => {
+                format!("eq({}, {})", predicate, self.variable(value))
+            }
+            Function::Gt(predicate, value) => {
+                format!("gt({}, {})", predicate, self.variable(value))
+            }
+            Function::Ge(predicate, value) => {
+                format!("ge({}, {})", predicate, self.variable(value))
+            }
+            Function::Lt(predicate, value) => {
+                format!("lt({}, {})", predicate, self.variable(value))
+            }
+            Function::Le(predicate, value) => {
+                format!("le({}, {})", predicate, self.variable(value))
+            }
+            Function::AnyOfTerms(predicate, value) => {
+                format!("anyofterms({}, {})", predicate, self.variable(value))
+            }
+            Function::AllOfTerms(predicate, value) => {
+                format!("allofterms({}, {})", predicate, self.variable(value))
+            }
+            Function::AllOfText(predicate, value) => {
+                format!("alloftext({}, {})", predicate, self.variable(value))
+            }
+        }
+    }
+
+    fn filter(&mut self, filter: &Filter) -> String {
+        match filter {
+            Filter::Type(type_name) => format!("type({})", type_name),
+            Filter::Function(function) => self.function(function),
+            Filter::And(filters) => self.filters(filters, " AND "),
+            Filter::Or(filters) => self.filters(filters, " OR "),
+            Filter::Not(filter) => format!("NOT {}", self.operand(filter)),
+            Filter::Var(block) if !self.path.is_empty() => self.scoped_var(block),
+            Filter::Var(block) => {
+                let index = self.var_blocks.len();
+                let name = format!("f{}", index);
+                self.var_blocks.push(String::new());
+                let block = Block {
+                    variable: Some(name.clone()),
+                    ..(**block).clone()
+                };
+                let mut rendered = String::new();
+                self.block(&mut rendered, &block);
+                rendered.push('\n');
+                self.var_blocks[index] = rendered;
+                format!("uid({})", name)
+            }
+            Filter::Edge { path, func, filter } => {
+                let index = self.var_blocks.len();
+                let name = format!("f{}", index);
+                // Reserve this block's place before rendering its filter, which may add its own
+                self.var_blocks.push(String::new());
+                let mut block = format!(
+                    "{}var(func: {}) @filter({}) {{\n",
+                    INDENT,
+                    self.function(func),
+                    self.filter(filter)
+                );
+                // The path is followed backwards from its far end, with the var on the last step
+                let depth = path.len();
+                for (step, predicate) in path.iter().rev().enumerate() {
+                    let indent = INDENT.repeat(step + 2);
+                    if step + 1 == depth {
+                        writeln!(block, "{}{} as ~{}", indent, name, predicate).unwrap();
+                    } else {
+                        writeln!(block, "{}~{} {{", indent, predicate).unwrap();
+                    }
+                }
+                for step in (0..depth).rev() {
+                    writeln!(block, "{}}}", INDENT.repeat(step + 1)).unwrap();
+                }
+                self.var_blocks[index] = block;
+                format!("uid({})", name)
+            }
+        }
+    }
+
+    // A var filter on an edge's nodes, which rather than finding the block's nodes out of every node
+    // follows the same edges from the same root, eg.
+    // `var(func: eq(discordSnowflake, $v0)) { f0 as ~author (after: 0x5) @filter(...) }`.
+    // The edge is only ever filtered to nodes it reaches, so this matches the same ones.
+    fn scoped_var(&mut self, block: &Block) -> String {
+        let index = self.var_blocks.len();
+        let name = format!("f{}", index);
+        self.var_blocks.push(String::new());
+        let path = self.path.clone();
+        let root = self
+            .root
+            .clone()
+            .expect("edges are only rendered within blocks");
+        let mut rendered = format!("{}var(func: {}) {{\n", INDENT, self.function(&root));
+        let depth = path.len();
+        for (step, predicate) in path.iter().enumerate() {
+            rendered.push_str(&INDENT.repeat(step + 2));
+            if step + 1 < depth {
+                self.predicate(&mut rendered, predicate);
+                rendered.push_str(" {\n");
+                continue;
+            }
+            write!(rendered, "{} as ", name).unwrap();
+            self.predicate(&mut rendered, predicate);
+            let args = self.pagination(&block.pagination);
+            if !args.is_empty() {
+                write!(rendered, " ({})", args.join(", ")).unwrap();
+            }
+            let filter = match &block.filter {
+                Some(filter) => Filter::from(block.func.clone()).and(filter.clone()),
+                None => Filter::from(block.func.clone()),
+            };
+            writeln!(rendered, " @filter({})", self.filter(&filter)).unwrap();
+        }
+        for step in (0..depth - 1).rev() {
+            writeln!(rendered, "{}}}", INDENT.repeat(step + 2)).unwrap();
+        }
+        writeln!(rendered, "{}}}", INDENT).unwrap();
+        self.var_blocks[index] = rendered;
+        format!("uid({})", name)
+    }
+
+    fn filters(&mut self, filters: &[Filter], operator: &str) -> String {
+        let filters = filters
+            .iter()
+            .map(|filter| self.operand(filter))
+            .collect::<Vec<_>>();
+        filters.join(operator)
+    }
+
+    // Filters combining others are bracketed when combined themselves, as AND binds tighter than OR
+    fn operand(&mut self, filter: &Filter) -> String {
+        match filter {
+            Filter::And(_) | Filter::Or(_) => format!("({})", self.filter(filter)),
+            _ => self.filter(filter),
+        }
+    }
+
+    fn pagination(&self, pagination: &Pagination) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(order) = &pagination.order {
+            let direction = if order.descending {
+                "orderdesc"
+            } else {
+                "orderasc"
+            };
+            args.push(format!("{}: {}", direction, order.predicate));
+        }
+        if let Some(first) = pagination.first {
+            args.push(format!("first: {}", first));
+        }
+        if let Some(offset) = pagination.offset {
+            args.push(format!("offset: {}", offset));
+        }
+        if let Some(after) = pagination.after {
+            args.push(format!("after: {:#x}", after));
+        }
+        args
+    }
+
+    fn block(&mut self, out: &mut String, block: &Block) {
+        // Var blocks are rendered in the middle of other blocks' filters, so this puts back the
+        // scope they were rendered in afterwards
+        let root = self.root.replace(block.func.clone());
+        let path = std::mem::take(&mut self.path);
+        let mut args = vec![format!("func: {}", self.function(&block.func))];
+        args.extend(self.pagination(&block.pagination));
+        out.push_str(INDENT);
+        if let Some(variable) = &block.variable {
+            write!(out, "{} as ", variable).unwrap();
+        }
+        write!(out, "{}({})", block.name, args.join(", ")).unwrap();
+        if let Some(filter) = &block.filter {
+            write!(out, " @filter({})", self.filter(filter)).unwrap();
+        }
+        // Var blocks only bind their nodes, so they needn't select anything
+        if block.variable.is_none() || !block.fields.is_empty() {
+            self.selection(out, &block.fields, 1);
+        }
+        self.root = root;
+        self.path = path;
+    }
+
+    fn predicate(&self, out: &mut String, predicate: &Predicate) {
+        match predicate {
+            Predicate::Forward(predicate) => out.push_str(predicate),
+            Predicate::Reverse(predicate) => write!(out, "~{}", predicate).unwrap(),
+        }
+    }
+
+    fn edge(&mut self, out: &mut String, edge: &Edge, depth: usize) {
+        if let Some(alias) = &edge.alias {
+            write!(out, "{}: ", alias).unwrap();
+        }
+        self.predicate(out, &edge.predicate);
+        let args = self.pagination(&edge.pagination);
+        if !args.is_empty() {
+            write!(out, " ({})", args.join(", ")).unwrap();
+        }
+        self.path.push(edge.predicate.clone());
+        if let Some(filter) = &edge.filter {
+            write!(out, " @filter({})", self.filter(filter)).unwrap();
+        }
+        self.selection(out, &edge.fields, depth);
+        self.path.pop();
+    }
+
+    fn selection(&mut self, out: &mut String, fields: &[Field], depth: usize) {
+        out.push_str(" {\n");
+        for field in fields {
+            out.push_str(&INDENT.repeat(depth + 1));
+            match field {
+                Field::Uid { alias } => write!(out, "{}: uid", alias).unwrap(),
+                Field::Value {
+                    alias: Some(alias),
+                    predicate,
+                } => write!(out, "{}: {}", alias, predicate).unwrap(),
+                Field::Value {
+                    alias: None,
+                    predicate,
+                } => out.push_str(predicate),
+                Field::Edge(edge) => self.edge(out, edge, depth + 1),
+                Field::Count {
+                    alias,
+                    predicate,
+                    filter,
+                } => {
+                    write!(out, "{}: count(", alias).unwrap();
+                    self.predicate(out, predicate);
+                    if let Some(filter) = filter {
+                        self.path.push(predicate.clone());
+                        write!(out, " @filter({})", self.filter(filter)).unwrap();
+                        self.path.pop();
+                    }
+                    out.push(')');
+                }
+                Field::CountUid { alias } => write!(out, "{}: count(uid)", alias).unwrap(),
+            }
+            out.push('\n');
+        }
+        write!(out, "{}}}", INDENT.repeat(depth)).unwrap();
+    }
+}
+
+// Renders the query as DQL text along with the variables it needs to be run with
+pub fn render(query: &Query) -> (String, HashMap<String, String>) {
+    let mut renderer = Renderer {
+        declarations: Vec::new(),
+        vars: HashMap::new(),
+        var_blocks: Vec::new(),
+        root: None,
+        path: Vec::new(),
+    };
+    let mut blocks = String::new();
+    for block in &query.blocks {
+        renderer.block(&mut blocks, block);
+        blocks.push('\n');
+    }
+    let body = renderer.var_blocks.concat() + blocks.as_str();
+    let declarations = renderer
+        .declarations
+        .iter()
+        .map(|(name, var_type)| format!("{}: {}", name, var_type))
+        .collect::<Vec<_>>();
+    let text = if declarations.is_empty() {
+        format!("query {} {{\n{}}}", query.name, body)
+    } else {
+        format!(
+            "query {}({}) {{\n{}}}",
+            query.name,
+            declarations.join(", "),
+            body
+        )
+    };
+    (text, renderer.vars)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+
+    #[test]
+    fn render_query() {
+        let query = Query::new("user").block(
+            Block::new(
+                "user",
+                Function::Eq("discordSnowflake".to_owned(), "1".into()),
+            )
+            .filter(Filter::type_of("DiscordUser"))
+            .fields(vec![
+                Field::value("discordSnowflake"),
+                Edge::new(Predicate::reverse("author"))
+                    .alias("haikus")
+                    .filter(
+                        Filter::type_of("Haiku")
+                            .and(
+                                Function::AnyOfTerms(
+                                    "content".to_owned(),
+                                    r#"") { uid } #"#.into(),
+                                )
+                                .into(),
+                            )
+                            .and(Function::Ge("rulesVersion".to_owned(), 1.into()).into())
+                            .and(Filter::edge(
+                                "channel",
+                                Function::Eq(
+                                    "discordSnowflake".to_owned(),
+                                    vec!["2".to_owned(), "3".to_owned()].into(),
+                                ),
+                                Filter::type_of("DiscordChannel"),
+                            ))
+                            .and(Filter::path(
+                                &["channel", "server"],
+                                Function::Eq("discordSnowflake".to_owned(), "4".into()),
+                                Filter::type_of("DiscordServer"),
+                            )),
+                    )
+                    .pagination(Pagination {
+                        order: Some(Order {
+                            predicate: "timestamp".to_owned(),
+                            descending: true,
+                        }),
+                        first: Some(2),
+                        offset: Some(4),
+                        ..Pagination::default()
+                    })
+                    .fields(vec![Field::uid("id"), Field::value("content")])
+                    .into(),
+                Field::count(
+                    "haikuCount",
+                    Predicate::reverse("author"),
+                    Filter::type_of("Haiku"),
+                ),
+            ]),
+        );
+        let (text, vars) = render(&query);
+        assert_eq!(
+            text,
+            r#"query user($v0: string, $v1: string, $v2: int, $v3: string, $v4: string, $v5: string) {
+  var(func: eq(discordSnowflake, $v3, $v4)) @filter(type(DiscordChannel)) {
+    f0 as ~channel
+  }
+  var(func: eq(discordSnowflake, $v5)) @filter(type(DiscordServer)) {
+    ~server {
+      f1 as ~channel
+    }
+  }
+  user(func: eq(discordSnowflake, $v0)) @filter(type(DiscordUser)) {
+    discordSnowflake
+    haikus: ~author (orderdesc: timestamp, first: 2, offset: 4) @filter(type(Haiku) AND anyofterms(content, $v1) AND ge(rulesVersion, $v2) AND uid(f0) AND uid(f1)) {
+      id: uid
+      content
+    }
+    haikuCount: count(~author @filter(type(Haiku)))
+  }
+}"#
+        );
+        let mut expected_vars = HashMap::new();
+        expected_vars.insert("$v0".to_owned(), "1".to_owned());
+        expected_vars.insert("$v1".to_owned(), r#"") { uid } #"#.to_owned());
+        expected_vars.insert("$v2".to_owned(), "1".to_owned());
+        expected_vars.insert("$v3".to_owned(), "2".to_owned());
+        expected_vars.insert("$v4".to_owned(), "3".to_owned());
+        expected_vars.insert("$v5".to_owned(), "4".to_owned());
+        assert_eq!(vars, expected_vars);
+    }
+
+    #[test]
+    fn render_uid_list() {
+        let query = Query::new("batch").block(
+            Block::new(
+                "haiku",
+                Function::Uid(vec!["0x1".to_owned(), "0x2".to_owned()].into()),
+            )
+            .fields(vec![Field::uid("id")]),
+        );
+        let (text, vars) = render(&query);
+        assert_eq!(
+            text,
+            r#"query batch($v0: string, $v1: string) {
+  haiku(func: uid($v0, $v1)) {
+    id: uid
+  }
+}"#
+        );
+        assert_eq!(vars.len(), 2);
+    }
+
+    #[test]
+    fn render_var_blocks() {
+        let query = Query::new("addHaiku").block(
+            Block::var(
+                "server",
+                Function::Eq("discordSnowflake".to_owned(), "1".into()),
+            )
+            .filter(Filter::type_of("DiscordServer")),
+        );
+        let (text, vars) = render(&query);
+        assert_eq!(
+            text,
+            r#"query addHaiku($v0: string) {
+  server as var(func: eq(discordSnowflake, $v0)) @filter(type(DiscordServer))
+}"#
+        );
+        assert_eq!(vars["$v0"], "1");
+    }
+
+    #[test]
+    fn render_edge_var_filters() {
+        let cursor = Filter::Var(Box::new(
+            Block::new("var", Function::Eq("rulesVersion".to_owned(), 1.into()))
+                .filter(Filter::type_of("Haiku"))
+                .pagination(Pagination {
+                    after: Some(5),
+                    ..Pagination::default()
+                }),
+        ));
+        let query = Query::new("server").block(
+            Block::new(
+                "server",
+                Function::Eq("discordSnowflake".to_owned(), "1".into()),
+            )
+            .filter(Filter::type_of("DiscordServer"))
+            .fields(vec![Edge::new(Predicate::reverse("server"))
+                .fields(vec![Edge::new(Predicate::reverse("channel"))
+                    .filter(Filter::type_of("Haiku").and(cursor))
+                    .fields(vec![Field::uid("id")])
+                    .into()])
+                .into()]),
+        );
+        let (text, vars) = render(&query);
+        assert_eq!(
+            text,
+            r#"query server($v0: string, $v1: string, $v2: int) {
+  var(func: eq(discordSnowflake, $v1)) {
+    ~server {
+      f0 as ~channel (after: 0x5) @filter(eq(rulesVersion, $v2) AND type(Haiku))
+    }
+  }
+  server(func: eq(discordSnowflake, $v0)) @filter(type(DiscordServer)) {
+    ~server {
+      ~channel @filter(type(Haiku) AND uid(f0)) {
+        id: uid
+      }
+    }
+  }
+}"#
+        );
+        assert_eq!(vars["$v1"], "1");
+    }
+}
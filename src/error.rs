@@ -1,7 +1,12 @@
+use super::auth::Scope;
+use super::metrics;
+use dgraph::grpcio::{self, RpcStatusCode};
 use dgraph::DgraphError;
-use juniper::FieldError;
+use juniper::{FieldError, Object, Value};
 use std::convert::From;
 use std::fmt;
+use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 #[derive(Debug)]
@@ -10,6 +15,20 @@ pub enum QueryCreationError {
     UnknownField(String),
     MissingArgument(String),
     InvalidArgument(String, String),
+    UnsupportedAlias(String),
+}
+
+impl QueryCreationError {
+    // The kind of error, for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Composite(_) => "composite",
+            Self::UnknownField(_) => "unknown_field",
+            Self::MissingArgument(_) => "missing_argument",
+            Self::InvalidArgument(_, _) => "invalid_argument",
+            Self::UnsupportedAlias(_) => "unsupported_alias",
+        }
+    }
 }
 
 impl fmt::Display for QueryCreationError {
@@ -19,6 +38,13 @@ impl fmt::Display for QueryCreationError {
             Self::UnknownField(field) => write!(f, "Unknown field: {}", field),
             Self::MissingArgument(arg) => write!(f, "Missing argument: {}", arg),
             Self::InvalidArgument(arg, msg) => write!(f, "Invalid argument: {} - {}", arg, msg),
+            Self::UnsupportedAlias(alias) => {
+                write!(
+                    f,
+                    "Unsupported alias: {} - only top level fields can be aliased",
+                    alias
+                )
+            }
         }
     }
 }
@@ -44,16 +70,69 @@ impl fmt::Display for CompositeQueryCreationError {
 #[derive(Debug)]
 pub enum DgraphQueryError {
     Dgraph(DgraphError),
+    // A failure which may not happen again, such as an alpha restarting or a query timing out
+    Transient(grpcio::Error),
+    // Requests aren't being sent as Dgraph has been failing
+    CircuitOpen,
     InvalidUTF(FromUtf8Error),
     InvalidJson(serde_json::Error),
 }
 
+impl DgraphQueryError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Transient(_))
+    }
+
+    // The kind of error, for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Dgraph(_) => "dgraph",
+            Self::Transient(_) => "transient",
+            Self::CircuitOpen => "circuit_open",
+            Self::InvalidUTF(_) => "invalid_utf",
+            Self::InvalidJson(_) => "invalid_json",
+        }
+    }
+}
+
+impl fmt::Display for DgraphQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dgraph(err) => write!(f, "Dgraph error: {}", err),
+            Self::Transient(err) => write!(f, "Dgraph unavailable: {}", err),
+            Self::CircuitOpen => write!(f, "Dgraph unavailable: circuit breaker is open"),
+            Self::InvalidUTF(err) => write!(f, "Invalid UTF-8 in response: {}", err),
+            Self::InvalidJson(err) => write!(f, "Invalid JSON in response: {}", err),
+        }
+    }
+}
+
 impl From<DgraphError> for DgraphQueryError {
     fn from(err: DgraphError) -> DgraphQueryError {
         DgraphQueryError::Dgraph(err)
     }
 }
 
+impl From<grpcio::Error> for DgraphQueryError {
+    fn from(err: grpcio::Error) -> DgraphQueryError {
+        match &err {
+            grpcio::Error::RpcFailure(status)
+                if matches!(
+                    status.status,
+                    RpcStatusCode::Unavailable
+                        | RpcStatusCode::DeadlineExceeded
+                        | RpcStatusCode::ResourceExhausted
+                        | RpcStatusCode::Aborted
+                ) =>
+            {
+                DgraphQueryError::Transient(err)
+            }
+            grpcio::Error::RemoteStopped => DgraphQueryError::Transient(err),
+            _ => DgraphQueryError::Dgraph(DgraphError::GrpcError(err)),
+        }
+    }
+}
+
 impl From<FromUtf8Error> for DgraphQueryError {
     fn from(err: FromUtf8Error) -> DgraphQueryError {
         DgraphQueryError::InvalidUTF(err)
@@ -66,17 +145,483 @@ impl From<serde_json::Error> for DgraphQueryError {
     }
 }
 
-const INTERNAL_ERROR: &str = "internal_error";
-const INVALID_INPUT: &str = "invalid_input";
-const UNABLE_TO_RESOLVE_FIELD: &str = "Unable to resolve field";
+#[derive(Debug)]
+pub enum StorageError {
+    QueryCreation(QueryCreationError),
+    Dgraph(DgraphQueryError),
+    MalformedResponse(String),
+    // Predicates from schema.dgraph which Dgraph doesn't have
+    MissingPredicates(Vec<String>),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QueryCreation(err) => write!(f, "{}", err),
+            Self::Dgraph(err) => write!(f, "{}", err),
+            Self::MalformedResponse(msg) => write!(f, "Malformed response: {}", msg),
+            Self::MissingPredicates(predicates) => {
+                write!(f, "Schema is missing predicates: {}", predicates.join(", "))
+            }
+        }
+    }
+}
+
+impl From<QueryCreationError> for StorageError {
+    fn from(err: QueryCreationError) -> StorageError {
+        StorageError::QueryCreation(err)
+    }
+}
+
+impl From<DgraphQueryError> for StorageError {
+    fn from(err: DgraphQueryError) -> StorageError {
+        StorageError::Dgraph(err)
+    }
+}
+
+#[derive(Debug)]
+pub struct RdfParseError {
+    pub line: usize,
+    pub msg: String,
+}
 
+impl fmt::Display for RdfParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid RDF on line {}: {}", self.line, self.msg)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "Unable to read {}: {}", path.display(), err),
+            Self::Parse(err) => write!(f, "Invalid config file: {}", err),
+            Self::Invalid(msg) => write!(f, "Invalid config: {}", msg),
+        }
+    }
+}
+
+impl From<ConfigError> for io::Error {
+    fn from(err: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    // The Authorization header isn't of the form "Bearer <key>"
+    MalformedHeader,
+    UnknownKey,
+    Session(SessionError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedHeader => write!(f, "Authorization header must be a bearer API key"),
+            Self::UnknownKey => write!(f, "Unknown API key"),
+            Self::Session(err) => write!(f, "Invalid session - {}", err),
+        }
+    }
+}
+
+// Subscriptions report authentication errors through the graphql-ws connection, which needs them
+// to be errors
+impl std::error::Error for AuthError {}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    // Discord login isn't configured, so there's no secret to check the token with
+    Disabled,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "Malformed session token"),
+            Self::InvalidSignature => write!(f, "Session token signature doesn't match"),
+            Self::Expired => write!(f, "Session has expired"),
+            Self::Disabled => write!(f, "Discord login is disabled"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OAuthError {
+    // Discord couldn't be reached or sent back something unreadable
+    Request(String),
+    // Discord refused the request, eg. as the authorization code was invalid or had been used
+    Rejected(u16),
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(msg) => write!(f, "Discord request failed: {}", msg),
+            Self::Rejected(status) => write!(f, "Discord rejected the request with {}", status),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ComplexityError {
+    TooDeep { depth: u32, max_depth: u32 },
+    TooCostly { cost: u64, max_cost: u64 },
+}
+
+impl fmt::Display for ComplexityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooDeep { depth, max_depth } => write!(
+                f,
+                "Query is too deep: {} levels of fields, but at most {} are allowed",
+                depth, max_depth
+            ),
+            Self::TooCostly { cost, max_cost } => write!(
+                f,
+                "Query is too costly: it could fetch {} fields, but at most {} are allowed",
+                cost, max_cost
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PersistedQueryError {
+    // The hash isn't known, so the client should send it again along with the query
+    NotFound,
+    HashMismatch,
+    UnsupportedVersion(i64),
+    // Only queries from the manifest are allowed
+    NotPersisted,
+    MissingQuery,
+}
+
+impl PersistedQueryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound => "PERSISTED_QUERY_NOT_FOUND",
+            Self::NotPersisted => "PERSISTED_QUERY_REQUIRED",
+            Self::HashMismatch | Self::UnsupportedVersion(_) | Self::MissingQuery => {
+                "INVALID_REQUEST"
+            }
+        }
+    }
+}
+
+impl fmt::Display for PersistedQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Apollo's clients look for this exact message before retrying with the query
+            Self::NotFound => write!(f, "PersistedQueryNotFound"),
+            Self::HashMismatch => write!(f, "Persisted query hash doesn't match the query"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported persisted query version {}", version)
+            }
+            Self::NotPersisted => write!(f, "Only persisted queries are allowed"),
+            Self::MissingQuery => write!(f, "Request must have a query or persisted query hash"),
+        }
+    }
+}
+
+// Codes given in the "code" extension of every error, which clients can rely on staying the same
+const INVALID_ARGUMENT: &str = "INVALID_ARGUMENT";
+const UNKNOWN_FIELD: &str = "UNKNOWN_FIELD";
+const BACKEND_UNAVAILABLE: &str = "BACKEND_UNAVAILABLE";
+const NOT_FOUND: &str = "NOT_FOUND";
+const DATA_CORRUPT: &str = "DATA_CORRUPT";
+const MUTATIONS_DISABLED: &str = "MUTATIONS_DISABLED";
+const FORBIDDEN: &str = "FORBIDDEN";
+const QUERY_TOO_COMPLEX: &str = "QUERY_TOO_COMPLEX";
+const INTERNAL_ERROR: &str = "INTERNAL_ERROR";
+
+// Something unexpected went wrong, which has been logged
 pub fn internal_error() -> FieldError {
     FieldError::new(
-        UNABLE_TO_RESOLVE_FIELD,
-        graphql_value!({ INTERNAL_ERROR: INTERNAL_ERROR }),
+        "Unable to resolve field",
+        graphql_value!({ "code": INTERNAL_ERROR }),
+    )
+}
+
+pub fn invalid_argument(argument: &str, msg: &str) -> FieldError {
+    FieldError::new(
+        msg,
+        graphql_value!({ "code": INVALID_ARGUMENT, "argument": argument }),
+    )
+}
+
+// Dgraph can't be reached, so the request may succeed if tried again later
+pub fn backend_unavailable() -> FieldError {
+    FieldError::new(
+        "Storage is unavailable",
+        graphql_value!({ "code": BACKEND_UNAVAILABLE }),
+    )
+}
+
+pub fn not_found(msg: &str) -> FieldError {
+    FieldError::new(msg, graphql_value!({ "code": NOT_FOUND }))
+}
+
+// Stored data isn't in the shape the schema expects, eg. a haiku without any content
+pub fn data_corrupt() -> FieldError {
+    FieldError::new(
+        "Stored data is invalid",
+        graphql_value!({ "code": DATA_CORRUPT }),
+    )
+}
+
+pub fn mutations_disabled() -> FieldError {
+    FieldError::new(
+        "Mutations are disabled",
+        graphql_value!({ "code": MUTATIONS_DISABLED }),
+    )
+}
+
+// The request's API key, or lack of one, doesn't have the scope needed
+pub fn forbidden(scope: Scope) -> FieldError {
+    let scope = scope.to_string();
+    FieldError::new(
+        format!("Missing scope: {}", scope),
+        graphql_value!({ "code": FORBIDDEN, "scope": scope }),
+    )
+}
+
+// Viewers logged in with Discord can only see the servers they're a member of
+pub fn not_a_member() -> FieldError {
+    FieldError::new(
+        "Not a member of this server",
+        graphql_value!({ "code": FORBIDDEN }),
     )
 }
 
-pub fn invalid_input(msg: &str) -> FieldError {
-    FieldError::new(INVALID_INPUT, graphql_value!({ INVALID_INPUT: msg }))
+// The query would make Dgraph do too much work, so it's refused before anything is fetched
+pub fn query_too_complex(err: ComplexityError) -> FieldError {
+    // Costs multiply with nesting, so they're capped to fit in a GraphQL Int
+    let int = |value: u64| value.min(i32::MAX as u64) as i32;
+    let details = match err {
+        ComplexityError::TooDeep { depth, max_depth } => vec![
+            ("depth", Value::scalar(depth as i32)),
+            ("maxDepth", Value::scalar(max_depth as i32)),
+        ],
+        ComplexityError::TooCostly { cost, max_cost } => vec![
+            ("cost", Value::scalar(int(cost))),
+            ("maxCost", Value::scalar(int(max_cost))),
+        ],
+    };
+    coded_error(&err.to_string(), QUERY_TOO_COMPLEX, details)
+}
+
+// The errors making up a query creation error, each with the path of fields leading to it
+fn leaf_errors<'a>(
+    err: &'a QueryCreationError,
+    path: &mut Vec<&'a str>,
+    leaves: &mut Vec<(Vec<&'a str>, &'a QueryCreationError)>,
+) {
+    match err {
+        QueryCreationError::Composite(composite) => {
+            path.push(&composite.at_field);
+            for child in &composite.children {
+                leaf_errors(child, path, leaves);
+            }
+            path.pop();
+        }
+        err => leaves.push((path.clone(), err)),
+    }
+}
+
+fn leaf_details<'a>(
+    path: &[&'a str],
+    err: &'a QueryCreationError,
+) -> (&'static str, Vec<(&'static str, Value)>) {
+    let path = |field: Option<&str>| {
+        Value::list(
+            path.iter()
+                .cloned()
+                .chain(field)
+                .map(Value::scalar)
+                .collect(),
+        )
+    };
+    match err {
+        QueryCreationError::UnknownField(field) | QueryCreationError::UnsupportedAlias(field) => (
+            UNKNOWN_FIELD,
+            vec![
+                ("path", path(Some(field))),
+                ("field", Value::scalar(field.as_str())),
+            ],
+        ),
+        QueryCreationError::MissingArgument(argument)
+        | QueryCreationError::InvalidArgument(argument, _) => (
+            INVALID_ARGUMENT,
+            vec![
+                ("path", path(None)),
+                ("argument", Value::scalar(argument.as_str())),
+            ],
+        ),
+        QueryCreationError::Composite(_) => unreachable!(),
+    }
+}
+
+fn coded_error(msg: &str, code: &str, details: Vec<(&str, Value)>) -> FieldError {
+    let mut extensions = Object::with_capacity(details.len() + 1);
+    extensions.add_field("code", Value::scalar(code));
+    for (key, value) in details {
+        extensions.add_field(key, value);
+    }
+    FieldError::new(msg, Value::Object(extensions))
+}
+
+// Reports an error which made a query impossible to create, counting its kinds. Composite errors
+// give the details of their first error, along with every error under "errors" if there are more.
+pub fn query_creation_error(err: QueryCreationError) -> FieldError {
+    metrics::count_query_creation_error(&err);
+    let mut leaves = Vec::new();
+    leaf_errors(&err, &mut Vec::new(), &mut leaves);
+    let (code, mut details) = match leaves.first() {
+        Some((path, leaf)) => leaf_details(path, leaf),
+        None => return internal_error(),
+    };
+    if leaves.len() > 1 {
+        let errors = leaves
+            .iter()
+            .map(|(path, leaf)| {
+                let (code, details) = leaf_details(path, leaf);
+                let mut error = Object::with_capacity(details.len() + 2);
+                error.add_field("code", Value::scalar(code));
+                error.add_field("message", Value::scalar(leaf.to_string()));
+                for (key, value) in details {
+                    error.add_field(key, value);
+                }
+                Value::Object(error)
+            })
+            .collect();
+        details.push(("errors", Value::list(errors)));
+    }
+    let msg = match leaves.as_slice() {
+        [(_, leaf)] => leaf.to_string(),
+        _ => err.to_string(),
+    };
+    coded_error(&msg, code, details)
+}
+
+pub fn storage_error(err: StorageError) -> FieldError {
+    match err {
+        StorageError::QueryCreation(err) => query_creation_error(err),
+        // The circuit breaker logs when it opens, rather than for every request turned away
+        StorageError::Dgraph(DgraphQueryError::CircuitOpen) => backend_unavailable(),
+        StorageError::Dgraph(err @ DgraphQueryError::InvalidUTF(_))
+        | StorageError::Dgraph(err @ DgraphQueryError::InvalidJson(_)) => {
+            error!("Storage error - {}", err);
+            data_corrupt()
+        }
+        StorageError::MalformedResponse(msg) => {
+            error!("Storage error - Malformed response: {}", msg);
+            data_corrupt()
+        }
+        // Errors which retrying won't fix, such as a query Dgraph rejects, are bugs in the API
+        StorageError::Dgraph(err @ DgraphQueryError::Dgraph(_)) => {
+            error!("Storage error - {}", err);
+            internal_error()
+        }
+        err => {
+            error!("Storage error - {}", err);
+            backend_unavailable()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(err, code,
+        case(StorageError::Dgraph(DgraphQueryError::Dgraph(DgraphError::EmptyTxn)), INTERNAL_ERROR),
+        case(
+            StorageError::Dgraph(DgraphQueryError::Transient(grpcio::Error::RemoteStopped)),
+            BACKEND_UNAVAILABLE
+        ),
+        case(StorageError::Dgraph(DgraphQueryError::CircuitOpen), BACKEND_UNAVAILABLE),
+        case(StorageError::MissingPredicates(vec!["content".to_owned()]), BACKEND_UNAVAILABLE),
+        case(StorageError::MalformedResponse("missing uid".to_owned()), DATA_CORRUPT),
+    )]
+    fn map_storage_errors(err: StorageError, code: &str) {
+        assert_eq!(
+            storage_error(err)
+                .extensions()
+                .as_object_value()
+                .unwrap()
+                .get_field_value("code"),
+            Some(&Value::scalar(code))
+        );
+    }
+
+    #[test]
+    fn flatten_composite_errors() {
+        let err = QueryCreationError::Composite(CompositeQueryCreationError {
+            at_field: "user".to_owned(),
+            children: vec![QueryCreationError::Composite(CompositeQueryCreationError {
+                at_field: "haikus".to_owned(),
+                children: vec![QueryCreationError::InvalidArgument(
+                    "first".to_owned(),
+                    "must not be negative".to_owned(),
+                )],
+            })],
+        });
+        assert_eq!(
+            query_creation_error(err),
+            FieldError::new(
+                "Invalid argument: first - must not be negative",
+                graphql_value!({
+                    "code": "INVALID_ARGUMENT",
+                    "path": ["user", "haikus"],
+                    "argument": "first",
+                })
+            )
+        );
+
+        let err = QueryCreationError::Composite(CompositeQueryCreationError {
+            at_field: "haiku".to_owned(),
+            children: vec![
+                QueryCreationError::UnsupportedAlias("id2".to_owned()),
+                QueryCreationError::MissingArgument("terms".to_owned()),
+            ],
+        });
+        let msg = err.to_string();
+        assert_eq!(
+            query_creation_error(err),
+            FieldError::new(
+                msg,
+                graphql_value!({
+                    "code": "UNKNOWN_FIELD",
+                    "path": ["haiku", "id2"],
+                    "field": "id2",
+                    "errors": [
+                        {
+                            "code": "UNKNOWN_FIELD",
+                            "message": "Unsupported alias: id2 - only top level fields can be aliased",
+                            "path": ["haiku", "id2"],
+                            "field": "id2",
+                        },
+                        {
+                            "code": "INVALID_ARGUMENT",
+                            "message": "Missing argument: terms",
+                            "path": ["haiku"],
+                            "argument": "terms",
+                        },
+                    ],
+                })
+            )
+        );
+    }
 }
@@ -0,0 +1,80 @@
+use futures::channel::mpsc;
+use std::sync::Mutex;
+
+// How many events a subscriber can fall behind by before it starts missing them
+const SUBSCRIBER_BUFFER: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HaikuAdded {
+    pub haiku_id: String,
+    pub server_snowflake: String,
+    pub channel_snowflake: String,
+}
+
+// Passes newly added haikus on to subscriptions. Only haikus added through this instance are
+// seen, so subscribers should connect to the instance the bot adds haikus through.
+#[derive(Debug, Default)]
+pub struct HaikuEvents {
+    subscribers: Mutex<Vec<mpsc::Sender<HaikuAdded>>>,
+}
+
+impl HaikuEvents {
+    pub fn subscribe(&self) -> mpsc::Receiver<HaikuAdded> {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Sends the event to every subscriber without waiting on any of them, dropping subscribers
+    // which have gone away
+    pub fn publish(&self, event: HaikuAdded) {
+        self.subscribers.lock().unwrap().retain_mut(|subscriber| {
+            match subscriber.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(err) if err.is_full() => {
+                    warn!(
+                        "Subscriber is falling behind, skipping haiku {}",
+                        event.haiku_id
+                    );
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(haiku_id: &str) -> HaikuAdded {
+        HaikuAdded {
+            haiku_id: haiku_id.to_owned(),
+            server_snowflake: "1".to_owned(),
+            channel_snowflake: "2".to_owned(),
+        }
+    }
+
+    #[test]
+    fn publish_to_subscribers() {
+        let events = HaikuEvents::default();
+        let mut first = events.subscribe();
+        let second = events.subscribe();
+        drop(second);
+        events.publish(event("0x1"));
+        assert_eq!(first.try_next().unwrap(), Some(event("0x1")));
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+
+        for i in 0..SUBSCRIBER_BUFFER + 2 {
+            events.publish(event(&format!("{:#x}", i)));
+        }
+        let mut received = 0;
+        while let Ok(Some(_)) = first.try_next() {
+            received += 1;
+        }
+        // Each sender gets a guaranteed slot on top of the buffer
+        assert_eq!(received, SUBSCRIBER_BUFFER + 1);
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+    }
+}
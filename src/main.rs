@@ -1,12 +1,21 @@
+mod auth;
+mod config;
+mod detection;
+mod dql;
 mod error;
+mod events;
+mod metrics;
+mod oauth;
+mod persisted_queries;
+mod request_id;
 mod schema;
+mod session;
+mod storage;
+mod subscriptions;
 
 #[macro_use]
 extern crate juniper;
 #[macro_use]
-extern crate dgraph;
-#[allow(unused_imports)]
-#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate log;
@@ -14,69 +23,532 @@ extern crate regex;
 #[macro_use]
 extern crate lazy_static;
 
-use actix_web::{middleware, web, App, Error, HttpResponse, HttpServer};
+use actix_web::http::header;
+use actix_web::{middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use auth::Caller;
+use config::{Config, Options, StorageBackend};
+use error::PersistedQueryError;
+use events::HaikuEvents;
+use futures::future;
 use juniper::http::graphiql::graphiql_source;
-use juniper::http::GraphQLRequest;
-use juniper::EmptyMutation;
-use schema::{Context, Query, Schema};
+use juniper::http::{GraphQLRequest, GraphQLResponse};
+use persisted_queries::{GraphQLBatchPayload, PersistedQueries};
+use schema::{Context, Schema};
 use std::io;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
+use storage::{DgraphStorage, MemoryStorage, Storage};
+use structopt::StructOpt;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 
-const PROTOCOL: &str = "http";
-const HOSTNAME: &str = "127.0.0.1";
-const PORT: u32 = 4000;
-
-const DGRAPH_HOSTNAME: &str = "127.0.0.1";
-const DGRAPH_PORT: u32 = 9080;
-
-async fn graphiql() -> HttpResponse {
-    let html = graphiql_source(&format!("{}://{}:{}/graphql", PROTOCOL, HOSTNAME, PORT));
+async fn graphiql(config: web::Data<Arc<Config>>) -> HttpResponse {
+    // Subscriptions are made over a WebSocket at the same host
+    let subscriptions_url = format!(
+        "ws{}/subscriptions",
+        config.public_url().trim_start_matches("http")
+    );
+    let html = graphiql_source(
+        &format!("{}/graphql", config.public_url()),
+        Some(&subscriptions_url),
+    );
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(html)
 }
 
+// Everything needed to resolve a request made by the caller
+fn request_context(
+    storage: Arc<dyn Storage>,
+    events: Arc<HaikuEvents>,
+    config: &Config,
+    caller: Caller,
+) -> Context {
+    Context {
+        storage,
+        mutations_enabled: config.features.mutations,
+        scopes: caller.scopes,
+        viewer: caller.viewer,
+        limits: config.limits,
+        spent_cost: Arc::default(),
+        events,
+        lookups: Default::default(),
+    }
+}
+
+// The body of a response made up of a single error, for requests which couldn't be executed
+fn error_body(message: &str, code: &str) -> serde_json::Value {
+    json!({
+        "errors": [{
+            "message": message,
+            "extensions": { "code": code },
+        }],
+    })
+}
+
+async fn execute<'a>(
+    st: &'a Schema,
+    context: &'a Context,
+    persisted: &PersistedQueries,
+    data: &'a GraphQLRequest,
+) -> GraphQLResponse<'a> {
+    let operation = metrics::operation_label(data.operation_name(), persisted.operations());
+    let span = tracing::info_span!("graphql", operation);
+    let start = Instant::now();
+    let res = data.execute(st, context).instrument(span).await;
+    metrics::observe_request(operation, res.is_ok(), start.elapsed());
+    res
+}
+
 async fn graphql(
     st: web::Data<Arc<Schema>>,
-    context: web::Data<Arc<Context>>,
-    data: web::Json<GraphQLRequest>,
+    storage: web::Data<Arc<dyn Storage>>,
+    events: web::Data<Arc<HaikuEvents>>,
+    config: web::Data<Arc<Config>>,
+    persisted: web::Data<Arc<PersistedQueries>>,
+    req: HttpRequest,
+    payload: web::Json<GraphQLBatchPayload>,
 ) -> Result<HttpResponse, Error> {
-    let user = web::block(move || {
-        let res = data.execute(&st, &context);
-        Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
-    })
-    .await?;
+    let caller = match auth::authenticate(&config.auth, req.headers().get(header::AUTHORIZATION)) {
+        Ok(caller) => caller,
+        Err(err) => {
+            warn!("Rejected request - {}", err);
+            return Ok(
+                HttpResponse::Unauthorized().json(error_body(&err.to_string(), "UNAUTHENTICATED"))
+            );
+        }
+    };
+    // Requests in a batch share the context, so lookups are batched and one cost budget covers the
+    // whole batch, as if its requests had been sent as one
+    let context = request_context(
+        storage.get_ref().clone(),
+        events.get_ref().clone(),
+        &config,
+        caller,
+    );
+    let body = match payload.into_inner() {
+        GraphQLBatchPayload::Single(payload) => {
+            let data = match persisted.resolve(payload) {
+                Ok(data) => data,
+                Err(err) => return Ok(persisted_query_error(err)),
+            };
+            serde_json::to_string(&execute(&st, &context, &persisted, &data).await)?
+        }
+        GraphQLBatchPayload::Batch(payloads) => {
+            let max_batch_size = config.limits.max_batch_size;
+            if payloads.is_empty() || payloads.len() > max_batch_size {
+                let message = format!(
+                    "Batches must have between 1 and {} requests",
+                    max_batch_size
+                );
+                return Ok(HttpResponse::BadRequest().json(error_body(&message, "INVALID_REQUEST")));
+            }
+            // Each request is answered separately, even if others in the batch couldn't be run
+            let respond = |payload| async {
+                match persisted.resolve(payload) {
+                    Ok(data) => {
+                        serde_json::to_value(&execute(&st, &context, &persisted, &data).await)
+                    }
+                    Err(err) => Ok(error_body(&err.to_string(), err.code())),
+                }
+            };
+            // Later requests may rely on what mutations write, so batches with any in them are run
+            // in order, while others are run together so that their lookups are batched
+            let responses = if payloads.iter().any(|payload| persisted.may_mutate(payload)) {
+                let mut responses = Vec::with_capacity(payloads.len());
+                for payload in payloads {
+                    responses.push(respond(payload).await?);
+                }
+                responses
+            } else {
+                future::join_all(payloads.into_iter().map(respond))
+                    .await
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+            serde_json::to_string(&responses)?
+        }
+    };
     Ok(HttpResponse::Ok()
         .content_type("application/json")
-        .body(user))
+        .body(body))
+}
+
+// Unknown hashes are reported like any other GraphQL error, so that clients retry with the query
+fn persisted_query_error(err: PersistedQueryError) -> HttpResponse {
+    let mut response = match err {
+        PersistedQueryError::NotFound => HttpResponse::Ok(),
+        _ => HttpResponse::BadRequest(),
+    };
+    response.json(error_body(&err.to_string(), err.code()))
+}
+
+async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics::render())
+}
+
+// Whether the process is up, regardless of whether it can serve requests
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+// Whether requests can be served, which needs the storage backend to be reachable and set up
+async fn readyz(storage: web::Data<Arc<dyn Storage>>) -> HttpResponse {
+    match storage.check_ready().await {
+        Ok(()) => HttpResponse::Ok().json(json!({ "status": "ready" })),
+        Err(err) => {
+            warn!("Not ready - {}", err);
+            HttpResponse::ServiceUnavailable().json(json!({ "status": "unavailable" }))
+        }
+    }
+}
+
+async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "apiVersion": schema::API_VERSION,
+    }))
+}
+
+// Routes for orchestrators checking on the instance
+fn health_routes(config: &mut web::ServiceConfig) {
+    config
+        .service(web::resource("/healthz").route(web::get().to(healthz)))
+        .service(web::resource("/readyz").route(web::get().to(readyz)))
+        .service(web::resource("/version").route(web::get().to(version)));
+}
+
+// In-memory storage, seeded from an RDF file if one is given
+fn memory_storage(seed_data: Option<&Path>) -> io::Result<MemoryStorage> {
+    match seed_data {
+        Some(path) => {
+            let rdf = std::fs::read_to_string(path)?;
+            MemoryStorage::from_rdf(&rdf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+        }
+        None => Ok(MemoryStorage::new()),
+    }
 }
 
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
-    std::env::set_var("RUST_LOG", "actix_web=info,haikubot_rs_api");
-    env_logger::init();
+    let options = Options::from_args();
+    if options.hash_api_key {
+        let mut key = String::new();
+        io::stdin().read_line(&mut key)?;
+        println!("{}", auth::hash_key(key.trim()));
+        return Ok(());
+    }
+    let print_config = options.print_config;
+    let config = Config::load(options)?;
+    if print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    // Records from the log crate, including actix-web's, are passed on to the subscriber too
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log.filter))
+        .init();
 
     // Create Juniper schema
-    let schema = std::sync::Arc::new(Schema::new(Query, EmptyMutation::new()));
+    let schema = std::sync::Arc::new(schema::schema());
+
+    // Create storage backend
+    let storage: Arc<dyn Storage> = match config.storage.backend {
+        StorageBackend::Memory => Arc::new(memory_storage(config.storage.seed_data.as_deref())?),
+        StorageBackend::Dgraph => Arc::new(DgraphStorage::new(
+            config
+                .storage
+                .dgraph_endpoints
+                .iter()
+                .map(|endpoint| dgraph::new_dgraph_client(endpoint))
+                .collect(),
+            &config.storage,
+        )),
+    };
+
+    let persisted = Arc::new(PersistedQueries::load(&config.persisted_queries)?);
 
-    //Create Dgraph client
-    let context = std::sync::Arc::new(Context {
-        dgraph_client: make_dgraph!(dgraph::new_dgraph_client(&format!(
-            "{}:{}",
-            DGRAPH_HOSTNAME, DGRAPH_PORT
-        ))),
-    });
+    // Shared between workers, so that subscriptions see haikus added through any of them
+    let events = Arc::new(HaikuEvents::default());
 
     // Start http server
+    let address = format!("{}:{}", config.server.host, config.server.port);
+    let config = Arc::new(config);
     HttpServer::new(move || {
-        App::new()
+        let app = App::new()
             .data(schema.clone())
-            .data(context.clone())
+            .data(storage.clone())
+            .data(events.clone())
+            .data(config.clone())
+            .data(persisted.clone())
             .wrap(middleware::Logger::default())
+            .wrap_fn(request_id::trace_request)
             .service(web::resource("/graphql").route(web::post().to(graphql)))
-            .service(web::resource("/graphiql").route(web::get().to(graphiql)))
+            .service(
+                web::resource("/subscriptions").route(web::get().to(subscriptions::subscriptions)),
+            )
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .configure(health_routes);
+        let app = match &config.auth.discord {
+            Some(discord) => app.configure(oauth::routes(discord.clone())),
+            None => app,
+        };
+        if config.features.graphiql {
+            app.service(web::resource("/graphiql").route(web::get().to(graphiql)))
+        } else {
+            app
+        }
     })
-    .bind(format!("{}:{}", HOSTNAME, PORT))?
+    .bind(address)?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+    use async_trait::async_trait;
+    use auth::Scope;
+    use config::ApiKeyConfig;
+    use error::StorageError;
+    use schema::NewHaiku;
+
+    // Storage whose schema is missing a predicate, so every request fails
+    struct UnreadyStorage;
+
+    fn missing_predicates() -> StorageError {
+        StorageError::MissingPredicates(vec!["content".to_owned()])
+    }
+
+    #[async_trait]
+    impl Storage for UnreadyStorage {
+        async fn query(&self, _query: &dql::Query) -> Result<serde_json::Value, StorageError> {
+            Err(missing_predicates())
+        }
+
+        async fn add_haiku(&self, _haiku: &NewHaiku) -> Result<String, StorageError> {
+            Err(missing_predicates())
+        }
+
+        async fn check_ready(&self) -> Result<(), StorageError> {
+            Err(missing_predicates())
+        }
+    }
+
+    async fn get(uri: &str, storage: impl Storage + 'static) -> (StatusCode, serde_json::Value) {
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        let mut app = test::init_service(App::new().data(storage).configure(health_routes)).await;
+        let response =
+            test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = response.status();
+        let body = test::read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn health_endpoints() {
+        assert_eq!(
+            get("/healthz", UnreadyStorage).await,
+            (StatusCode::OK, json!({ "status": "ok" }))
+        );
+        assert_eq!(
+            get("/readyz", MemoryStorage::new()).await,
+            (StatusCode::OK, json!({ "status": "ready" }))
+        );
+        assert_eq!(
+            get("/readyz", UnreadyStorage).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({ "status": "unavailable" })
+            )
+        );
+        assert_eq!(
+            get("/version", UnreadyStorage).await,
+            (
+                StatusCode::OK,
+                json!({ "version": "0.1.0", "apiVersion": "1.0" })
+            )
+        );
+    }
+
+    #[actix_rt::test]
+    async fn echo_request_id() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(request_id::trace_request)
+                .configure(health_routes),
+        )
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/healthz")
+            .header(request_id::REQUEST_ID_HEADER, "req_42")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(
+            response
+                .headers()
+                .get(request_id::REQUEST_ID_HEADER)
+                .unwrap(),
+            "req_42"
+        );
+    }
+
+    const CHECK_HAIKU: &str = r#"{ checkHaiku(text: "pond") { isHaiku } }"#;
+
+    async fn post_graphql(
+        authorization: Option<&str>,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut config = Config::default();
+        config.auth.api_keys.push(ApiKeyConfig {
+            name: "website".to_owned(),
+            hash: auth::hash_key("website-key"),
+            scopes: vec![Scope::Read],
+        });
+        config.auth.api_keys.push(ApiKeyConfig {
+            name: "bot".to_owned(),
+            hash: auth::hash_key("bot-key"),
+            scopes: vec![Scope::Read, Scope::WriteHaikus],
+        });
+        let mut app = test::init_service(
+            App::new()
+                .data(Arc::new(schema::schema()))
+                .data(storage)
+                .data(Arc::new(HaikuEvents::default()))
+                .data(Arc::new(PersistedQueries::new(
+                    &config.persisted_queries,
+                    Default::default(),
+                )))
+                .data(Arc::new(config))
+                .service(web::resource("/graphql").route(web::post().to(graphql))),
+        )
+        .await;
+        let mut request = test::TestRequest::post().uri("/graphql").set_json(&body);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let response = test::call_service(&mut app, request.to_request()).await;
+        let status = response.status();
+        let body = test::read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn authenticate_graphql_requests() {
+        let body = json!({ "query": CHECK_HAIKU });
+        assert_eq!(
+            post_graphql(Some("Bearer website-key"), body.clone()).await,
+            (
+                StatusCode::OK,
+                json!({ "data": { "checkHaiku": { "isHaiku": false } } })
+            )
+        );
+        let (status, response) = post_graphql(None, body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        let (status, response) = post_graphql(Some("Bearer other-key"), body).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response["errors"][0]["extensions"]["code"],
+            "UNAUTHENTICATED"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn batch_graphql_requests() {
+        let persisted = json!({
+            "extensions": {
+                "persistedQuery": { "version": 1, "sha256Hash": persisted_queries::query_hash("{ apiVersion }") },
+            },
+        });
+        let (status, response) = post_graphql(
+            Some("Bearer website-key"),
+            json!([{ "query": CHECK_HAIKU }, persisted, { "query": "{ apiVersion }" }]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response,
+            json!([
+                { "data": { "checkHaiku": { "isHaiku": false } } },
+                {
+                    "errors": [{
+                        "message": "PersistedQueryNotFound",
+                        "extensions": { "code": "PERSISTED_QUERY_NOT_FOUND" },
+                    }],
+                },
+                { "data": { "apiVersion": "1.0" } },
+            ])
+        );
+        // Batches with mutations are run in order, so later requests see what they wrote
+        let add_haiku = r#"mutation {
+            addHaiku(input: {
+                authorSnowflakes: ["3"],
+                channelSnowflake: "2",
+                serverSnowflake: "1",
+                content: "an old silent pond",
+                rulesVersion: 1,
+                timestamp: "2020-01-01T00:00:00Z",
+            }) { content }
+        }"#;
+        let (status, response) = post_graphql(
+            Some("Bearer bot-key"),
+            json!([{ "query": add_haiku }, { "query": "{ haikus { totalCount } }" }]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response,
+            json!([
+                { "data": { "addHaiku": { "content": "an old silent pond" } } },
+                { "data": { "haikus": { "totalCount": 1 } } },
+            ])
+        );
+        for batch in &[json!([]), json!(vec![json!({ "query": CHECK_HAIKU }); 11])] {
+            let (status, response) = post_graphql(Some("Bearer website-key"), batch.clone()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(
+                response["errors"][0]["extensions"]["code"],
+                "INVALID_REQUEST"
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn resolve_persisted_queries() {
+        let extensions = json!({
+            "persistedQuery": { "version": 1, "sha256Hash": persisted_queries::query_hash("{ apiVersion }") },
+        });
+        let (status, response) = post_graphql(
+            Some("Bearer website-key"),
+            json!({ "extensions": extensions }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            response,
+            json!({
+                "errors": [{
+                    "message": "PersistedQueryNotFound",
+                    "extensions": { "code": "PERSISTED_QUERY_NOT_FOUND" },
+                }],
+            })
+        );
+        let (status, response) = post_graphql(
+            Some("Bearer website-key"),
+            json!({ "query": CHECK_HAIKU, "extensions": extensions }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response["errors"][0]["extensions"]["code"],
+            "INVALID_REQUEST"
+        );
+    }
+}
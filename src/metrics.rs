@@ -0,0 +1,187 @@
+use super::error::{DgraphQueryError, QueryCreationError};
+use juniper::FieldResult;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref GRAPHQL_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "haikubot_graphql_requests_total",
+        "GraphQL requests by operation name and whether they had any errors",
+        &["operation", "status"]
+    )
+    .unwrap();
+    static ref GRAPHQL_DURATION: HistogramVec = register_histogram_vec!(
+        "haikubot_graphql_request_duration_seconds",
+        "Time taken to execute GraphQL requests by operation name",
+        &["operation"]
+    )
+    .unwrap();
+    static ref FIELD_DURATION: HistogramVec = register_histogram_vec!(
+        "haikubot_graphql_field_duration_seconds",
+        "Time taken to resolve top level fields, including their storage queries",
+        &["field"]
+    )
+    .unwrap();
+    static ref FIELD_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "haikubot_graphql_field_errors_total",
+        "Top level fields which resolved to an error",
+        &["field"]
+    )
+    .unwrap();
+    static ref DGRAPH_DURATION: HistogramVec = register_histogram_vec!(
+        "haikubot_dgraph_request_duration_seconds",
+        "Time taken by Dgraph requests, including any retries",
+        &["request"]
+    )
+    .unwrap();
+    static ref DGRAPH_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "haikubot_dgraph_errors_total",
+        "Failed Dgraph requests by the kind of error",
+        &["request", "error"]
+    )
+    .unwrap();
+    static ref QUERY_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "haikubot_query_cache_lookups_total",
+        "Dgraph queries looked up in the query cache by whether a result was cached",
+        &["result"]
+    )
+    .unwrap();
+    static ref QUERY_CREATION_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "haikubot_query_creation_errors_total",
+        "Errors creating storage queries from GraphQL selections by kind",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+// Operation names come from clients, so only the known names of persisted queries' operations are
+// used as labels, and any others are lumped together
+pub fn operation_label<'a>(operation: Option<&'a str>, known: &HashSet<String>) -> &'a str {
+    match operation {
+        Some(name) if known.contains(name) => name,
+        Some(_) => "other",
+        None => "anonymous",
+    }
+}
+
+pub fn observe_request(operation: &str, ok: bool, elapsed: Duration) {
+    let status = if ok { "ok" } else { "error" };
+    GRAPHQL_REQUESTS
+        .with_label_values(&[operation, status])
+        .inc();
+    GRAPHQL_DURATION
+        .with_label_values(&[operation])
+        .observe(elapsed.as_secs_f64());
+}
+
+// Resolves a top level field, timing it and counting any error
+pub async fn observe_field<T>(
+    field: &str,
+    resolve: impl Future<Output = FieldResult<T>>,
+) -> FieldResult<T> {
+    let start = Instant::now();
+    let result = resolve.await;
+    FIELD_DURATION
+        .with_label_values(&[field])
+        .observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        FIELD_ERRORS.with_label_values(&[field]).inc();
+    }
+    result
+}
+
+// Makes a Dgraph request, timing it and counting any error by its kind
+pub async fn observe_dgraph<T>(
+    request: &str,
+    send: impl Future<Output = Result<T, DgraphQueryError>>,
+) -> Result<T, DgraphQueryError> {
+    let start = Instant::now();
+    let result = send.await;
+    DGRAPH_DURATION
+        .with_label_values(&[request])
+        .observe(start.elapsed().as_secs_f64());
+    if let Err(err) = &result {
+        DGRAPH_ERRORS
+            .with_label_values(&[request, err.kind()])
+            .inc();
+    }
+    result
+}
+
+pub fn count_cache_lookup(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    QUERY_CACHE_LOOKUPS.with_label_values(&[result]).inc();
+}
+
+// Counts each error making up a composite error, rather than the composite itself
+pub fn count_query_creation_error(err: &QueryCreationError) {
+    match err {
+        QueryCreationError::Composite(composite) => composite
+            .children
+            .iter()
+            .for_each(count_query_creation_error),
+        err => QUERY_CREATION_ERRORS.with_label_values(&[err.kind()]).inc(),
+    }
+}
+
+// Every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::error::CompositeQueryCreationError;
+    use super::*;
+    use futures::executor::block_on;
+    use rstest::rstest;
+
+    #[rstest(
+        operation,
+        label,
+        case(Some("SearchHaikus"), "SearchHaikus"),
+        case(Some("SearchHaikus2"), "other"),
+        case(Some("search haikus"), "other"),
+        case(None, "anonymous")
+    )]
+    fn label_operations(operation: Option<&str>, label: &str) {
+        let known = vec!["SearchHaikus".to_owned()].into_iter().collect();
+        assert_eq!(operation_label(operation, &known), label);
+    }
+
+    #[test]
+    fn render_metrics() {
+        observe_request("RenderMetrics", true, Duration::from_millis(20));
+        let _ = block_on(observe_field("renderMetrics", async {
+            Err::<(), _>(juniper::FieldError::from("failed"))
+        }));
+        count_cache_lookup(true);
+        count_query_creation_error(&QueryCreationError::Composite(
+            CompositeQueryCreationError {
+                at_field: "haiku".to_owned(),
+                children: vec![QueryCreationError::UnsupportedAlias("alias".to_owned())],
+            },
+        ));
+        let metrics = render();
+        for line in &[
+            r#"haikubot_graphql_requests_total{operation="RenderMetrics",status="ok"} 1"#,
+            r#"haikubot_graphql_request_duration_seconds_count{operation="RenderMetrics"} 1"#,
+            r#"haikubot_graphql_field_errors_total{field="renderMetrics"} 1"#,
+            r#"haikubot_graphql_field_duration_seconds_count{field="renderMetrics"} 1"#,
+            r#"haikubot_query_cache_lookups_total{result="hit"}"#,
+            r#"haikubot_query_creation_errors_total{kind="unsupported_alias"}"#,
+        ] {
+            assert!(metrics.contains(line), "missing {}", line);
+        }
+        assert!(!metrics.contains(r#"kind="composite""#));
+    }
+}
@@ -0,0 +1,196 @@
+use super::config::DiscordConfig;
+use super::error::OAuthError;
+use super::session::{self, Viewer};
+use actix_web::client::Client;
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use chrono::{TimeZone, Utc};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Lets the user see who they are and which servers they're in
+const DISCORD_SCOPES: &str = "identify guilds";
+// Users can be in up to 200 servers, which is more than the default JSON limit allows for
+const RESPONSE_LIMIT: usize = 1 << 20;
+
+// Logging in with Discord using the OAuth2 authorization code flow. The website sends the user to
+// /auth/discord/login, Discord sends them back to the website's redirect URI with a code, and the
+// website swaps the code for a session token at /auth/discord/session.
+pub fn routes(discord: DiscordConfig) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |config| {
+        config
+            .data(Arc::new(discord))
+            .service(web::resource("/auth/discord/login").route(web::get().to(login)))
+            .service(web::resource("/auth/discord/session").route(web::post().to(session)));
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    // Passed through to the redirect URI untouched, for the website to protect against CSRF
+    state: Option<String>,
+}
+
+async fn login(
+    discord: web::Data<Arc<DiscordConfig>>,
+    query: web::Query<LoginQuery>,
+) -> HttpResponse {
+    let mut params = vec![
+        ("client_id", discord.client_id.as_str()),
+        ("redirect_uri", discord.redirect_uri.as_str()),
+        ("response_type", "code"),
+        ("scope", DISCORD_SCOPES),
+    ];
+    if let Some(state) = &query.state {
+        params.push(("state", state));
+    }
+    let location = format!(
+        "{}?{}",
+        discord.authorize_url,
+        serde_urlencoded::to_string(&params).unwrap()
+    );
+    HttpResponse::Found()
+        .header(header::LOCATION, location)
+        .finish()
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionRequest {
+    code: String,
+}
+
+async fn session(
+    discord: web::Data<Arc<DiscordConfig>>,
+    request: web::Json<SessionRequest>,
+) -> HttpResponse {
+    let viewer = match fetch_viewer(&discord, &request.code).await {
+        Ok(viewer) => viewer,
+        Err(OAuthError::Rejected(status)) => {
+            warn!("Discord login rejected with {}", status);
+            return HttpResponse::Unauthorized()
+                .json(json!({ "error": "Invalid authorization code" }));
+        }
+        Err(err) => {
+            error!("Discord login failed - {}", err);
+            return HttpResponse::BadGateway().json(json!({ "error": "Unable to reach Discord" }));
+        }
+    };
+    let expires_at = Utc::now().timestamp() + discord.session_ttl_secs as i64;
+    info!("Discord user {} logged in", viewer.discord_snowflake);
+    HttpResponse::Ok().json(json!({
+        "token": session::issue(&discord.session_secret, &viewer, expires_at),
+        "expiresAt": Utc.timestamp(expires_at, 0).to_rfc3339(),
+        "discordSnowflake": viewer.discord_snowflake,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordObject {
+    id: String,
+}
+
+// Swaps an authorization code for an access token, then uses it to look up the user and their
+// servers
+async fn fetch_viewer(discord: &DiscordConfig, code: &str) -> Result<Viewer, OAuthError> {
+    let client = Client::build().timeout(Duration::from_secs(10)).finish();
+    let mut response = client
+        .post(&discord.token_url)
+        .send_form(&[
+            ("client_id", discord.client_id.as_str()),
+            ("client_secret", discord.client_secret.as_str()),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", discord.redirect_uri.as_str()),
+        ])
+        .await
+        .map_err(|err| OAuthError::Request(err.to_string()))?;
+    if !response.status().is_success() {
+        return Err(OAuthError::Rejected(response.status().as_u16()));
+    }
+    let token = response
+        .json::<TokenResponse>()
+        .await
+        .map_err(|err| OAuthError::Request(err.to_string()))?;
+    let user: DiscordObject = get_json(&client, discord, &token.access_token, "/users/@me").await?;
+    let guilds: Vec<DiscordObject> =
+        get_json(&client, discord, &token.access_token, "/users/@me/guilds").await?;
+    Ok(Viewer {
+        discord_snowflake: user.id,
+        guilds: guilds.into_iter().map(|guild| guild.id).collect(),
+    })
+}
+
+async fn get_json<T: DeserializeOwned>(
+    client: &Client,
+    discord: &DiscordConfig,
+    access_token: &str,
+    path: &str,
+) -> Result<T, OAuthError> {
+    let mut response = client
+        .get(format!("{}{}", discord.api_url, path))
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .map_err(|err| OAuthError::Request(err.to_string()))?;
+    if !response.status().is_success() {
+        return Err(OAuthError::Rejected(response.status().as_u16()));
+    }
+    response
+        .json::<T>()
+        .limit(RESPONSE_LIMIT)
+        .await
+        .map_err(|err| OAuthError::Request(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, App};
+
+    fn discord_config() -> DiscordConfig {
+        DiscordConfig {
+            client_id: "1234".to_owned(),
+            client_secret: "client-secret".to_owned(),
+            redirect_uri: "https://haiku.example.com/login".to_owned(),
+            authorize_url: "https://discord.example.com/oauth2/authorize".to_owned(),
+            // Nothing listens here, so every request to Discord fails
+            token_url: "http://127.0.0.1:9/oauth2/token".to_owned(),
+            api_url: "http://127.0.0.1:9/api".to_owned(),
+            session_secret: "0123456789abcdef0123456789abcdef".to_owned(),
+            session_ttl_secs: 60,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn redirect_to_discord() {
+        let mut app = test::init_service(App::new().configure(routes(discord_config()))).await;
+        let request = test::TestRequest::get()
+            .uri("/auth/discord/login?state=abc")
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(
+            response.headers().get(header::LOCATION).unwrap(),
+            "https://discord.example.com/oauth2/authorize?client_id=1234\
+             &redirect_uri=https%3A%2F%2Fhaiku.example.com%2Flogin&response_type=code\
+             &scope=identify+guilds&state=abc"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn report_unreachable_discord() {
+        let mut app = test::init_service(App::new().configure(routes(discord_config()))).await;
+        let request = test::TestRequest::post()
+            .uri("/auth/discord/session")
+            .set_json(&json!({ "code": "valid-code" }))
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), 502);
+    }
+}
@@ -0,0 +1,357 @@
+use super::config::PersistedQueriesConfig;
+use super::error::PersistedQueryError;
+use juniper::http::GraphQLRequest;
+use juniper::InputValue;
+use lru::LruCache;
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+// The only version of automatic persisted queries there is so far
+const APQ_VERSION: i64 = 1;
+
+// A GraphQL request as sent over HTTP, which may give a persisted query's hash in place of the
+// query, as in Apollo's automatic persisted queries
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphQLPayload {
+    query: Option<String>,
+    operation_name: Option<String>,
+    variables: Option<InputValue>,
+    #[serde(default)]
+    extensions: Extensions,
+}
+
+// Several requests can be sent together as an array, which are executed with the same context
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum GraphQLBatchPayload {
+    Single(GraphQLPayload),
+    Batch(Vec<GraphQLPayload>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Extensions {
+    persisted_query: Option<PersistedQuery>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedQuery {
+    version: i64,
+    sha256_hash: String,
+}
+
+pub struct PersistedQueries {
+    manifest: HashMap<String, String>,
+    // The names of the operations in the manifest, which are the only ones given their own metrics
+    operations: HashSet<String>,
+    // Queries registered by clients, or None if they can't register any
+    automatic: Option<Mutex<LruCache<String, String>>>,
+    only_persisted: bool,
+}
+
+impl PersistedQueries {
+    pub fn new(config: &PersistedQueriesConfig, manifest: HashMap<String, String>) -> Self {
+        let automatic = match NonZeroUsize::new(config.max_automatic) {
+            Some(max) if config.automatic && !config.only_persisted => {
+                Some(Mutex::new(LruCache::new(max)))
+            }
+            _ => None,
+        };
+        let operations = manifest
+            .values()
+            .flat_map(|query| operation_names(query))
+            .collect();
+        Self {
+            manifest,
+            operations,
+            automatic,
+            only_persisted: config.only_persisted,
+        }
+    }
+
+    // Reads the manifest if one is configured
+    pub fn load(config: &PersistedQueriesConfig) -> io::Result<Self> {
+        let manifest = match &config.manifest {
+            Some(path) => parse_manifest(&std::fs::read_to_string(path)?)
+                .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?,
+            None => HashMap::new(),
+        };
+        info!("Loaded {} persisted queries", manifest.len());
+        Ok(Self::new(config, manifest))
+    }
+
+    // Turns the payload into a request for its query, looking up the query if only its hash was
+    // given and registering it if both were
+    pub fn resolve(&self, payload: GraphQLPayload) -> Result<GraphQLRequest, PersistedQueryError> {
+        let hash = match payload.extensions.persisted_query {
+            Some(persisted) if persisted.version != APQ_VERSION => {
+                return Err(PersistedQueryError::UnsupportedVersion(persisted.version))
+            }
+            Some(persisted) => Some(persisted.sha256_hash.to_ascii_lowercase()),
+            None => None,
+        };
+        let query = match (payload.query, hash) {
+            (Some(query), Some(hash)) => {
+                if query_hash(&query) != hash {
+                    return Err(PersistedQueryError::HashMismatch);
+                }
+                self.register(hash, &query)?;
+                query
+            }
+            (Some(query), None) => {
+                self.register(query_hash(&query), &query)?;
+                query
+            }
+            (None, Some(hash)) => self.lookup(&hash).ok_or(PersistedQueryError::NotFound)?,
+            (None, None) => return Err(PersistedQueryError::MissingQuery),
+        };
+        Ok(GraphQLRequest::new(
+            query,
+            payload.operation_name,
+            payload.variables,
+        ))
+    }
+
+    pub fn operations(&self) -> &HashSet<String> {
+        &self.operations
+    }
+
+    // Whether the payload's query could make a mutation, erring towards yes for any query which
+    // mentions one, so that batches with mutations in them can be run in order
+    pub fn may_mutate(&self, payload: &GraphQLPayload) -> bool {
+        lazy_static! {
+            static ref MUTATION_REGEX: Regex = Regex::new(r"\bmutation\b").unwrap();
+        }
+        let query = match (&payload.query, &payload.extensions.persisted_query) {
+            (Some(query), _) => Some(query.clone()),
+            (None, Some(persisted)) => self.lookup(&persisted.sha256_hash.to_ascii_lowercase()),
+            (None, None) => None,
+        };
+        query.is_some_and(|query| MUTATION_REGEX.is_match(&query))
+    }
+
+    fn lookup(&self, hash: &str) -> Option<String> {
+        match self.manifest.get(hash) {
+            Some(query) => Some(query.clone()),
+            None => self.automatic.as_ref()?.lock().unwrap().get(hash).cloned(),
+        }
+    }
+
+    // Checks a query sent in full is allowed, remembering it if clients can register queries
+    fn register(&self, hash: String, query: &str) -> Result<(), PersistedQueryError> {
+        if self.manifest.contains_key(&hash) {
+            return Ok(());
+        }
+        if self.only_persisted {
+            return Err(PersistedQueryError::NotPersisted);
+        }
+        if let Some(automatic) = &self.automatic {
+            automatic.lock().unwrap().put(hash, query.to_owned());
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn query_hash(query: &str) -> String {
+    Sha256::digest(query.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn operation_names(query: &str) -> Vec<String> {
+    lazy_static! {
+        static ref OPERATION_REGEX: Regex =
+            Regex::new(r"\b(?:query|mutation|subscription)\s+([_A-Za-z][_0-9A-Za-z]*)").unwrap();
+    }
+    OPERATION_REGEX
+        .captures_iter(query)
+        .map(|captures| captures[1].to_owned())
+        .collect()
+}
+
+// Checks every hash in the manifest is the hash of its query, so that clients sending the query
+// in full get the same one
+fn parse_manifest(json: &str) -> Result<HashMap<String, String>, String> {
+    let manifest: HashMap<String, String> = serde_json::from_str(json)
+        .map_err(|err| format!("Invalid persisted queries manifest: {}", err))?;
+    manifest
+        .into_iter()
+        .map(|(hash, query)| {
+            let hash = hash.to_ascii_lowercase();
+            if query_hash(&query) == hash {
+                Ok((hash, query))
+            } else {
+                Err(format!(
+                    "Persisted query {} doesn't match the hash of its query",
+                    hash
+                ))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    const QUERY: &str = "{ apiVersion }";
+
+    fn persisted_queries(only_persisted: bool) -> PersistedQueries {
+        let config = PersistedQueriesConfig {
+            max_automatic: 1,
+            only_persisted,
+            ..PersistedQueriesConfig::default()
+        };
+        let manifest = parse_manifest(&json!({ query_hash(QUERY): QUERY }).to_string()).unwrap();
+        PersistedQueries::new(&config, manifest)
+    }
+
+    fn payload(query: Option<&str>, hash: Option<&str>) -> GraphQLPayload {
+        let extensions = match hash {
+            Some(hash) => json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } }),
+            None => json!({}),
+        };
+        serde_json::from_value(json!({
+            "query": query,
+            "operationName": null,
+            "extensions": extensions,
+        }))
+        .unwrap()
+    }
+
+    fn resolve(
+        persisted: &PersistedQueries,
+        query: Option<&str>,
+        hash: Option<&str>,
+    ) -> Result<GraphQLRequest, PersistedQueryError> {
+        persisted.resolve(payload(query, hash))
+    }
+
+    fn request(query: &str) -> Result<GraphQLRequest, PersistedQueryError> {
+        Ok(GraphQLRequest::new(query.to_owned(), None, None))
+    }
+
+    #[test]
+    fn register_automatic_persisted_queries() {
+        let persisted = persisted_queries(false);
+        let query = "{ viewer { discordSnowflake } }";
+        let hash = query_hash(query);
+        assert_eq!(
+            resolve(&persisted, None, Some(hash.as_str())),
+            Err(PersistedQueryError::NotFound)
+        );
+        assert!(resolve(&persisted, Some(query), Some(hash.as_str())).is_ok());
+        assert_eq!(
+            resolve(&persisted, None, Some(hash.to_ascii_uppercase().as_str())),
+            request(query)
+        );
+        assert_eq!(
+            resolve(&persisted, None, Some(query_hash(QUERY).as_str())),
+            request(QUERY)
+        );
+
+        // Only the most recently used query is kept, but manifest queries are never dropped
+        let other = "{ haiku(haikuId: \"0x1\") { id } }";
+        assert!(resolve(&persisted, Some(other), None).is_ok());
+        assert_eq!(
+            resolve(&persisted, None, Some(hash.as_str())),
+            Err(PersistedQueryError::NotFound)
+        );
+        assert!(resolve(&persisted, None, Some(query_hash(other).as_str())).is_ok());
+        assert!(resolve(&persisted, None, Some(query_hash(QUERY).as_str())).is_ok());
+    }
+
+    // Hashes are given as the query they're the hash of
+    #[rstest(
+        query,
+        hashed,
+        expected,
+        case(Some(QUERY), None, Ok(())),
+        case(None, Some(QUERY), Ok(())),
+        case(Some("{ viewer { id } }"), None, Err(PersistedQueryError::NotPersisted)),
+        case(
+            Some("{ viewer { id } }"),
+            Some("{ viewer { id } }"),
+            Err(PersistedQueryError::NotPersisted)
+        ),
+        case(Some(QUERY), Some("{ viewer { id } }"), Err(PersistedQueryError::HashMismatch)),
+        case(None, None, Err(PersistedQueryError::MissingQuery))
+    )]
+    fn allow_only_persisted_queries(
+        query: Option<&str>,
+        hashed: Option<&str>,
+        expected: Result<(), PersistedQueryError>,
+    ) {
+        let persisted = persisted_queries(true);
+        let hash = hashed.map(query_hash);
+        assert_eq!(
+            resolve(&persisted, query, hash.as_deref()).map(|_| ()),
+            expected
+        );
+    }
+
+    #[test]
+    fn reject_unsupported_versions() {
+        let payload = serde_json::from_value(json!({
+            "extensions": { "persistedQuery": { "version": 2, "sha256Hash": query_hash(QUERY) } },
+        }))
+        .unwrap();
+        assert_eq!(
+            persisted_queries(false).resolve(payload).map(|_| ()),
+            Err(PersistedQueryError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn name_manifest_operations() {
+        let queries = [
+            "query Viewer { viewer { id } }",
+            "mutation AddHaiku($input: NewHaiku!) { addHaiku(input: $input) { id } }\nquery Haiku { haiku(haikuId: \"0x1\") { id } }",
+            QUERY,
+        ];
+        let manifest = queries
+            .iter()
+            .map(|query| (query_hash(query), (*query).to_owned()))
+            .collect();
+        let persisted = PersistedQueries::new(&PersistedQueriesConfig::default(), manifest);
+        let mut operations = persisted.operations().iter().collect::<Vec<_>>();
+        operations.sort();
+        assert_eq!(operations, vec!["AddHaiku", "Haiku", "Viewer"]);
+    }
+
+    #[test]
+    fn find_mutations() {
+        let persisted = persisted_queries(false);
+        let mutation = "mutation AddHaiku($input: NewHaiku!) { addHaiku(input: $input) { id } }";
+        assert!(persisted.may_mutate(&payload(Some(mutation), None)));
+        assert!(!persisted.may_mutate(&payload(Some(QUERY), None)));
+        assert!(!persisted.may_mutate(&payload(None, Some(query_hash(QUERY).as_str()))));
+        assert!(!persisted.may_mutate(&payload(None, Some(query_hash(mutation).as_str()))));
+        assert!(resolve(&persisted, Some(mutation), None).is_ok());
+        assert!(persisted.may_mutate(&payload(None, Some(query_hash(mutation).as_str()))));
+    }
+
+    #[test]
+    fn check_manifest_hashes() {
+        let manifest = json!({ query_hash(QUERY).to_ascii_uppercase(): QUERY });
+        assert_eq!(
+            parse_manifest(&manifest.to_string()).unwrap(),
+            vec![(query_hash(QUERY), QUERY.to_owned())]
+                .into_iter()
+                .collect()
+        );
+        assert!(
+            parse_manifest(&json!({ query_hash("{ viewer { id } }"): QUERY }).to_string()).is_err()
+        );
+        assert!(parse_manifest("[]").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::FutureExt;
+use regex::Regex;
+use std::future::Future;
+use tracing::Instrument;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Uses the id the client sent if it's usable, so that its logs can be matched up with ours, and
+// otherwise makes a new one
+fn request_id(header: Option<&HeaderValue>) -> String {
+    lazy_static! {
+        static ref REQUEST_ID_REGEX: Regex = Regex::new(r"^[-_.:0-9A-Za-z]{1,128}$").unwrap();
+    }
+    header
+        .and_then(|header| header.to_str().ok())
+        .filter(|id| REQUEST_ID_REGEX.is_match(id))
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+// Middleware running each request in a span tagged with its id, which is also given back to the
+// client in the response headers
+pub fn trace_request<S, B>(
+    req: ServiceRequest,
+    service: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let id = request_id(req.headers().get(REQUEST_ID_HEADER));
+    let span = tracing::info_span!(
+        "http_request",
+        request_id = %id,
+        method = %req.method(),
+        path = req.path(),
+    );
+    // Either checked against the regex or a UUID, so always a valid header
+    let header = HeaderValue::from_str(&id).unwrap();
+    service.call(req).instrument(span).map(move |response| {
+        response.map(|mut response| {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+            response
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        header,
+        kept,
+        case(Some("5f0c6b1e-3c1d-4f5e-9b1a-2d7f8c9e0a1b"), true),
+        case(Some("req_42"), true),
+        case(Some(""), false),
+        case(Some("two words"), false),
+        case(None, false)
+    )]
+    fn choose_request_id(header: Option<&str>, kept: bool) {
+        let header = header.map(|header| HeaderValue::from_str(header).unwrap());
+        let id = request_id(header.as_ref());
+        assert_eq!(header.is_some_and(|header| header == id.as_str()), kept);
+        assert!(!id.is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use super::super::dql::{self, Block, Field, Filter, Function, Value};
+use super::super::error::{data_corrupt, storage_error};
+use super::Context;
+use futures::channel::oneshot;
+use juniper::{FieldError, FieldResult};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{self, Poll};
+
+// How a root lookup finds its node
+#[derive(Debug, Clone, PartialEq)]
+pub enum LookupKey {
+    Uid(String),
+    Snowflake(String),
+}
+
+impl LookupKey {
+    // The field the key is read back from in the results, fetched whether or not it was selected
+    fn field(&self) -> Field {
+        match self {
+            LookupKey::Uid(_) => Field::uid("id"),
+            LookupKey::Snowflake(_) => Field::value("discordSnowflake"),
+        }
+    }
+
+    fn function(keys: Vec<&LookupKey>) -> Function {
+        let value = |key: &LookupKey| match key {
+            LookupKey::Uid(value) | LookupKey::Snowflake(value) => Value::from(value.as_str()),
+        };
+        let value = match keys.as_slice() {
+            [key] => value(key),
+            keys => Value::List(keys.iter().map(|key| value(key)).collect()),
+        };
+        match keys[0] {
+            LookupKey::Uid(_) => Function::Uid(value),
+            LookupKey::Snowflake(_) => Function::Eq("discordSnowflake".to_owned(), value),
+        }
+    }
+
+    // Uids are given back by Dgraph in lower case without leading zeros, while snowflakes have
+    // already been normalized by `valid_snowflake`
+    fn normalized(self) -> LookupKey {
+        match self {
+            LookupKey::Uid(uid) => match parse_uid(&uid) {
+                Some(uid) => LookupKey::Uid(format!("{:#x}", uid)),
+                None => LookupKey::Uid(uid),
+            },
+            snowflake => snowflake,
+        }
+    }
+
+    fn same_kind(&self, other: &LookupKey) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    // Uids are compared by value, whichever way they're written
+    fn matches(&self, node: &serde_json::Value) -> bool {
+        match self {
+            LookupKey::Uid(uid) => node
+                .get("id")
+                .and_then(serde_json::Value::as_str)
+                .is_some_and(|id| parse_uid(id).is_some() && parse_uid(id) == parse_uid(uid)),
+            LookupKey::Snowflake(snowflake) => node
+                .get("discordSnowflake")
+                .and_then(serde_json::Value::as_str)
+                .is_some_and(|node_snowflake| node_snowflake == snowflake),
+        }
+    }
+}
+
+fn parse_uid(uid: &str) -> Option<u64> {
+    u64::from_str_radix(uid.trim_start_matches("0x"), 16).ok()
+}
+
+// A node of a type to fetch by its key, along with the fields selected from it
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub root: String,
+    pub type_name: String,
+    pub key: LookupKey,
+    pub fields: Vec<Field>,
+}
+
+type LookupResult = FieldResult<Option<serde_json::Value>>;
+
+// Gathers the lookups made by the root fields of a request, so that they can be made with a single
+// query rather than a round trip each
+#[derive(Debug, Default)]
+pub struct LookupBatcher {
+    pending: Mutex<Vec<(Lookup, oneshot::Sender<LookupResult>)>>,
+}
+
+impl LookupBatcher {
+    pub async fn load(&self, context: &Context, lookup: Lookup) -> LookupResult {
+        loop {
+            let (sender, receiver) = oneshot::channel();
+            self.pending.lock().unwrap().push((lookup.clone(), sender));
+            // Root fields are resolved together, so this gives the others the chance to add their
+            // lookups. Whichever field carries on first makes the query for all of them.
+            YieldNow(false).await;
+            let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+            if !batch.is_empty() {
+                fetch(context, batch).await;
+            }
+            match receiver.await {
+                Ok(result) => return result,
+                // The field making the query was dropped before it finished, eg. when a request
+                // is cancelled, so the lookup is queued up again
+                Err(oneshot::Canceled) => continue,
+            }
+        }
+    }
+}
+
+// Returns pending once, waking straight away so that the task is polled again
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+// Lookups of the same type with the same fields, which are fetched by one block
+struct Group {
+    name: String,
+    type_name: String,
+    fields: Vec<Field>,
+    lookups: Vec<(LookupKey, oneshot::Sender<LookupResult>)>,
+}
+
+impl Group {
+    fn block(&self) -> Block {
+        let mut keys: Vec<&LookupKey> = Vec::new();
+        for (key, _) in &self.lookups {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        let key_field = keys[0].field();
+        let mut fields = self.fields.clone();
+        if !fields.contains(&key_field) {
+            fields.insert(0, key_field);
+        }
+        Block::new(&self.name, LookupKey::function(keys))
+            .filter(Filter::type_of(&self.type_name))
+            .fields(fields)
+    }
+
+    fn send_results(self, result: &serde_json::Value) {
+        let nodes = match result.get(&self.name).and_then(serde_json::Value::as_array) {
+            Some(nodes) => nodes,
+            None => {
+                error!("Error parsing query result - missing {}", self.name);
+                for (_, sender) in self.lookups {
+                    let _ = sender.send(Err(data_corrupt()));
+                }
+                return;
+            }
+        };
+        for (key, sender) in self.lookups {
+            let node = nodes.iter().find(|node| key.matches(node)).cloned();
+            let _ = sender.send(Ok(node));
+        }
+    }
+}
+
+async fn fetch(context: &Context, batch: Vec<(Lookup, oneshot::Sender<LookupResult>)>) {
+    let mut groups: Vec<Group> = Vec::new();
+    for (lookup, sender) in batch {
+        let group = groups.iter_mut().find(|group| {
+            group.type_name == lookup.type_name
+                && group.fields == lookup.fields
+                && group.lookups[0].0.same_kind(&lookup.key)
+        });
+        match group {
+            Some(group) => group.lookups.push((lookup.key.normalized(), sender)),
+            None => {
+                // Blocks are named after their root field where possible, as they are unbatched
+                let name = if groups.iter().any(|group| group.name == lookup.root) {
+                    format!("{}{}", lookup.root, groups.len())
+                } else {
+                    lookup.root
+                };
+                groups.push(Group {
+                    name,
+                    type_name: lookup.type_name,
+                    fields: lookup.fields,
+                    lookups: vec![(lookup.key.normalized(), sender)],
+                });
+            }
+        }
+    }
+    let name = match groups.as_slice() {
+        [group] => group.name.clone(),
+        _ => "batch".to_owned(),
+    };
+    let query = groups.iter().fold(dql::Query::new(&name), |query, group| {
+        query.block(group.block())
+    });
+    match context.storage.query(&context.restrict(query)).await {
+        Ok(result) => {
+            for group in groups {
+                group.send_results(&result);
+            }
+        }
+        Err(err) => {
+            // Logged once for the whole batch, then given to every lookup in it
+            let err = storage_error(err);
+            for (_, sender) in groups.into_iter().flat_map(|group| group.lookups) {
+                let _ = sender.send(Err(FieldError::new(
+                    err.message(),
+                    err.extensions().clone(),
+                )));
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use super::connection::{int_argument, MAX_PAGE_SIZE};
+use super::discord_server::MAX_CHANNELS;
+use super::haiku::MAX_AUTHORS;
+use super::search::SearchArguments;
+use juniper::{DefaultScalarValue, LookAheadMethods, LookAheadSelection};
+
+// How many levels of fields are selected, counting the field itself
+pub fn depth(selection: &LookAheadSelection<DefaultScalarValue>) -> u32 {
+    1 + selection
+        .children()
+        .into_iter()
+        .map(depth)
+        .max()
+        .unwrap_or(0)
+}
+
+// How many fields could be fetched for a selection, where each field costs one and the fields
+// under a list are counted once for every item it could hold. The top level field's name is
+// given, as it may be aliased. Juniper only gives the alias of an aliased field, but below the top
+// level any field is fetched by the name it's given, or rejected as an unsupported alias if there's
+// no such field, so that's the name to cost it by too.
+pub fn cost(selection: &LookAheadSelection<DefaultScalarValue>, field: &str) -> u64 {
+    let children = selection
+        .children()
+        .into_iter()
+        .map(|child| cost(child, child.field_name()))
+        .fold(0u64, u64::saturating_add);
+    children
+        .saturating_mul(list_size(selection, field))
+        .saturating_add(1)
+}
+
+// The most items a field could return, going by the same arguments and defaults as the query
+// will be made with. Invalid arguments are reported once the query is made, so they're only
+// clamped here.
+fn list_size(selection: &LookAheadSelection<DefaultScalarValue>, field: &str) -> u64 {
+    let page_size = |args: &[&str]| {
+        let sizes: Vec<_> = args
+            .iter()
+            .filter_map(|arg| int_argument(selection, arg).ok().flatten())
+            .collect();
+        match sizes.into_iter().max() {
+            Some(size) => size.clamp(0, MAX_PAGE_SIZE) as u64,
+            None => MAX_PAGE_SIZE as u64,
+        }
+    };
+    match field {
+        "haikus" => page_size(&["first", "last"]),
+        // Phrase searches fetch more haikus than they return, to check for the phrase
+        "search" => match SearchArguments::from_selection(selection) {
+            Ok(arguments) => arguments.pagination().first.unwrap_or_default() as u64,
+            Err(_) => page_size(&["max"]),
+        },
+        "haikusSearch" => page_size(&["max"]),
+        "channels" => MAX_CHANNELS as u64,
+        "authors" => MAX_AUTHORS as u64,
+        _ => 1,
+    }
+}
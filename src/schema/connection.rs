@@ -0,0 +1,772 @@
+use super::super::dql::{
+    Block, Edge, Field, Filter, Function, Order, Pagination, Predicate, Value,
+};
+use super::super::error::{data_corrupt, QueryCreationError};
+use super::haiku::Haiku;
+use super::haiku_filter::HaikuFilter;
+use super::util::MapsToDgraphQuery;
+use super::Context;
+use juniper::{
+    DefaultScalarValue, FieldError, FieldResult, LookAheadArgument, LookAheadMethods,
+    LookAheadSelection, LookAheadValue,
+};
+use std::convert::TryFrom;
+
+// The most haikus which can be fetched in one page, also used when neither first nor last is given
+pub const MAX_PAGE_SIZE: i32 = 100;
+const CURSOR_PREFIX: &str = "haiku:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash, juniper::GraphQLEnum)]
+pub enum HaikuOrderField {
+    Timestamp,
+    RulesVersion,
+    Id,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash, juniper::GraphQLEnum)]
+pub enum OrderDirection {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash, juniper::GraphQLInputObject)]
+pub struct HaikuOrder {
+    pub field: HaikuOrderField,
+    pub direction: OrderDirection,
+}
+
+impl Default for HaikuOrder {
+    fn default() -> Self {
+        Self {
+            field: HaikuOrderField::Id,
+            direction: OrderDirection::Asc,
+        }
+    }
+}
+
+impl HaikuOrder {
+    pub fn from_selection(
+        selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Self, QueryCreationError> {
+        let fields = match selection.argument("orderBy").map(LookAheadArgument::value) {
+            None | Some(LookAheadValue::Null) => return Ok(Self::default()),
+            Some(LookAheadValue::Object(fields)) => fields,
+            _ => return Err(invalid_order("must be an object")),
+        };
+        let enum_value = |name: &str| {
+            fields
+                .iter()
+                .find(|(field_name, _)| *field_name == name)
+                .and_then(|(_, value)| match value {
+                    LookAheadValue::Enum(value) => Some(*value),
+                    _ => None,
+                })
+        };
+        let field = match enum_value("field") {
+            Some("TIMESTAMP") => HaikuOrderField::Timestamp,
+            Some("RULES_VERSION") => HaikuOrderField::RulesVersion,
+            Some("ID") => HaikuOrderField::Id,
+            _ => {
+                return Err(invalid_order(
+                    "field must be one of TIMESTAMP, RULES_VERSION or ID",
+                ))
+            }
+        };
+        let direction = match enum_value("direction") {
+            Some("ASC") => OrderDirection::Asc,
+            Some("DESC") => OrderDirection::Desc,
+            _ => return Err(invalid_order("direction must be one of ASC or DESC")),
+        };
+        Ok(Self { field, direction })
+    }
+
+    fn predicate(&self) -> Option<&'static str> {
+        match self.field {
+            HaikuOrderField::Timestamp => Some("timestamp"),
+            HaikuOrderField::RulesVersion => Some("rulesVersion"),
+            HaikuOrderField::Id => None,
+        }
+    }
+
+    pub fn dql_order(&self) -> Option<Order> {
+        self.predicate().map(|predicate| Order {
+            predicate: predicate.to_owned(),
+            descending: self.direction == OrderDirection::Desc,
+        })
+    }
+
+    // Dgraph can't order by uid, so lists in descending id order are fetched in ascending order
+    // from the other end of the list and then reversed
+    pub fn reversed(&self) -> bool {
+        self.field == HaikuOrderField::Id && self.direction == OrderDirection::Desc
+    }
+
+    // The pagination to fetch the first haikus in this order with, or the last if first is negative
+    pub fn pagination(&self, first: i64) -> Pagination {
+        Pagination {
+            order: self.dql_order(),
+            first: Some(if self.reversed() { -first } else { first }),
+            ..Pagination::default()
+        }
+    }
+
+    // The haikus out of those matching the filter after the cursor in this order, or before it.
+    // Haikus with the same value being ordered by are in uid order, so only those sharing the
+    // cursor's value need comparing by uid.
+    fn beyond(&self, cursor: &Cursor, after: bool, filter: &Filter) -> Filter {
+        let descending = self.direction == OrderDirection::Desc;
+        match (self.predicate(), &cursor.key) {
+            (Some(predicate), Some(key)) => {
+                let predicate = predicate.to_owned();
+                let beyond_key = if after != descending {
+                    Function::Gt(predicate.clone(), key.clone())
+                } else {
+                    Function::Lt(predicate.clone(), key.clone())
+                };
+                let same_key = Function::Eq(predicate, key.clone());
+                Filter::from(beyond_key).or(Filter::from(same_key.clone())
+                    .and(beyond_uid(same_key, filter, cursor.uid, after)))
+            }
+            _ => beyond_uid(
+                Function::Type("Haiku".to_owned()),
+                filter,
+                cursor.uid,
+                after != descending,
+            ),
+        }
+    }
+
+    // The key to find a haiku by in a list in this order, or None if it's missing
+    fn key(&self, haiku: &serde_json::Value) -> Option<Option<Value>> {
+        let value = match self.predicate() {
+            Some(predicate) => haiku.get(predicate)?,
+            None => return Some(None),
+        };
+        match (self.field, value) {
+            (HaikuOrderField::Timestamp, serde_json::Value::String(timestamp)) => {
+                Some(Some(Value::from(timestamp.as_str())))
+            }
+            (HaikuOrderField::RulesVersion, serde_json::Value::Number(version)) => {
+                Some(Some(Value::Int(version.as_i64()?)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn invalid_order(msg: &str) -> QueryCreationError {
+    QueryCreationError::InvalidArgument("orderBy".to_owned(), msg.to_owned())
+}
+
+fn haiku_uid(haiku: &serde_json::Value) -> u64 {
+    haiku
+        .get("id")
+        .and_then(serde_json::Value::as_str)
+        .and_then(|id| u64::from_str_radix(id.trim_start_matches("0x"), 16).ok())
+        .unwrap_or_default()
+}
+
+// Haikus found by the function and filter with greater uids than the given one, or lesser. DQL
+// can't compare uids, so this pages through those haikus past the uid instead. Lists in uid order
+// use `after:` on the list itself where they can, leaving only lesser uids to be found this way.
+fn beyond_uid(func: Function, filter: &Filter, uid: u64, greater: bool) -> Filter {
+    let after = |uid| {
+        Filter::Var(Box::new(
+            Block::new("var", func)
+                .filter(filter.clone())
+                .pagination(Pagination {
+                    after: Some(uid),
+                    ..Pagination::default()
+                }),
+        ))
+    };
+    if greater {
+        after(uid)
+    } else {
+        after(uid - 1).not()
+    }
+}
+
+// The arguments shared by every haiku connection, selecting which haikus it holds
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct ConnectionArguments {
+    pub window: Window,
+    pub order: HaikuOrder,
+    pub filter: HaikuFilter,
+}
+
+impl ConnectionArguments {
+    pub fn new(
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+        order_by: Option<HaikuOrder>,
+        filter: Option<HaikuFilter>,
+    ) -> Result<Self, QueryCreationError> {
+        let order = order_by.unwrap_or_default();
+        Ok(Self {
+            window: Window::new(first, after, last, before, &order)?,
+            order,
+            filter: filter.unwrap_or_default().validate()?,
+        })
+    }
+
+    pub fn from_selection(
+        selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Self, QueryCreationError> {
+        let order = HaikuOrder::from_selection(selection)?;
+        Ok(Self {
+            window: Window::from_selection(selection, &order)?,
+            order,
+            filter: HaikuFilter::from_selection(selection)?.validate()?,
+        })
+    }
+
+    // The key a connection's haikus are stored under, allowing it to be fetched with several
+    // different sets of arguments at once
+    pub fn alias(&self, field: &str) -> String {
+        format!("{}_{:#x}", field, hash!(self))
+    }
+
+    pub fn pagination(&self) -> Pagination {
+        Pagination {
+            after: self.uid_cursor().map(|cursor| cursor.uid),
+            ..self.window.pagination(&self.order)
+        }
+    }
+
+    // The filter for every haiku in the connection, as counted by its totalCount
+    pub fn dql_filter(&self) -> Filter {
+        self.filter.to_dql()
+    }
+
+    // The filter for the haikus between the cursors, other than any paged past with `after:`
+    pub fn page_filter(&self) -> Filter {
+        let filter = self.dql_filter();
+        let mut page_filter = filter.clone();
+        let uid_cursor = self.uid_cursor();
+        if let Some(after) = self
+            .window
+            .after
+            .as_ref()
+            .filter(|after| Some(*after) != uid_cursor)
+        {
+            page_filter = page_filter.and(self.order.beyond(after, true, &filter));
+        }
+        if let Some(before) = self
+            .window
+            .before
+            .as_ref()
+            .filter(|before| Some(*before) != uid_cursor)
+        {
+            page_filter = page_filter.and(self.order.beyond(before, false, &filter));
+        }
+        page_filter
+    }
+
+    // The cursor marking where a list in uid order starts, which Dgraph can page past itself.
+    // Lists in descending id order are fetched in ascending order, so that's the before cursor.
+    fn uid_cursor(&self) -> Option<&Cursor> {
+        match self.order.field {
+            HaikuOrderField::Id if self.order.reversed() => self.window.before.as_ref(),
+            HaikuOrderField::Id => self.window.after.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+// A haiku's place in an ordered list, given by the value being ordered by and its uid. Unlike its
+// index, this doesn't change when haikus are added ahead of it.
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct Cursor {
+    key: Option<Value>,
+    uid: u64,
+}
+
+impl Cursor {
+    fn new(haiku: &serde_json::Value, order: &HaikuOrder) -> Option<Self> {
+        Some(Self {
+            key: order.key(haiku)?,
+            uid: Some(haiku_uid(haiku)).filter(|uid| *uid > 0)?,
+        })
+    }
+
+    // Cursors are opaque to clients, but are just the fields identifying the haiku
+    fn encode(&self, order: &HaikuOrder) -> String {
+        let mut haiku = json!({ "id": format!("{:#x}", self.uid) });
+        if let (Some(predicate), Some(key)) = (order.predicate(), &self.key) {
+            haiku[predicate] = key.to_json();
+        }
+        base64::encode(&format!("{}{}", CURSOR_PREFIX, haiku))
+    }
+
+    fn decode(cursor: &str, order: &HaikuOrder, arg: &str) -> Result<Self, QueryCreationError> {
+        base64::decode(cursor)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|cursor| serde_json::from_str(cursor.strip_prefix(CURSOR_PREFIX)?).ok())
+            .and_then(|haiku| Self::new(&haiku, order))
+            .ok_or_else(|| {
+                QueryCreationError::InvalidArgument(
+                    arg.to_owned(),
+                    "is not a valid cursor".to_owned(),
+                )
+            })
+    }
+}
+
+// The part of a haiku list selected by the Relay connection arguments first/after/last/before:
+// up to count haikus between the cursors, taken from the end if last was given
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct Window {
+    pub after: Option<Cursor>,
+    pub before: Option<Cursor>,
+    pub count: i64,
+    pub from_end: bool,
+}
+
+impl Window {
+    pub fn new(
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+        order: &HaikuOrder,
+    ) -> Result<Self, QueryCreationError> {
+        let first = valid_page_size(first, "first")?;
+        let last = valid_page_size(last, "last")?;
+        let after = after
+            .map(|after| Cursor::decode(&after, order, "after"))
+            .transpose()?;
+        let before = before
+            .map(|before| Cursor::decode(&before, order, "before"))
+            .transpose()?;
+        let (count, from_end) = match (first, last) {
+            (Some(_), Some(_)) => {
+                return Err(QueryCreationError::InvalidArgument(
+                    "last".to_owned(),
+                    "cannot be combined with first".to_owned(),
+                ))
+            }
+            (None, Some(last)) => (last, true),
+            (first, None) => (first.unwrap_or_else(|| MAX_PAGE_SIZE.into()), false),
+        };
+        Ok(Self {
+            after,
+            before,
+            count,
+            from_end,
+        })
+    }
+
+    pub fn from_selection(
+        selection: &LookAheadSelection<DefaultScalarValue>,
+        order: &HaikuOrder,
+    ) -> Result<Self, QueryCreationError> {
+        Self::new(
+            int_argument(selection, "first")?,
+            string_argument(selection, "after")?,
+            int_argument(selection, "last")?,
+            string_argument(selection, "before")?,
+            order,
+        )
+    }
+
+    // The pagination to fetch the window with, from each list if it's merged from several. One
+    // more haiku than the window holds is fetched, to tell whether there are more beyond it.
+    pub fn pagination(&self, order: &HaikuOrder) -> Pagination {
+        let first = self.count + 1;
+        order.pagination(if self.from_end { -first } else { first })
+    }
+}
+
+pub fn valid_page_size(size: Option<i32>, arg: &str) -> Result<Option<i64>, QueryCreationError> {
+    match size {
+        Some(size) if !(0..=MAX_PAGE_SIZE).contains(&size) => {
+            Err(QueryCreationError::InvalidArgument(
+                arg.to_owned(),
+                format!("must be between 0 and {}", MAX_PAGE_SIZE),
+            ))
+        }
+        size => Ok(size.map(i64::from)),
+    }
+}
+
+pub fn int_argument(
+    selection: &LookAheadSelection<DefaultScalarValue>,
+    arg: &str,
+) -> Result<Option<i32>, QueryCreationError> {
+    match selection.argument(arg).map(LookAheadArgument::value) {
+        None | Some(LookAheadValue::Null) => Ok(None),
+        Some(LookAheadValue::Scalar(DefaultScalarValue::Int(value))) => Ok(Some(*value)),
+        _ => Err(QueryCreationError::InvalidArgument(
+            arg.to_owned(),
+            "must be an integer".to_owned(),
+        )),
+    }
+}
+
+pub fn string_argument(
+    selection: &LookAheadSelection<DefaultScalarValue>,
+    arg: &str,
+) -> Result<Option<String>, QueryCreationError> {
+    match selection.argument(arg).map(LookAheadArgument::value) {
+        None | Some(LookAheadValue::Null) => Ok(None),
+        Some(LookAheadValue::Scalar(DefaultScalarValue::String(value))) => Ok(Some(value.clone())),
+        _ => Err(QueryCreationError::InvalidArgument(
+            arg.to_owned(),
+            "must be a string".to_owned(),
+        )),
+    }
+}
+
+fn count_alias(alias: &str) -> String {
+    format!("{}_count", alias)
+}
+
+// The fields to fetch for each haiku in a connection. The id and the value being ordered by are
+// always fetched, so that pages can be merged and so that pages made up of haikus with no other
+// selected fields still have an entry for every haiku.
+pub fn node_fields(
+    selection: &LookAheadSelection<DefaultScalarValue>,
+    order: &HaikuOrder,
+) -> Result<Vec<Field>, QueryCreationError> {
+    let mut fields = match selection
+        .select_child("edges")
+        .and_then(|edges| edges.select_child("node"))
+    {
+        Some(node) => Haiku::generate_inner_query(node)?,
+        None => Vec::new(),
+    };
+    if !fields.contains(&Field::uid("id")) {
+        fields.insert(0, Field::uid("id"));
+    }
+    if let Some(predicate) = order.predicate() {
+        if !fields.contains(&Field::value(predicate)) {
+            fields.push(Field::value(predicate));
+        }
+    }
+    Ok(fields)
+}
+
+// The fields for a connection following a single predicate: the page of haikus and their total
+pub fn connection_fields(
+    selection: &LookAheadSelection<DefaultScalarValue>,
+    predicate: Predicate,
+) -> Result<Vec<Field>, QueryCreationError> {
+    let arguments = ConnectionArguments::from_selection(selection)?;
+    let alias = arguments.alias(selection.field_name());
+    Ok(vec![
+        Edge::new(predicate.clone())
+            .alias(&alias)
+            .filter(arguments.page_filter())
+            .pagination(arguments.pagination())
+            .fields(node_fields(selection, &arguments.order)?)
+            .into(),
+        Field::count(&count_alias(&alias), predicate, arguments.dql_filter()),
+    ])
+}
+
+pub fn haiku_list(json: &serde_json::Value, key: &str) -> FieldResult<Vec<serde_json::Value>> {
+    match json.get(key) {
+        Some(serde_json::Value::Array(haikus)) => Ok(haikus.clone()),
+        None => Ok(Vec::new()),
+        _ => Err(data_corrupt()),
+    }
+}
+
+pub fn haiku_count(json: &serde_json::Value, key: &str) -> FieldResult<i64> {
+    match json.get(key) {
+        Some(serde_json::Value::Number(count)) => count.as_i64().ok_or_else(data_corrupt),
+        None => Ok(0),
+        _ => Err(data_corrupt()),
+    }
+}
+
+#[derive(Debug, juniper::GraphQLObject)]
+pub struct PageInfo {
+    has_next_page: bool,
+    has_previous_page: bool,
+    start_cursor: Option<String>,
+    end_cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct HaikuEdge {
+    cursor: String,
+    inner: serde_json::Value,
+}
+
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
+impl HaikuEdge {
+    fn cursor(&self) -> &str {
+        &self.cursor
+    }
+
+    fn node(&self) -> Haiku {
+        Haiku::from(self.inner.clone())
+    }
+}
+
+#[derive(Debug)]
+pub struct HaikuConnection {
+    edges: Vec<(String, serde_json::Value)>,
+    total: i64,
+    has_next_page: bool,
+    has_previous_page: bool,
+}
+
+impl HaikuConnection {
+    // Builds the page selected by the arguments from haikus fetched with their pagination, in the
+    // order Dgraph gives them back in, out of a list of total haikus
+    pub fn new(
+        mut haikus: Vec<serde_json::Value>,
+        total: i64,
+        arguments: &ConnectionArguments,
+    ) -> FieldResult<Self> {
+        let order = &arguments.order;
+        let window = &arguments.window;
+        if order.reversed() {
+            haikus.reverse();
+        }
+        let count = window.count as usize;
+        let more = haikus.len() > count;
+        if window.from_end {
+            haikus.drain(..haikus.len().saturating_sub(count));
+        } else {
+            haikus.truncate(count);
+        }
+        let edges = haikus
+            .into_iter()
+            .map(|haiku| {
+                let cursor = Cursor::new(&haiku, order).ok_or_else(data_corrupt)?;
+                Ok((cursor.encode(order), haiku))
+            })
+            .collect::<Result<_, FieldError>>()?;
+        // Whether there are haikus on the far side of a cursor isn't known without another query,
+        // but the haiku the cursor was taken from at least was there
+        Ok(Self {
+            edges,
+            total,
+            has_next_page: if window.from_end {
+                window.before.is_some()
+            } else {
+                more
+            },
+            has_previous_page: if window.from_end {
+                more
+            } else {
+                window.after.is_some()
+            },
+        })
+    }
+
+    // Reads a connection fetched with connection_fields
+    pub fn from_json(
+        json: &serde_json::Value,
+        field: &str,
+        arguments: &ConnectionArguments,
+    ) -> FieldResult<Self> {
+        let alias = arguments.alias(field);
+        Self::new(
+            haiku_list(json, &alias)?,
+            haiku_count(json, &count_alias(&alias))?,
+            arguments,
+        )
+    }
+}
+
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
+impl HaikuConnection {
+    fn edges(&self) -> Vec<HaikuEdge> {
+        self.edges
+            .iter()
+            .map(|(cursor, haiku)| HaikuEdge {
+                cursor: cursor.clone(),
+                inner: haiku.clone(),
+            })
+            .collect()
+    }
+
+    fn pageInfo(&self) -> PageInfo {
+        PageInfo {
+            has_next_page: self.has_next_page,
+            has_previous_page: self.has_previous_page,
+            start_cursor: self.edges.first().map(|(cursor, _)| cursor.clone()),
+            end_cursor: self.edges.last().map(|(cursor, _)| cursor.clone()),
+        }
+    }
+
+    fn totalCount(&self) -> i32 {
+        i32::try_from(self.total).unwrap_or(i32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::dql::{render, Query};
+    use super::*;
+    use rstest::rstest;
+
+    fn order(field: HaikuOrderField, direction: OrderDirection) -> HaikuOrder {
+        HaikuOrder { field, direction }
+    }
+
+    fn cursor(haiku: serde_json::Value) -> String {
+        base64::encode(&format!("{}{}", CURSOR_PREFIX, haiku))
+    }
+
+    #[rstest(
+        first,
+        last,
+        expected_count,
+        expected_from_end,
+        case(None, None, 100, false),
+        case(Some(2), None, 2, false),
+        case(None, Some(2), 2, true)
+    )]
+    fn create_window(
+        first: Option<i32>,
+        last: Option<i32>,
+        expected_count: i64,
+        expected_from_end: bool,
+    ) {
+        let window = Window::new(first, None, last, None, &HaikuOrder::default()).unwrap();
+        assert_eq!(window.count, expected_count);
+        assert_eq!(window.from_end, expected_from_end);
+    }
+
+    #[rstest(haiku, field,
+        case(json!({ "id": "0x5" }), HaikuOrderField::Id),
+        case(json!({ "id": "0x5", "timestamp": "2020-01-01T00:00:00Z" }), HaikuOrderField::Timestamp),
+        case(json!({ "id": "0x5", "rulesVersion": 1 }), HaikuOrderField::RulesVersion),
+    )]
+    fn decode_cursors(haiku: serde_json::Value, field: HaikuOrderField) {
+        let order = order(field, OrderDirection::Desc);
+        let decoded = Cursor::new(&haiku, &order).unwrap();
+        assert_eq!(decoded.uid, 5);
+        let encoded = decoded.encode(&order);
+        assert_eq!(encoded, cursor(haiku));
+        assert_eq!(Cursor::decode(&encoded, &order, "after").unwrap(), decoded);
+    }
+
+    #[rstest(first, after, last, field,
+        case(Some(1), None, Some(1), HaikuOrderField::Id),
+        case(Some(-1), None, None, HaikuOrderField::Id),
+        case(None, None, Some(MAX_PAGE_SIZE + 1), HaikuOrderField::Id),
+        case(None, Some("haiku:1"), None, HaikuOrderField::Id),
+        case(None, Some("aGFpa3U6MQ=="), None, HaikuOrderField::Id),
+        case(None, Some("aGFpa3U6eyJpZCI6IjB4MCJ9"), None, HaikuOrderField::Id),
+        case(None, Some("aGFpa3U6eyJpZCI6IjB4NSJ9"), None, HaikuOrderField::Timestamp),
+    )]
+    fn reject_invalid_window(
+        first: Option<i32>,
+        after: Option<&str>,
+        last: Option<i32>,
+        field: HaikuOrderField,
+    ) {
+        let order = order(field, OrderDirection::Asc);
+        assert!(Window::new(first, after.map(str::to_owned), last, None, &order).is_err());
+    }
+
+    #[test]
+    fn filter_between_cursors() {
+        let order = order(HaikuOrderField::RulesVersion, OrderDirection::Desc);
+        let arguments = ConnectionArguments {
+            window: Window::new(
+                None,
+                Some(cursor(json!({ "id": "0x5", "rulesVersion": 2 }))),
+                Some(2),
+                Some(cursor(json!({ "id": "0x9", "rulesVersion": 1 }))),
+                &order,
+            )
+            .unwrap(),
+            order,
+            filter: HaikuFilter::default(),
+        };
+        let query = Query::new("haikus").block(
+            Block::new("haikus", Function::Type("Haiku".to_owned()))
+                .filter(arguments.page_filter())
+                .pagination(arguments.pagination()),
+        );
+        assert_eq!(
+            render(&query).0,
+            r#"query haikus($v0: int, $v1: int, $v2: int, $v3: int, $v4: int, $v5: int) {
+  f0 as var(func: eq(rulesVersion, $v2), after: 0x5) @filter(type(Haiku))
+  f1 as var(func: eq(rulesVersion, $v5), after: 0x8) @filter(type(Haiku))
+  haikus(func: type(Haiku), orderdesc: rulesVersion, first: -3) @filter(type(Haiku) AND (lt(rulesVersion, $v0) OR (eq(rulesVersion, $v1) AND uid(f0))) AND (gt(rulesVersion, $v3) OR (eq(rulesVersion, $v4) AND NOT uid(f1)))) {
+  }
+}"#
+        );
+    }
+
+    #[test]
+    fn page_past_uid_cursors() {
+        let order = order(HaikuOrderField::Id, OrderDirection::Asc);
+        let arguments = ConnectionArguments {
+            window: Window::new(
+                Some(2),
+                Some(cursor(json!({ "id": "0x5" }))),
+                None,
+                Some(cursor(json!({ "id": "0x9" }))),
+                &order,
+            )
+            .unwrap(),
+            order,
+            filter: HaikuFilter::default(),
+        };
+        let query = Query::new("haikus").block(
+            Block::new("haikus", Function::Type("Haiku".to_owned()))
+                .filter(arguments.page_filter())
+                .pagination(arguments.pagination()),
+        );
+        assert_eq!(
+            render(&query).0,
+            r#"query haikus {
+  f0 as var(func: type(Haiku), after: 0x8) @filter(type(Haiku))
+  haikus(func: type(Haiku), first: 3, after: 0x5) @filter(type(Haiku) AND NOT uid(f0)) {
+  }
+}"#
+        );
+    }
+
+    #[rstest(field, direction, last, expected_ids, has_previous_page, has_next_page,
+        case(HaikuOrderField::Id, OrderDirection::Asc, None, vec!["0x1", "0x2"], false, true),
+        case(HaikuOrderField::Id, OrderDirection::Asc, Some(2), vec!["0x2", "0x3"], true, false),
+        case(HaikuOrderField::Id, OrderDirection::Desc, None, vec!["0x3", "0x2"], false, true),
+        case(HaikuOrderField::Id, OrderDirection::Desc, Some(2), vec!["0x2", "0x1"], true, false),
+        case(HaikuOrderField::Timestamp, OrderDirection::Asc, Some(5), vec!["0x1", "0x2", "0x3"], false, false),
+    )]
+    fn build_pages(
+        field: HaikuOrderField,
+        direction: OrderDirection,
+        last: Option<i32>,
+        expected_ids: Vec<&str>,
+        has_previous_page: bool,
+        has_next_page: bool,
+    ) {
+        let order = order(field, direction);
+        let first = if last.is_some() { None } else { Some(2) };
+        let arguments = ConnectionArguments {
+            window: Window::new(first, None, last, None, &order).unwrap(),
+            order,
+            filter: HaikuFilter::default(),
+        };
+        // As fetched from Dgraph, in ascending uid order
+        let haikus = (1..=3)
+            .map(|uid| json!({ "id": format!("{:#x}", uid), "timestamp": "2020-01-01T00:00:00Z" }))
+            .collect();
+        let connection = HaikuConnection::new(haikus, 3, &arguments).unwrap();
+        assert_eq!(
+            connection
+                .edges
+                .iter()
+                .map(|(_, haiku)| haiku["id"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            expected_ids
+        );
+        assert_eq!(connection.has_previous_page, has_previous_page);
+        assert_eq!(connection.has_next_page, has_next_page);
+    }
+}
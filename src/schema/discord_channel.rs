@@ -1,7 +1,13 @@
-use super::super::error::{internal_error, QueryCreationError};
-use super::discord_server::DiscordServer;
-use super::haiku::Haiku;
+use super::super::dql::{Edge, Field, Filter, Predicate};
+use super::super::error::{data_corrupt, query_creation_error, QueryCreationError};
+use super::connection::{
+    connection_fields, haiku_list, ConnectionArguments, HaikuConnection, HaikuOrder,
+};
+use super::discord_server::{server_fields, DiscordServer};
+use super::haiku_filter::HaikuFilter;
+use super::search::{SearchArguments, SearchMode, SearchResults};
 use super::util;
+use super::Context;
 use juniper::{DefaultScalarValue, FieldResult, LookAheadMethods, LookAheadSelection};
 
 #[derive(Debug)]
@@ -15,48 +21,71 @@ impl From<serde_json::Value> for DiscordChannel {
     }
 }
 
-#[juniper::object]
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
 impl DiscordChannel {
     fn discordSnowflake(&self) -> FieldResult<String> {
         match self.inner.get("discordSnowflake") {
             Some(serde_json::Value::String(snowflake)) => Ok(snowflake.clone()),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
+    // Channels are only ever fetched from servers the viewer is a member of
     fn server(&self) -> FieldResult<DiscordServer> {
         match self.inner.get("server") {
             Some(server) => Ok(DiscordServer::from(server.clone())),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
-    fn haikus(&self) -> FieldResult<Vec<Haiku>> {
-        match self.inner.get("haikus") {
-            Some(serde_json::Value::Array(haikus)) => Ok(haikus
-                .iter()
-                .map(|json| Haiku::from(json.clone()))
-                .collect()),
-            None => Ok(Vec::new()),
-            _ => Err(internal_error()),
-        }
+    fn haikus(
+        &self,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+        order_by: Option<HaikuOrder>,
+        filter: Option<HaikuFilter>,
+    ) -> FieldResult<HaikuConnection> {
+        let arguments = ConnectionArguments::new(first, after, last, before, order_by, filter)
+            .map_err(query_creation_error)?;
+        HaikuConnection::from_json(&self.inner, "haikus", &arguments)
+    }
+
+    fn search(
+        &self,
+        terms: String,
+        mode: Option<SearchMode>,
+        max: Option<i32>,
+    ) -> FieldResult<SearchResults> {
+        let arguments =
+            SearchArguments::new(terms, mode, None, max).map_err(query_creation_error)?;
+        let haikus = haiku_list(&self.inner, &arguments.alias("search"))?;
+        Ok(arguments.results(haikus))
     }
 }
 
 impl util::MapsToDgraphQuery for DiscordChannel {
     fn generate_inner_query_for_field(
         child_selection: &LookAheadSelection<DefaultScalarValue>,
-    ) -> Result<String, QueryCreationError> {
+    ) -> Result<Vec<Field>, QueryCreationError> {
         match child_selection.field_name() {
-            "discordSnowflake" => Ok("discordSnowflake".to_owned()),
-            "server" => Ok(format!(
-                "server: server @filter(type(DiscordServer)) {{ {} }}",
-                DiscordServer::generate_inner_query(child_selection)?
-            )),
-            "haikus" => Ok(format!(
-                "haikus: ~channel @filter(type(Haiku)) {{ {} }}",
-                Haiku::generate_inner_query(child_selection)?
-            )),
+            "discordSnowflake" => Ok(vec![Field::value("discordSnowflake")]),
+            "server" => Ok(vec![Edge::new(Predicate::forward("server"))
+                .alias("server")
+                .filter(Filter::type_of("DiscordServer"))
+                .fields(server_fields(child_selection)?)
+                .into()]),
+            "haikus" => connection_fields(child_selection, Predicate::reverse("channel")),
+            "search" => {
+                let arguments = SearchArguments::from_selection(child_selection)?;
+                Ok(vec![Edge::new(Predicate::reverse("channel"))
+                    .alias(&arguments.alias("search"))
+                    .filter(arguments.dql_filter())
+                    .pagination(arguments.pagination())
+                    .fields(arguments.fields(child_selection)?)
+                    .into()])
+            }
             unknown_field => Err(QueryCreationError::UnknownField(unknown_field.to_owned())),
         }
     }
@@ -65,22 +94,27 @@ impl util::MapsToDgraphQuery for DiscordChannel {
 #[cfg(test)]
 mod test {
     use super::*;
-    use juniper::{EmptyMutation, RootNode, Variables};
+    use juniper::{EmptyMutation, EmptySubscription, RootNode, Variables};
     use rstest::rstest;
 
-    type Schema = RootNode<'static, DiscordChannel, EmptyMutation<()>>;
+    type Schema =
+        RootNode<'static, DiscordChannel, EmptyMutation<Context>, EmptySubscription<Context>>;
 
     #[test]
     fn resolve_fields() {
+        let haikus_alias = ConnectionArguments::new(None, None, None, None, None, None)
+            .unwrap()
+            .alias("haikus");
         let channel_json = json!(
         {
             "discordSnowflake": "0000000000000000001",
             "server": {
                 "discordSnowflake": "0000000000000000002"
             },
-            "haikus": [{
+            haikus_alias.clone(): [{
                 "id": "1"
             }],
+            format!("{}_count", haikus_alias): 1,
         });
         let query = r#"
         query {
@@ -89,15 +123,24 @@ mod test {
                 discordSnowflake
             }
             haikus {
-                id
+                totalCount
+                edges {
+                    node {
+                        id
+                    }
+                }
             }
         }"#;
-        let (result, _errs) = juniper::execute(
+        let (result, _errs) = juniper::execute_sync(
             query,
             None,
-            &Schema::new(DiscordChannel::from(channel_json), EmptyMutation::new()),
+            &Schema::new(
+                DiscordChannel::from(channel_json),
+                EmptyMutation::new(),
+                EmptySubscription::new(),
+            ),
             &Variables::new(),
-            &(),
+            &util::test_context(),
         )
         .unwrap();
         assert_eq!(
@@ -107,9 +150,14 @@ mod test {
                 "server": {
                     "discordSnowflake": "0000000000000000002"
                 },
-                "haikus": [{
-                    "id": "1",
-                }],
+                "haikus": {
+                    "totalCount": 1,
+                    "edges": [{
+                        "node": {
+                            "id": "1",
+                        },
+                    }],
+                },
             })
         )
     }
@@ -117,9 +165,9 @@ mod test {
     #[rstest(query, expected_result,
         case("discordSnowflake", Err(vec!["discordSnowflake"])),
         case(r#"server { discordSnowflake }"#, Err(vec!["server"])),
-        case(r#"haikus { id }"#, Ok(graphql_value!({"haikus": []}))),
+        case(r#"haikus { totalCount edges { node { id } } }"#, Ok(graphql_value!({"haikus": {"totalCount": 0, "edges": []}}))),
     )]
     fn resolve_missing_fields(query: &str, expected_result: Result<juniper::Value, Vec<&str>>) {
-        util::resolve_missing_field::<DiscordChannel>(query, (), expected_result);
+        util::resolve_missing_field::<DiscordChannel>(query, util::test_context(), expected_result);
     }
 }
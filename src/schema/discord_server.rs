@@ -1,9 +1,17 @@
-use super::super::error::{internal_error, QueryCreationError};
+use super::super::dql::{Edge, Field, Filter, Function, Pagination, Predicate};
+use super::super::error::{data_corrupt, query_creation_error, QueryCreationError};
+use super::connection::{ConnectionArguments, HaikuConnection, HaikuOrder};
 use super::discord_channel::DiscordChannel;
-use super::haiku::Haiku;
-use super::util;
+use super::haiku_filter::HaikuFilter;
+use super::search::{SearchArguments, SearchMode, SearchResults, SearchScope};
+use super::util::{self, MapsToDgraphQuery};
+use super::Context;
+use super::{haikus_lookup, search_lookup};
 use juniper::{DefaultScalarValue, FieldResult, LookAheadMethods, LookAheadSelection};
 
+// Discord allows at most 500 channels in a server, so fetching that many never leaves any out
+pub const MAX_CHANNELS: i64 = 500;
+
 #[derive(Debug)]
 pub struct DiscordServer {
     inner: serde_json::Value,
@@ -15,13 +23,10 @@ impl From<serde_json::Value> for DiscordServer {
     }
 }
 
-#[juniper::object]
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
 impl DiscordServer {
     fn discordSnowflake(&self) -> FieldResult<String> {
-        match self.inner.get("discordSnowflake") {
-            Some(serde_json::Value::String(snowflake)) => Ok(snowflake.clone()),
-            _ => Err(internal_error()),
-        }
+        self.snowflake()
     }
 
     fn channels(&self) -> FieldResult<Vec<DiscordChannel>> {
@@ -31,47 +36,99 @@ impl DiscordServer {
                 .map(|json| DiscordChannel::from(json.clone()))
                 .collect()),
             None => Ok(Vec::new()),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
-    fn haikus(&self) -> FieldResult<Vec<Haiku>> {
-        if let Some(serde_json::Value::Array(channels)) = self.inner.get("haikuChannels") {
-            Ok(channels
-                .iter()
-                .flat_map(|channel_json| match channel_json.get("haikus") {
-                    Some(serde_json::Value::Array(haikus)) => haikus
-                        .iter()
-                        .map(|json| Haiku::from(json.clone()))
-                        .collect::<Vec<Haiku>>(),
-                    _ => vec![],
-                })
-                .collect::<Vec<Haiku>>())
-        } else {
-            Ok(vec![])
+    // A server's haikus are spread across its channels, so rather than fetching a page from every
+    // channel along with the server, they're looked up on their own
+    async fn haikus(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+        order_by: Option<HaikuOrder>,
+        filter: Option<HaikuFilter>,
+    ) -> FieldResult<HaikuConnection> {
+        let arguments = ConnectionArguments::new(first, after, last, before, order_by, filter)
+            .map_err(query_creation_error)?;
+        let scope = Filter::path(
+            &["channel", "server"],
+            Function::Eq("discordSnowflake".to_owned(), self.snowflake()?.into()),
+            Filter::type_of("DiscordServer"),
+        );
+        haikus_lookup(context, &executor.look_ahead(), &arguments, Some(scope)).await
+    }
+
+    // Like haikus, searches are made on their own within the server
+    async fn search(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        terms: String,
+        mode: Option<SearchMode>,
+        max: Option<i32>,
+    ) -> FieldResult<SearchResults> {
+        let scope = SearchScope {
+            server_snowflake: Some(self.snowflake()?),
+            channel_snowflake: None,
+        };
+        let arguments =
+            SearchArguments::new(terms, mode, Some(scope), max).map_err(query_creation_error)?;
+        search_lookup(context, &executor.look_ahead(), &arguments).await
+    }
+}
+
+impl DiscordServer {
+    fn snowflake(&self) -> FieldResult<String> {
+        match self.inner.get("discordSnowflake") {
+            Some(serde_json::Value::String(snowflake)) => Ok(snowflake.clone()),
+            _ => Err(data_corrupt()),
         }
     }
 }
 
+// The fields to fetch for a server nested in another node, which always include its snowflake so
+// that it can be checked against the viewer's servers
+pub fn server_fields(
+    selection: &LookAheadSelection<DefaultScalarValue>,
+) -> Result<Vec<Field>, QueryCreationError> {
+    let mut fields = DiscordServer::generate_inner_query(selection)?;
+    let snowflake = Field::value("discordSnowflake");
+    if !fields.contains(&snowflake) {
+        fields.push(snowflake);
+    }
+    Ok(fields)
+}
+
 impl util::MapsToDgraphQuery for DiscordServer {
     fn generate_inner_query_for_field(
         child_selection: &LookAheadSelection<DefaultScalarValue>,
-    ) -> Result<String, QueryCreationError> {
+    ) -> Result<Vec<Field>, QueryCreationError> {
         match child_selection.field_name() {
-            "discordSnowflake" => Ok("discordSnowflake".to_owned()),
-            "channels" => Ok(format!(
-                "channels: ~server @filter(type(DiscordChannel)) {{ {} }}",
-                DiscordChannel::generate_inner_query(child_selection)?
-            )),
-            "haikus" => Ok(format!(
-                r#"
-                haikuChannels: ~server @filter(type(DiscordChannel)) {{
-                    haikus: ~channel @filter(type(Haiku)) {{
-                        {}
-                    }}
-                }}"#,
-                Haiku::generate_inner_query(child_selection)?
-            )),
+            "discordSnowflake" => Ok(vec![Field::value("discordSnowflake")]),
+            "channels" => Ok(vec![Edge::new(Predicate::reverse("server"))
+                .alias("channels")
+                .filter(Filter::type_of("DiscordChannel"))
+                .pagination(Pagination {
+                    first: Some(MAX_CHANNELS),
+                    ..Pagination::default()
+                })
+                .fields(DiscordChannel::generate_inner_query(child_selection)?)
+                .into()]),
+            // Only the snowflake is needed to look these up once the server's been fetched, but the
+            // arguments are checked now so that a query with bad ones isn't made at all
+            "haikus" => {
+                ConnectionArguments::from_selection(child_selection)?;
+                Ok(vec![Field::value("discordSnowflake")])
+            }
+            "search" => {
+                SearchArguments::from_selection(child_selection)?;
+                Ok(vec![Field::value("discordSnowflake")])
+            }
             unknown_field => Err(QueryCreationError::UnknownField(unknown_field.to_owned())),
         }
     }
@@ -80,10 +137,11 @@ impl util::MapsToDgraphQuery for DiscordServer {
 #[cfg(test)]
 mod test {
     use super::*;
-    use juniper::{EmptyMutation, RootNode, Variables};
+    use juniper::{EmptyMutation, EmptySubscription, RootNode, Variables};
     use rstest::rstest;
 
-    type Schema = RootNode<'static, DiscordServer, EmptyMutation<()>>;
+    type Schema =
+        RootNode<'static, DiscordServer, EmptyMutation<Context>, EmptySubscription<Context>>;
 
     #[test]
     fn resolve_fields() {
@@ -93,13 +151,6 @@ mod test {
             "channels": [{
                 "discordSnowflake": "0000000000000000002"
             }],
-            "haikuChannels": [
-                {
-                    "haikus": [{
-                        "id": "1"
-                    }],
-                }
-            ]
         });
         let query = r#"
         query {
@@ -107,16 +158,17 @@ mod test {
             channels {
                 discordSnowflake
             }
-            haikus {
-                id
-            }
         }"#;
-        let (result, _errs) = juniper::execute(
+        let (result, _errs) = juniper::execute_sync(
             query,
             None,
-            &Schema::new(DiscordServer::from(server_json), EmptyMutation::new()),
+            &Schema::new(
+                DiscordServer::from(server_json),
+                EmptyMutation::new(),
+                EmptySubscription::new(),
+            ),
             &Variables::new(),
-            &(),
+            &util::test_context(),
         )
         .unwrap();
         assert_eq!(
@@ -126,9 +178,6 @@ mod test {
                 "channels": [{
                     "discordSnowflake": "0000000000000000002"
                 }],
-                "haikus": [{
-                    "id": "1",
-                }],
             })
         )
     }
@@ -136,9 +185,8 @@ mod test {
     #[rstest(query, expected_result,
         case("discordSnowflake", Err(vec!["discordSnowflake"])),
         case(r#"channels { discordSnowflake }"#, Ok(graphql_value!({"channels": []}))),
-        case(r#"haikus { id }"#, Ok(graphql_value!({"haikus": []}))),
     )]
     fn resolve_missing_fields(query: &str, expected_result: Result<juniper::Value, Vec<&str>>) {
-        util::resolve_missing_field::<DiscordServer>(query, (), expected_result);
+        util::resolve_missing_field::<DiscordServer>(query, util::test_context(), expected_result);
     }
 }
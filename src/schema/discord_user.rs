@@ -1,10 +1,17 @@
-use super::super::error::{internal_error, QueryCreationError};
+use super::super::dql::{Edge, Field, Function, Predicate};
+use super::super::error::{data_corrupt, query_creation_error, QueryCreationError};
+use super::connection::{
+    connection_fields, haiku_list, valid_page_size, ConnectionArguments, HaikuConnection,
+    HaikuOrder,
+};
 use super::haiku::Haiku;
+use super::haiku_filter::HaikuFilter;
+use super::search::valid_search_terms;
 use super::util;
+use super::Context;
 use juniper::{
     DefaultScalarValue, FieldResult, LookAheadMethods, LookAheadSelection, LookAheadValue,
 };
-use regex::Regex;
 
 #[derive(Debug)]
 pub struct DiscordUser {
@@ -17,131 +24,154 @@ impl From<serde_json::Value> for DiscordUser {
     }
 }
 
-#[juniper::object]
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
 impl DiscordUser {
     fn discordSnowflake(&self) -> FieldResult<String> {
         match self.inner.get("discordSnowflake") {
             Some(serde_json::Value::String(snowflake)) => Ok(snowflake.clone()),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
-    fn haikus(&self) -> FieldResult<Vec<Haiku>> {
-        match self.inner.get("haikus") {
-            Some(serde_json::Value::Array(haikus)) => Ok(haikus
-                .iter()
-                .map(|json| Haiku::from(json.clone()))
-                .collect()),
-            None => Ok(Vec::new()),
-            _ => Err(internal_error()),
-        }
+    fn haikus(
+        &self,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+        order_by: Option<HaikuOrder>,
+        filter: Option<HaikuFilter>,
+    ) -> FieldResult<HaikuConnection> {
+        let arguments = ConnectionArguments::new(first, after, last, before, order_by, filter)
+            .map_err(query_creation_error)?;
+        HaikuConnection::from_json(&self.inner, "haikus", &arguments)
     }
 
-    fn haikus_search(&self, search_term: String, max: i32) -> FieldResult<Vec<Haiku>> {
-        let alias = format!("haikusSearch_{:#x}", hash!(&search_term, &max));
-        match self.inner.get(&alias) {
-            Some(serde_json::Value::Array(haikus)) => Ok(haikus
-                .iter()
-                .map(|json| Haiku::from(json.clone()))
-                .collect()),
-            None => Ok(Vec::new()),
-            _ => Err(internal_error()),
+    fn haikus_search(
+        &self,
+        search_term: String,
+        max: i32,
+        order_by: Option<HaikuOrder>,
+        filter: Option<HaikuFilter>,
+    ) -> FieldResult<Vec<Haiku>> {
+        let order = order_by.unwrap_or_default();
+        let filter = filter
+            .unwrap_or_default()
+            .validate()
+            .map_err(query_creation_error)?;
+        let alias = search_alias(&search_term, max, &order, &filter);
+        let mut haikus = haiku_list(&self.inner, &alias)?;
+        if order.reversed() {
+            haikus.reverse();
         }
+        Ok(haikus.into_iter().map(Haiku::from).collect())
     }
 }
 
 impl util::MapsToDgraphQuery for DiscordUser {
     fn generate_inner_query_for_field(
         child_selection: &LookAheadSelection<DefaultScalarValue>,
-    ) -> Result<String, QueryCreationError> {
+    ) -> Result<Vec<Field>, QueryCreationError> {
         match child_selection.field_name() {
-            "discordSnowflake" => Ok("discordSnowflake".to_owned()),
-            "haikus" => Ok(format!(
-                "haikus: ~author @filter(type(Haiku)) {{ {} }}",
-                Haiku::generate_inner_query(child_selection)?
-            )),
+            "discordSnowflake" => Ok(vec![Field::value("discordSnowflake")]),
+            "haikus" => connection_fields(child_selection, Predicate::reverse("author")),
             "haikusSearch" => {
-                let search_term = child_selection
-                    .argument("searchTerm")
-                    .ok_or(QueryCreationError::MissingArgument("searchTerm".to_owned()))?;
-                let search_term = match search_term.value() {
-                    LookAheadValue::Scalar(DefaultScalarValue::String(term)) => Ok(term),
-                    _ => Err(QueryCreationError::InvalidArgument(
-                        "searchTerm".to_owned(),
-                        "must be a string".to_owned(),
-                    )),
-                }?
-                .clone();
-                let search_term = valid_search_terms(search_term)?;
-
-                let max = child_selection
-                    .argument("max")
-                    .ok_or(QueryCreationError::MissingArgument("max".to_owned()))?;
-                let max = match max.value() {
-                    LookAheadValue::Scalar(DefaultScalarValue::Int(max)) => Ok(max),
-                    _ => Err(QueryCreationError::InvalidArgument(
-                        "max".to_owned(),
-                        "must be an integer".to_owned(),
-                    )),
-                }?;
-
-                Ok(format!(
-                    r#"{}: ~author @filter(type(Haiku) AND anyofterms(content, "{}")) (first: {}) {{ {} }}"#,
-                    format!("haikusSearch_{:#x}", hash!(&search_term, &max)),
-                    search_term,
-                    max,
-                    Haiku::generate_inner_query(child_selection)?
-                ))
+                let (search_term, max) = search_arguments(child_selection)?;
+                let order = HaikuOrder::from_selection(child_selection)?;
+                let filter = HaikuFilter::from_selection(child_selection)?.validate()?;
+                Ok(vec![Edge::new(Predicate::reverse("author"))
+                    .alias(&search_alias(&search_term, max, &order, &filter))
+                    .filter(
+                        filter.to_dql().and(
+                            Function::AnyOfTerms("content".to_owned(), search_term.into()).into(),
+                        ),
+                    )
+                    .pagination(order.pagination(max.into()))
+                    .fields(Haiku::generate_inner_query(child_selection)?)
+                    .into()])
             }
             unknown_field => Err(QueryCreationError::UnknownField(unknown_field.to_owned())),
         }
     }
 }
 
-fn valid_search_terms(terms: String) -> Result<String, QueryCreationError> {
-    lazy_static! {
-        static ref SEARCH_TERM_REGEX: Regex =
-            Regex::new(r"^([[:alpha:]]+ )*[[:alpha:]]+$").unwrap();
-    }
-    if SEARCH_TERM_REGEX.is_match(&terms) {
-        Ok(terms)
-    } else {
-        Err(QueryCreationError::InvalidArgument(
+// The key a haikusSearch result is stored under, allowing several searches to be made at once
+fn search_alias(search_term: &str, max: i32, order: &HaikuOrder, filter: &HaikuFilter) -> String {
+    format!("haikusSearch_{:#x}", hash!(search_term, max, order, filter))
+}
+
+fn search_arguments(
+    selection: &LookAheadSelection<DefaultScalarValue>,
+) -> Result<(String, i32), QueryCreationError> {
+    let search_term = selection
+        .argument("searchTerm")
+        .ok_or(QueryCreationError::MissingArgument("searchTerm".to_owned()))?;
+    let search_term = match search_term.value() {
+        LookAheadValue::Scalar(DefaultScalarValue::String(term)) => Ok(term),
+        _ => Err(QueryCreationError::InvalidArgument(
             "searchTerm".to_owned(),
-            "must be a set of words made up of only alphabetic characters separated by spaces"
-                .to_owned(),
-        ))
-    }
+            "must be a string".to_owned(),
+        )),
+    }?
+    .clone();
+    let search_term = valid_search_terms(search_term, "searchTerm")?;
+
+    let max = selection
+        .argument("max")
+        .ok_or(QueryCreationError::MissingArgument("max".to_owned()))?;
+    let max = match max.value() {
+        LookAheadValue::Scalar(DefaultScalarValue::Int(max)) => Ok(*max),
+        _ => Err(QueryCreationError::InvalidArgument(
+            "max".to_owned(),
+            "must be an integer".to_owned(),
+        )),
+    }?;
+    valid_page_size(Some(max), "max")?;
+    Ok((search_term, max))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use juniper::{EmptyMutation, RootNode, Variables};
+    use juniper::{EmptyMutation, EmptySubscription, RootNode, Variables};
     use rstest::rstest;
-    type Schema = RootNode<'static, DiscordUser, EmptyMutation<()>>;
+    type Schema =
+        RootNode<'static, DiscordUser, EmptyMutation<Context>, EmptySubscription<Context>>;
 
     #[test]
     fn resolve_fields() {
+        let haikus_alias = ConnectionArguments::new(Some(1), None, None, None, None, None)
+            .unwrap()
+            .alias("haikus");
         let user_json = json!(
         {
             "discordSnowflake": "0000000000000000001",
-            "haikus": [{
-                "id": "1",
+            haikus_alias.clone(): [{
+                "id": "1"
+            }, {
                 "id": "2"
             }],
-            format!("haikusSearch_{:#x}", hash!("a", &2)): [{
+            format!("{}_count", haikus_alias): 2,
+            format!("haikusSearch_{:#x}", hash!("a", &2, HaikuOrder::default(), HaikuFilter::default())): [{
                 "id": "1"
             }],
-            format!("haikusSearch_{:#x}", hash!("b", &2)): [{
+            format!("haikusSearch_{:#x}", hash!("b", &2, HaikuOrder::default(), HaikuFilter::default())): [{
                 "id": "2"
             }],
         });
         let query = r#"
         query {
             discordSnowflake
-            haikus {
-                id
+            haikus(first: 1) {
+                totalCount
+                edges {
+                    node {
+                        id
+                    }
+                }
+                pageInfo {
+                    hasNextPage
+                }
             }
             haikusSearch(searchTerm: "a", max: 2) {
                 id
@@ -150,22 +180,33 @@ mod test {
                 id
             }
         }"#;
-        let (result, _errs) = juniper::execute(
+        let (result, _errs) = juniper::execute_sync(
             query,
             None,
-            &Schema::new(DiscordUser::from(user_json), EmptyMutation::new()),
+            &Schema::new(
+                DiscordUser::from(user_json),
+                EmptyMutation::new(),
+                EmptySubscription::new(),
+            ),
             &Variables::new(),
-            &(),
+            &util::test_context(),
         )
         .unwrap();
         assert_eq!(
             result,
             graphql_value!({
                 "discordSnowflake": "0000000000000000001",
-                "haikus": [{
-                    "id": "1",
-                    "id": "2"
-                }],
+                "haikus": {
+                    "totalCount": 2,
+                    "edges": [{
+                        "node": {
+                            "id": "1",
+                        },
+                    }],
+                    "pageInfo": {
+                        "hasNextPage": true,
+                    },
+                },
                 "haikusSearch": [{
                     "id": "1",
                 }],
@@ -178,10 +219,10 @@ mod test {
 
     #[rstest(query, expected_result,
         case("discordSnowflake", Err(vec!["discordSnowflake"])),
-        case(r#"haikus { id }"#, Ok(graphql_value!({"haikus": []}))),
+        case(r#"haikus { totalCount edges { node { id } } }"#, Ok(graphql_value!({"haikus": {"totalCount": 0, "edges": []}}))),
         case(r#"haikusSearch(searchTerm: "a", max: 2) { id }"#, Ok(graphql_value!({"haikusSearch": []}))),
     )]
     fn resolve_missing_fields(query: &str, expected_result: Result<juniper::Value, Vec<&str>>) {
-        util::resolve_missing_field::<DiscordUser>(query, (), expected_result);
+        util::resolve_missing_field::<DiscordUser>(query, util::test_context(), expected_result);
     }
 }
@@ -1,11 +1,14 @@
-use super::super::error::{internal_error, invalid_input, QueryCreationError};
+use super::super::dql::{Block, Edge, Field, Filter, Function, Pagination, Predicate, Query};
+use super::super::error::{data_corrupt, invalid_argument, QueryCreationError};
 use super::discord_channel::DiscordChannel;
-use super::discord_server::DiscordServer;
+use super::discord_server::{server_fields, DiscordServer};
 use super::discord_user::DiscordUser;
-use super::util;
+use super::util::{self, valid_snowflake};
+use super::Context;
 use chrono::{DateTime, Utc};
 use juniper::{DefaultScalarValue, FieldError, FieldResult, LookAheadMethods, LookAheadSelection};
 use regex::Regex;
+use serde_json::json;
 
 #[derive(Debug)]
 pub struct Haiku {
@@ -18,12 +21,12 @@ impl From<serde_json::Value> for Haiku {
     }
 }
 
-#[juniper::object]
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
 impl Haiku {
     fn id(&self) -> FieldResult<String> {
         match self.inner.get("id") {
             Some(serde_json::Value::String(id)) => Ok(id.clone()),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
@@ -33,24 +36,25 @@ impl Haiku {
                 .iter()
                 .map(|json| DiscordUser::from(json.clone()))
                 .collect()),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
     fn content(&self) -> FieldResult<String> {
         match self.inner.get("content") {
             Some(serde_json::Value::String(content)) => Ok(content.clone()),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
     fn channel(&self) -> FieldResult<DiscordChannel> {
         match self.inner.get("channel") {
             Some(json) => Ok(DiscordChannel::from(json.clone())),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
+    // Haikus are only ever fetched from servers the viewer is a member of
     fn server(&self) -> FieldResult<DiscordServer> {
         match self
             .inner
@@ -58,7 +62,7 @@ impl Haiku {
             .map(|channel_json| channel_json.get("server"))
         {
             Some(Some(json)) => Ok(DiscordServer::from(json.clone())),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 
@@ -68,16 +72,16 @@ impl Haiku {
                 return Ok(version as i32);
             }
         }
-        return Err(internal_error());
+        return Err(data_corrupt());
     }
 
     fn timestamp(&self) -> FieldResult<DateTime<Utc>> {
         match self.inner.get("timestamp") {
             Some(timestamp) => serde_json::from_value(timestamp.clone()).map_err(|err| {
-                error!("Error deserializing timestamp");
-                internal_error()
+                error!("Error deserializing timestamp: {}", err);
+                data_corrupt()
             }),
-            _ => Err(internal_error()),
+            _ => Err(data_corrupt()),
         }
     }
 }
@@ -85,27 +89,33 @@ impl Haiku {
 impl util::MapsToDgraphQuery for Haiku {
     fn generate_inner_query_for_field(
         child_selection: &LookAheadSelection<DefaultScalarValue>,
-    ) -> Result<String, QueryCreationError> {
+    ) -> Result<Vec<Field>, QueryCreationError> {
         match child_selection.field_name() {
-            "id" => Ok("id: uid".to_owned()),
-            "authors" => Ok(format!(
-                "authors: author @filter(type(DiscordUser)) {{ {} }}",
-                DiscordUser::generate_inner_query(child_selection)?
-            )),
-            "content" => Ok("content".to_owned()),
-            "channel" => Ok(format!(
-                "channel @filter(type(DiscordChannel)) {{ {} }}",
-                DiscordChannel::generate_inner_query(child_selection)?
-            )),
-            "server" => Ok(format!(
-                r#"
-                serverChannel: channel @filter(type(DiscordChannel)) {{
-                    server @filter(type(DiscordServer)) {{
-                        {}
-                    }}
-                }}"#,
-                DiscordServer::generate_inner_query(child_selection)?
-            )),
+            "id" => Ok(vec![Field::uid("id")]),
+            "authors" => Ok(vec![Edge::new(Predicate::forward("author"))
+                .alias("authors")
+                .filter(Filter::type_of("DiscordUser"))
+                .pagination(Pagination {
+                    first: Some(MAX_AUTHORS as i64),
+                    ..Pagination::default()
+                })
+                .fields(DiscordUser::generate_inner_query(child_selection)?)
+                .into()]),
+            "content" => Ok(vec![Field::value("content")]),
+            "channel" => Ok(vec![Edge::new(Predicate::forward("channel"))
+                .filter(Filter::type_of("DiscordChannel"))
+                .fields(DiscordChannel::generate_inner_query(child_selection)?)
+                .into()]),
+            "server" => Ok(vec![Edge::new(Predicate::forward("channel"))
+                .alias("serverChannel")
+                .filter(Filter::type_of("DiscordChannel"))
+                .fields(vec![Edge::new(Predicate::forward("server"))
+                    .filter(Filter::type_of("DiscordServer"))
+                    .fields(server_fields(child_selection)?)
+                    .into()])
+                .into()]),
+            "rulesVersion" => Ok(vec![Field::value("rulesVersion")]),
+            "timestamp" => Ok(vec![Field::value("timestamp")]),
             unknown_field => Err(QueryCreationError::UnknownField(unknown_field.to_owned())),
         }
     }
@@ -113,24 +123,127 @@ impl util::MapsToDgraphQuery for Haiku {
 
 pub fn valid_haiku_id(id: String) -> Result<String, FieldError> {
     lazy_static! {
-        static ref HAIKU_ID_REGEX: Regex = Regex::new(r"^0x\d+$").unwrap();
+        static ref HAIKU_ID_REGEX: Regex = Regex::new(r"^0x[[:xdigit:]]+$").unwrap();
     }
     if HAIKU_ID_REGEX.is_match(&id) {
         Ok(id)
     } else {
-        Err(invalid_input(
+        Err(invalid_argument(
+            "haikuId",
             r#"Invalid haiku id: must be of the form "0x<ID>""#,
         ))
     }
 }
 
+#[derive(Debug, juniper::GraphQLInputObject)]
+pub struct NewHaiku {
+    pub content: String,
+    pub author_snowflakes: Vec<String>,
+    pub channel_snowflake: String,
+    pub server_snowflake: String,
+    pub rules_version: i32,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub const NEW_HAIKU_BLANK_NODE: &str = "haiku";
+// The most authors a haiku can have, which bounds how many are fetched along with it
+pub const MAX_AUTHORS: usize = 10;
+
+impl NewHaiku {
+    // Checks the snowflakes and content, normalising the author list so that it can be stored
+    pub fn validate(self) -> Result<Self, FieldError> {
+        if self.content.trim().is_empty() {
+            return Err(invalid_argument(
+                "input.content",
+                "Invalid haiku: content must not be empty",
+            ));
+        }
+        let mut author_snowflakes = self
+            .author_snowflakes
+            .into_iter()
+            .map(|snowflake| valid_snowflake(snowflake, "input.authorSnowflakes"))
+            .collect::<Result<Vec<String>, FieldError>>()?;
+        author_snowflakes.sort();
+        author_snowflakes.dedup();
+        if author_snowflakes.is_empty() {
+            return Err(invalid_argument(
+                "input.authorSnowflakes",
+                "Invalid haiku: must have at least one author",
+            ));
+        }
+        if author_snowflakes.len() > MAX_AUTHORS {
+            return Err(invalid_argument(
+                "input.authorSnowflakes",
+                &format!("Invalid haiku: must have at most {} authors", MAX_AUTHORS),
+            ));
+        }
+        Ok(Self {
+            author_snowflakes,
+            channel_snowflake: valid_snowflake(self.channel_snowflake, "input.channelSnowflake")?,
+            server_snowflake: valid_snowflake(self.server_snowflake, "input.serverSnowflake")?,
+            ..self
+        })
+    }
+
+    // Produces the query and set_json halves of an upsert block for a validated haiku. Users, the
+    // channel and the server are matched by snowflake so existing nodes are reused and missing ones
+    // are created.
+    pub fn generate_upsert(&self) -> (Query, serde_json::Value) {
+        let var = |variable: &str, snowflake: &str, type_name: &str| {
+            Block::var(
+                variable,
+                Function::Eq("discordSnowflake".to_owned(), snowflake.into()),
+            )
+            .filter(Filter::type_of(type_name))
+        };
+        let mut query = Query::new("addHaiku")
+            .block(var("server", &self.server_snowflake, "DiscordServer"))
+            .block(var("channel", &self.channel_snowflake, "DiscordChannel"));
+        let mut nodes = vec![
+            json!({
+                "uid": "uid(server)",
+                "discordSnowflake": self.server_snowflake,
+                "dgraph.type": "DiscordServer",
+            }),
+            json!({
+                "uid": "uid(channel)",
+                "discordSnowflake": self.channel_snowflake,
+                "dgraph.type": "DiscordChannel",
+                "server": { "uid": "uid(server)" },
+            }),
+        ];
+        let mut authors = Vec::new();
+        for (index, snowflake) in self.author_snowflakes.iter().enumerate() {
+            let variable = format!("author_{}", index);
+            query = query.block(var(&variable, snowflake, "DiscordUser"));
+            nodes.push(json!({
+                "uid": format!("uid({})", variable),
+                "discordSnowflake": snowflake,
+                "dgraph.type": "DiscordUser",
+            }));
+            authors.push(json!({ "uid": format!("uid({})", variable) }));
+        }
+        nodes.push(json!({
+            "uid": format!("_:{}", NEW_HAIKU_BLANK_NODE),
+            "author": authors,
+            "content": self.content,
+            "channel": { "uid": "uid(channel)" },
+            "rulesVersion": self.rules_version,
+            "timestamp": self.timestamp,
+            "dgraph.type": "Haiku",
+        }));
+        (query, serde_json::Value::Array(nodes))
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::super::super::dql::render;
     use super::*;
-    use juniper::{EmptyMutation, RootNode, Variables};
+    use juniper::{EmptyMutation, EmptySubscription, RootNode, Variables};
     use rstest::rstest;
 
-    type Schema = RootNode<'static, Haiku, EmptyMutation<()>>;
+    type Schema = RootNode<'static, Haiku, EmptyMutation<Context>, EmptySubscription<Context>>;
 
     #[test]
     fn resolve_fields() {
@@ -168,12 +281,16 @@ mod test {
             rulesVersion
             timestamp
         }"#;
-        let (result, _errs) = juniper::execute(
+        let (result, _errs) = juniper::execute_sync(
             query,
             None,
-            &Schema::new(Haiku::from(haiku_json), EmptyMutation::new()),
+            &Schema::new(
+                Haiku::from(haiku_json),
+                EmptyMutation::new(),
+                EmptySubscription::new(),
+            ),
             &Variables::new(),
-            &(),
+            &util::test_context(),
         )
         .unwrap();
         assert_eq!(
@@ -206,6 +323,99 @@ mod test {
         case("timestamp", Err(vec!["timestamp"])),
     )]
     fn resolve_missing_fields(query: &str, expected_result: Result<juniper::Value, Vec<&str>>) {
-        util::resolve_missing_field::<Haiku>(query, (), expected_result);
+        util::resolve_missing_field::<Haiku>(query, util::test_context(), expected_result);
+    }
+
+    fn new_haiku(author_snowflakes: Vec<&str>) -> NewHaiku {
+        NewHaiku {
+            content: "line 1\nline 2\nline 3".to_owned(),
+            author_snowflakes: author_snowflakes.into_iter().map(str::to_owned).collect(),
+            channel_snowflake: "2".to_owned(),
+            server_snowflake: "1".to_owned(),
+            rules_version: 1,
+            timestamp: "1977-02-03T05:00:00Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn generate_upsert() {
+        let (query, set_json) = new_haiku(vec!["4", "3", "4"])
+            .validate()
+            .unwrap()
+            .generate_upsert();
+        let (text, vars) = render(&query);
+        assert_eq!(
+            text,
+            r#"query addHaiku($v0: string, $v1: string, $v2: string, $v3: string) {
+  server as var(func: eq(discordSnowflake, $v0)) @filter(type(DiscordServer))
+  channel as var(func: eq(discordSnowflake, $v1)) @filter(type(DiscordChannel))
+  author_0 as var(func: eq(discordSnowflake, $v2)) @filter(type(DiscordUser))
+  author_1 as var(func: eq(discordSnowflake, $v3)) @filter(type(DiscordUser))
+}"#
+        );
+        assert_eq!(
+            vars,
+            vec![("$v0", "1"), ("$v1", "2"), ("$v2", "3"), ("$v3", "4")]
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect()
+        );
+        assert_eq!(
+            set_json,
+            json!([
+                {
+                    "uid": "uid(server)",
+                    "discordSnowflake": "1",
+                    "dgraph.type": "DiscordServer",
+                },
+                {
+                    "uid": "uid(channel)",
+                    "discordSnowflake": "2",
+                    "dgraph.type": "DiscordChannel",
+                    "server": { "uid": "uid(server)" },
+                },
+                {
+                    "uid": "uid(author_0)",
+                    "discordSnowflake": "3",
+                    "dgraph.type": "DiscordUser",
+                },
+                {
+                    "uid": "uid(author_1)",
+                    "discordSnowflake": "4",
+                    "dgraph.type": "DiscordUser",
+                },
+                {
+                    "uid": "_:haiku",
+                    "author": [{ "uid": "uid(author_0)" }, { "uid": "uid(author_1)" }],
+                    "content": "line 1\nline 2\nline 3",
+                    "channel": { "uid": "uid(channel)" },
+                    "rulesVersion": 1,
+                    "timestamp": "1977-02-03T05:00:00Z",
+                    "dgraph.type": "Haiku",
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn normalize_snowflakes() {
+        let haiku = NewHaiku {
+            channel_snowflake: "0002".to_owned(),
+            ..new_haiku(vec!["003", "3"])
+        }
+        .validate()
+        .unwrap();
+        assert_eq!(haiku.author_snowflakes, vec!["3".to_owned()]);
+        assert_eq!(haiku.channel_snowflake, "2");
+        assert_eq!(haiku.server_snowflake, "1");
+    }
+
+    #[rstest(author_snowflakes,
+        case(vec![]),
+        case(vec!["not a snowflake"]),
+        case(vec!["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11"]),
+    )]
+    fn validate_invalid_authors(author_snowflakes: Vec<&str>) {
+        assert!(new_haiku(author_snowflakes).validate().is_err());
     }
 }
@@ -0,0 +1,72 @@
+use super::super::detection::{Rejection, Rules};
+use super::super::error::invalid_argument;
+use juniper::FieldError;
+
+// Why some text isn't a haiku
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum HaikuRejection {
+    Empty,
+    UncountableWord,
+    TooFewSyllables,
+    TooManySyllables,
+    NoLineBreak,
+}
+
+impl From<Rejection> for HaikuRejection {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Empty => HaikuRejection::Empty,
+            Rejection::UncountableWord => HaikuRejection::UncountableWord,
+            Rejection::TooFewSyllables => HaikuRejection::TooFewSyllables,
+            Rejection::TooManySyllables => HaikuRejection::TooManySyllables,
+            Rejection::NoLineBreak => HaikuRejection::NoLineBreak,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct WordSyllables {
+    pub word: String,
+    // Null when the word can't be counted, eg. "mp3"
+    pub syllables: Option<i32>,
+}
+
+// Whether some text is a haiku under a set of rules, along with the working out
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct HaikuCheck {
+    pub is_haiku: bool,
+    pub rules_version: i32,
+    pub lines: Option<Vec<String>>,
+    pub words: Vec<WordSyllables>,
+    pub rejection: Option<HaikuRejection>,
+}
+
+impl HaikuCheck {
+    // Checks the text with the given rules version, or the latest rules if none is given
+    pub fn new(text: &str, rules_version: Option<i32>) -> Result<Self, FieldError> {
+        let rules = match rules_version {
+            Some(version) => Rules::for_version(version).ok_or_else(|| {
+                invalid_argument(
+                    "rulesVersion",
+                    &format!("Invalid rulesVersion: unknown version {}", version),
+                )
+            })?,
+            None => Rules::latest(),
+        };
+        let check = rules.check(text);
+        Ok(Self {
+            is_haiku: check.lines.is_ok(),
+            rules_version: rules.version,
+            lines: check.lines.clone().ok(),
+            words: check
+                .words
+                .into_iter()
+                .map(|(word, syllables)| WordSyllables {
+                    word,
+                    syllables: syllables.map(|syllables| syllables as i32),
+                })
+                .collect(),
+            rejection: check.lines.err().map(HaikuRejection::from),
+        })
+    }
+}
@@ -0,0 +1,193 @@
+use super::super::dql::{Filter, Function};
+use super::super::error::QueryCreationError;
+use super::util::parse_snowflake;
+use chrono::{DateTime, Utc};
+use juniper::{
+    DefaultScalarValue, LookAheadArgument, LookAheadMethods, LookAheadSelection, LookAheadValue,
+};
+
+// Narrows down a list of haikus. Every given condition has to hold, with the snowflake lists
+// matching haikus by any of the listed authors or channels.
+#[derive(Debug, Clone, Default, PartialEq, Hash, juniper::GraphQLInputObject)]
+pub struct HaikuFilter {
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+    pub rules_version: Option<i32>,
+    pub author_snowflakes: Option<Vec<String>>,
+    pub channel_snowflakes: Option<Vec<String>>,
+}
+
+impl HaikuFilter {
+    pub fn from_selection(
+        selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Self, QueryCreationError> {
+        let fields = match selection.argument("filter").map(LookAheadArgument::value) {
+            None | Some(LookAheadValue::Null) => return Ok(Self::default()),
+            Some(LookAheadValue::Object(fields)) => fields,
+            _ => return Err(invalid_filter("must be an object")),
+        };
+        let mut filter = Self::default();
+        for (name, value) in fields {
+            match (*name, value) {
+                (_, LookAheadValue::Null) => (),
+                ("after", value) => filter.after = Some(datetime_value(value, name)?),
+                ("before", value) => filter.before = Some(datetime_value(value, name)?),
+                ("rulesVersion", LookAheadValue::Scalar(DefaultScalarValue::Int(version))) => {
+                    filter.rules_version = Some(*version)
+                }
+                ("authorSnowflakes", value) => {
+                    filter.author_snowflakes = Some(string_list_value(value, name)?)
+                }
+                ("channelSnowflakes", value) => {
+                    filter.channel_snowflakes = Some(string_list_value(value, name)?)
+                }
+                (name, _) => return Err(invalid_filter(&format!("invalid value for {}", name))),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn validate(self) -> Result<Self, QueryCreationError> {
+        Ok(Self {
+            author_snowflakes: valid_snowflakes(self.author_snowflakes, "authorSnowflakes")?,
+            channel_snowflakes: valid_snowflakes(self.channel_snowflakes, "channelSnowflakes")?,
+            ..self
+        })
+    }
+
+    // The DQL filter matching haikus which pass this filter
+    pub fn to_dql(&self) -> Filter {
+        let mut filter = Filter::type_of("Haiku");
+        if let Some(after) = self.after {
+            filter =
+                filter.and(Function::Ge("timestamp".to_owned(), after.to_rfc3339().into()).into());
+        }
+        if let Some(before) = self.before {
+            filter =
+                filter.and(Function::Le("timestamp".to_owned(), before.to_rfc3339().into()).into());
+        }
+        if let Some(rules_version) = self.rules_version {
+            filter = filter.and(
+                Function::Eq("rulesVersion".to_owned(), i64::from(rules_version).into()).into(),
+            );
+        }
+        if let Some(snowflakes) = &self.author_snowflakes {
+            filter = filter.and(Filter::edge(
+                "author",
+                Function::Eq("discordSnowflake".to_owned(), snowflakes.clone().into()),
+                Filter::type_of("DiscordUser"),
+            ));
+        }
+        if let Some(snowflakes) = &self.channel_snowflakes {
+            filter = filter.and(Filter::edge(
+                "channel",
+                Function::Eq("discordSnowflake".to_owned(), snowflakes.clone().into()),
+                Filter::type_of("DiscordChannel"),
+            ));
+        }
+        filter
+    }
+}
+
+fn valid_snowflakes(
+    snowflakes: Option<Vec<String>>,
+    name: &str,
+) -> Result<Option<Vec<String>>, QueryCreationError> {
+    let snowflakes = match snowflakes {
+        Some(snowflakes) if snowflakes.is_empty() => {
+            return Err(invalid_filter(&format!("{} must not be empty", name)))
+        }
+        Some(snowflakes) => snowflakes,
+        None => return Ok(None),
+    };
+    snowflakes
+        .iter()
+        .map(|snowflake| {
+            parse_snowflake(snowflake).ok_or_else(|| {
+                invalid_filter(&format!("{} must only contain discord snowflakes", name))
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn invalid_filter(msg: &str) -> QueryCreationError {
+    QueryCreationError::InvalidArgument("filter".to_owned(), msg.to_owned())
+}
+
+fn datetime_value(
+    value: &LookAheadValue<DefaultScalarValue>,
+    name: &str,
+) -> Result<DateTime<Utc>, QueryCreationError> {
+    match value {
+        LookAheadValue::Scalar(DefaultScalarValue::String(datetime)) => {
+            DateTime::parse_from_rfc3339(datetime)
+                .map(|datetime| datetime.with_timezone(&Utc))
+                .map_err(|_| invalid_filter(&format!("{} must be an RFC 3339 datetime", name)))
+        }
+        _ => Err(invalid_filter(&format!("{} must be a datetime", name))),
+    }
+}
+
+fn string_list_value(
+    value: &LookAheadValue<DefaultScalarValue>,
+    name: &str,
+) -> Result<Vec<String>, QueryCreationError> {
+    let invalid = || invalid_filter(&format!("{} must be a list of strings", name));
+    match value {
+        LookAheadValue::List(values) => values
+            .iter()
+            .map(|value| match value {
+                LookAheadValue::Scalar(DefaultScalarValue::String(value)) => Ok(value.clone()),
+                _ => Err(invalid()),
+            })
+            .collect(),
+        // Input coercion allows a single value to be given in place of a list
+        LookAheadValue::Scalar(DefaultScalarValue::String(value)) => Ok(vec![value.clone()]),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(author_snowflakes, valid,
+        case(None, true),
+        case(Some(vec!["1", "2"]), true),
+        case(Some(vec![]), false),
+        case(Some(vec!["1", "0x2"]), false),
+    )]
+    fn validate_filter(author_snowflakes: Option<Vec<&str>>, valid: bool) {
+        let filter = HaikuFilter {
+            author_snowflakes: author_snowflakes
+                .map(|snowflakes| snowflakes.into_iter().map(str::to_owned).collect()),
+            ..HaikuFilter::default()
+        };
+        assert_eq!(filter.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn generate_filter() {
+        let filter = HaikuFilter {
+            after: Some("2020-01-01T00:00:00Z".parse().unwrap()),
+            rules_version: Some(1),
+            channel_snowflakes: Some(vec!["2".to_owned()]),
+            ..HaikuFilter::default()
+        };
+        assert_eq!(
+            filter.to_dql(),
+            Filter::type_of("Haiku")
+                .and(
+                    Function::Ge("timestamp".to_owned(), "2020-01-01T00:00:00+00:00".into()).into()
+                )
+                .and(Function::Eq("rulesVersion".to_owned(), 1.into()).into())
+                .and(Filter::edge(
+                    "channel",
+                    Function::Eq("discordSnowflake".to_owned(), vec!["2".to_owned()].into()),
+                    Filter::type_of("DiscordChannel"),
+                ))
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use super::super::dql::{Block, Field, Filter, Function, Query, Value};
+
+// Limits every haiku and channel the query fetches or counts to those in the given servers, so
+// that nodes in other servers can't be reached by following edges from ones which are allowed
+pub fn restrict(query: Query, servers: &[String]) -> Query {
+    // No server has an empty snowflake, so this matches nothing when there are no servers
+    let servers = if servers.is_empty() {
+        Value::from("")
+    } else {
+        Value::List(servers.iter().cloned().map(Value::from).collect())
+    };
+    Query {
+        blocks: query
+            .blocks
+            .into_iter()
+            .map(|block| restrict_block(block, &servers))
+            .collect(),
+        ..query
+    }
+}
+
+fn restrict_block(block: Block, servers: &Value) -> Block {
+    Block {
+        filter: block.filter.map(|filter| restrict_filter(filter, servers)),
+        fields: restrict_fields(block.fields, servers),
+        ..block
+    }
+}
+
+fn restrict_fields(fields: Vec<Field>, servers: &Value) -> Vec<Field> {
+    fields
+        .into_iter()
+        .map(|field| match field {
+            Field::Edge(mut edge) => {
+                edge.filter = edge.filter.map(|filter| restrict_filter(filter, servers));
+                edge.fields = restrict_fields(edge.fields, servers);
+                Field::Edge(edge)
+            }
+            Field::Count {
+                alias,
+                predicate,
+                filter,
+            } => Field::Count {
+                alias,
+                predicate,
+                filter: filter.map(|filter| restrict_filter(filter, servers)),
+            },
+            field => field,
+        })
+        .collect()
+}
+
+// Haikus and channels are always filtered by their type, which is where their servers are checked
+fn restrict_filter(filter: Filter, servers: &Value) -> Filter {
+    let path: &[&str] = if has_type(&filter, "Haiku") {
+        &["channel", "server"]
+    } else if has_type(&filter, "DiscordChannel") {
+        &["server"]
+    } else {
+        return filter;
+    };
+    filter.and(Filter::path(
+        path,
+        Function::Eq("discordSnowflake".to_owned(), servers.clone()),
+        Filter::type_of("DiscordServer"),
+    ))
+}
+
+fn has_type(filter: &Filter, type_name: &str) -> bool {
+    match filter {
+        Filter::Type(name) => name == type_name,
+        Filter::And(filters) => filters.iter().any(|filter| has_type(filter, type_name)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::dql::{Edge, Predicate};
+    use super::*;
+
+    fn member_of(path: &[&str], servers: Value) -> Filter {
+        Filter::path(
+            path,
+            Function::Eq("discordSnowflake".to_owned(), servers),
+            Filter::type_of("DiscordServer"),
+        )
+    }
+
+    #[test]
+    fn restrict_queries() {
+        let haikus =
+            Filter::type_of("Haiku").and(Function::Ge("rulesVersion".to_owned(), 1.into()).into());
+        let query = Query::new("user").block(
+            Block::new(
+                "user",
+                Function::Eq("discordSnowflake".to_owned(), "3".into()),
+            )
+            .filter(Filter::type_of("DiscordUser"))
+            .fields(vec![
+                Edge::new(Predicate::reverse("author"))
+                    .filter(haikus.clone())
+                    .fields(vec![Edge::new(Predicate::forward("channel"))
+                        .filter(Filter::type_of("DiscordChannel"))
+                        .into()])
+                    .into(),
+                Field::count("haikuCount", Predicate::reverse("author"), haikus.clone()),
+            ]),
+        );
+        let servers = Value::List(vec!["1".into(), "5".into()]);
+        assert_eq!(
+            restrict(query, &["1".to_owned(), "5".to_owned()]),
+            Query::new("user").block(
+                Block::new(
+                    "user",
+                    Function::Eq("discordSnowflake".to_owned(), "3".into())
+                )
+                .filter(Filter::type_of("DiscordUser"))
+                .fields(vec![
+                    Edge::new(Predicate::reverse("author"))
+                        .filter(
+                            haikus
+                                .clone()
+                                .and(member_of(&["channel", "server"], servers.clone()))
+                        )
+                        .fields(vec![Edge::new(Predicate::forward("channel"))
+                            .filter(
+                                Filter::type_of("DiscordChannel")
+                                    .and(member_of(&["server"], servers.clone()))
+                            )
+                            .into()])
+                        .into(),
+                    Field::count(
+                        "haikuCount",
+                        Predicate::reverse("author"),
+                        haikus.and(member_of(&["channel", "server"], servers)),
+                    ),
+                ]),
+            )
+        );
+    }
+
+    #[test]
+    fn restrict_to_no_servers() {
+        let query = Query::new("haiku").block(
+            Block::new("haiku", Function::Uid("0x1".into())).filter(Filter::type_of("Haiku")),
+        );
+        assert_eq!(
+            restrict(query, &[]).blocks[0].filter,
+            Some(Filter::type_of("Haiku").and(member_of(&["channel", "server"], "".into())))
+        );
+    }
+}
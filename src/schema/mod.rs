@@ -1,75 +1,1560 @@
 #[macro_use]
 mod util;
+mod batch;
+mod complexity;
+mod connection;
 mod discord_channel;
 mod discord_server;
 mod discord_user;
 mod haiku;
+mod haiku_check;
+mod haiku_filter;
+mod membership;
+mod search;
 
-use super::error::{internal_error, DgraphQueryError};
+pub use haiku::{NewHaiku, NEW_HAIKU_BLANK_NODE};
+
+use super::auth::{self, Scope};
+use super::config::LimitsConfig;
+use super::dql::{self, Block, Field, Filter, Function};
+use super::error::{
+    data_corrupt, forbidden, mutations_disabled, not_a_member, not_found, query_creation_error,
+    query_too_complex, storage_error, ComplexityError, StorageError,
+};
+use super::events::{HaikuAdded, HaikuEvents};
+use super::metrics::observe_field;
+use super::session::Viewer;
+use super::storage::Storage;
+use batch::{Lookup, LookupBatcher, LookupKey};
+use connection::{
+    haiku_count, haiku_list, node_fields, ConnectionArguments, HaikuConnection, HaikuOrder,
+};
+use discord_channel::DiscordChannel;
+use discord_server::DiscordServer;
+use discord_user::DiscordUser;
+use futures::{future, Stream};
 use haiku::{valid_haiku_id, Haiku};
-use juniper::{EmptyMutation, FieldResult};
-use std::collections::HashMap;
-use util::MapsToDgraphQuery;
-
-fn perform_query(
-    client: &dgraph::Dgraph,
-    query: &str,
-    vars: HashMap<String, String>,
-) -> Result<serde_json::Value, DgraphQueryError> {
-    let response = client.new_readonly_txn().query_with_vars(query, vars)?;
-    let response = String::from_utf8(response.json)?;
-    let response = serde_json::from_str::<serde_json::Value>(&response)?;
-    Ok(response)
-}
+use haiku_check::HaikuCheck;
+use haiku_filter::HaikuFilter;
+use juniper::{DefaultScalarValue, FieldResult, LookAheadSelection};
+use search::{SearchArguments, SearchMode, SearchResults, SearchScope};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use util::{valid_snowflake, MapsToDgraphQuery};
+
+// The version of the GraphQL API, which is bumped whenever the schema changes
+pub const API_VERSION: &str = "1.0";
 
 pub struct Query;
+
+// Made for each request, as the scopes and viewer depend on how it was authenticated
 pub struct Context {
-    pub dgraph_client: dgraph::Dgraph,
+    pub storage: Arc<dyn Storage>,
+    pub mutations_enabled: bool,
+    pub scopes: Vec<Scope>,
+    pub viewer: Option<Viewer>,
+    pub limits: LimitsConfig,
+    // The cost of the top level fields checked so far, which is shared with graphql-ws connections
+    // so they can reset it for each operation
+    pub spent_cost: Arc<AtomicU64>,
+    pub events: Arc<HaikuEvents>,
+    pub lookups: LookupBatcher,
+}
+
+impl Context {
+    fn require(&self, scope: Scope) -> FieldResult<()> {
+        if auth::allows(&self.scopes, scope) {
+            Ok(())
+        } else {
+            Err(forbidden(scope))
+        }
+    }
+
+    // Viewers logged in with Discord can only see servers they're a member of, while API keys
+    // can see every server
+    fn check_server(&self, server_snowflake: Option<&str>) -> FieldResult<()> {
+        match &self.viewer {
+            Some(viewer) if !server_snowflake.is_some_and(|server| viewer.is_member(server)) => {
+                Err(not_a_member())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Limits the haikus and channels a query can reach to those in servers the viewer is a member
+    // of, while API keys can reach every server
+    fn restrict(&self, query: dql::Query) -> dql::Query {
+        match &self.viewer {
+            Some(viewer) => membership::restrict(query, &viewer.guilds),
+            None => query,
+        }
+    }
+
+    // Checks a top level field is within the limits before anything is fetched for it. Costs add
+    // up across the request, so the limit can't be dodged by aliasing a field many times.
+    fn check_complexity(
+        &self,
+        selection: &LookAheadSelection<'_, DefaultScalarValue>,
+        field: &str,
+    ) -> FieldResult<()> {
+        let depth = complexity::depth(selection);
+        if depth > self.limits.max_depth {
+            return Err(query_too_complex(ComplexityError::TooDeep {
+                depth,
+                max_depth: self.limits.max_depth,
+            }));
+        }
+        let cost = complexity::cost(selection, field);
+        let spent = self
+            .spent_cost
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spent| {
+                Some(spent.saturating_add(cost))
+            })
+            .unwrap();
+        let total = spent.saturating_add(cost);
+        if total > self.limits.max_cost {
+            return Err(query_too_complex(ComplexityError::TooCostly {
+                cost: total,
+                max_cost: self.limits.max_cost,
+            }));
+        }
+        Ok(())
+    }
 }
 
 impl juniper::Context for Context {}
 
-#[juniper::object (Context = Context)]
+fn single_result<T: From<serde_json::Value>>(
+    result: Result<serde_json::Value, StorageError>,
+    root: &str,
+) -> FieldResult<Option<T>> {
+    match result.map_err(storage_error)?.get(root) {
+        Some(nodes) => Ok(nodes.get(0).cloned().map(T::from)),
+        None => {
+            error!("Error parsing query result - missing {}", root);
+            Err(data_corrupt())
+        }
+    }
+}
+
+// Lookups made by the top level fields of a request are batched into a single query
+async fn batched_lookup<T: MapsToDgraphQuery + From<serde_json::Value>>(
+    context: &Context,
+    selection: &LookAheadSelection<'_, DefaultScalarValue>,
+    root: &str,
+    type_name: &str,
+    key: LookupKey,
+) -> FieldResult<Option<T>> {
+    let lookup = Lookup {
+        root: root.to_owned(),
+        type_name: type_name.to_owned(),
+        key,
+        fields: T::generate_inner_query(selection).map_err(query_creation_error)?,
+    };
+    let node = context.lookups.load(context, lookup).await?;
+    Ok(node.map(T::from))
+}
+
+async fn snowflake_lookup<T: MapsToDgraphQuery + From<serde_json::Value>>(
+    context: &Context,
+    selection: &LookAheadSelection<'_, DefaultScalarValue>,
+    root: &str,
+    type_name: &str,
+    snowflake: String,
+) -> FieldResult<Option<T>> {
+    let snowflake = valid_snowflake(snowflake, "discordSnowflake")?;
+    batched_lookup(
+        context,
+        selection,
+        root,
+        type_name,
+        LookupKey::Snowflake(snowflake),
+    )
+    .await
+}
+
+async fn haiku_lookup(
+    context: &Context,
+    selection: &LookAheadSelection<'_, DefaultScalarValue>,
+    haiku_id: String,
+) -> FieldResult<Option<Haiku>> {
+    let haiku_id = valid_haiku_id(haiku_id)?;
+    batched_lookup(
+        context,
+        selection,
+        "haiku",
+        "Haiku",
+        LookupKey::Uid(haiku_id),
+    )
+    .await
+}
+
+// Looks up haikus across every server, or only those within the scope, fetching a page of them
+// along with their total
+async fn haikus_lookup(
+    context: &Context,
+    selection: &LookAheadSelection<'_, DefaultScalarValue>,
+    arguments: &ConnectionArguments,
+    scope: Option<Filter>,
+) -> FieldResult<HaikuConnection> {
+    let within = |filter: Filter| match &scope {
+        Some(scope) => filter.and(scope.clone()),
+        None => filter,
+    };
+    let query = dql::Query::new("haikus")
+        .block(
+            Block::new("haikus", Function::Type("Haiku".to_owned()))
+                .filter(within(arguments.page_filter()))
+                .pagination(arguments.pagination())
+                .fields(node_fields(selection, &arguments.order).map_err(query_creation_error)?),
+        )
+        .block(
+            Block::new("total", Function::Type("Haiku".to_owned()))
+                .filter(within(arguments.dql_filter()))
+                .fields(vec![Field::count_uid("count")]),
+        );
+    let result = context
+        .storage
+        .query(&context.restrict(query))
+        .await
+        .map_err(storage_error)?;
+    let total = match result.get("total").and_then(|total| total.get(0)) {
+        Some(total) => haiku_count(total, "count")?,
+        None => 0,
+    };
+    HaikuConnection::new(haiku_list(&result, "haikus")?, total, arguments)
+}
+
+// Searches the content of every haiku within the search's scope
+async fn search_lookup(
+    context: &Context,
+    selection: &LookAheadSelection<'_, DefaultScalarValue>,
+    arguments: &SearchArguments,
+) -> FieldResult<SearchResults> {
+    let query = dql::Query::new("search").block(
+        Block::new("search", arguments.function())
+            .filter(arguments.scope_filter())
+            .pagination(arguments.pagination())
+            .fields(arguments.fields(selection).map_err(query_creation_error)?),
+    );
+    let result = context
+        .storage
+        .query(&context.restrict(query))
+        .await
+        .map_err(storage_error)?;
+    Ok(arguments.results(haiku_list(&result, "search")?))
+}
+
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
 impl Query {
-    fn apiVersion() -> &str {
-        "1.0"
+    fn apiVersion(&self) -> &str {
+        API_VERSION
     }
 
-    fn haiku(
+    async fn haiku(
+        &self,
         context: &Context,
         executor: &Executor,
         haiku_id: String,
     ) -> FieldResult<Option<Haiku>> {
-        let haiku_id = valid_haiku_id(haiku_id)?;
-        let query = Haiku::generate_inner_query(&executor.look_ahead())?;
-        let query = format!(
-            r#"
-query haiku($id: string){{
-    haiku(func: uid($id)) @filter(type(Haiku)) {{
-        {}
-    }}
-}}"#,
-            query
-        );
-        let mut vars = HashMap::new();
-        vars.insert("$id".to_string(), haiku_id);
-        let result = perform_query(&context.dgraph_client, dbg!(&query), vars);
-        match dbg!(result) {
-            Ok(result) => {
-                if let Some(haikus) = result.get("haiku") {
-                    if let Some(json) = haikus.get(0) {
-                        return Ok(Some(Haiku::from(json.clone())));
-                    } else {
-                        return Ok(None);
+        observe_field("haiku", async {
+            context.require(Scope::Read)?;
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "haiku")?;
+            haiku_lookup(context, &selection, haiku_id).await
+        })
+        .await
+    }
+
+    async fn haikus(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+        order_by: Option<HaikuOrder>,
+        filter: Option<HaikuFilter>,
+    ) -> FieldResult<HaikuConnection> {
+        observe_field("haikus", async {
+            context.require(Scope::Read)?;
+            let arguments = ConnectionArguments::new(first, after, last, before, order_by, filter)
+                .map_err(query_creation_error)?;
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "haikus")?;
+            haikus_lookup(context, &selection, &arguments, None).await
+        })
+        .await
+    }
+
+    async fn checkHaiku(
+        &self,
+        context: &Context,
+        text: String,
+        rules_version: Option<i32>,
+    ) -> FieldResult<HaikuCheck> {
+        observe_field("checkHaiku", async {
+            context.require(Scope::Read)?;
+            HaikuCheck::new(&text, rules_version)
+        })
+        .await
+    }
+
+    async fn search(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        terms: String,
+        mode: Option<SearchMode>,
+        scope: Option<SearchScope>,
+        max: Option<i32>,
+    ) -> FieldResult<SearchResults> {
+        observe_field("search", async {
+            context.require(Scope::Read)?;
+            let arguments =
+                SearchArguments::new(terms, mode, scope, max).map_err(query_creation_error)?;
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "search")?;
+            search_lookup(context, &selection, &arguments).await
+        })
+        .await
+    }
+
+    async fn user(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        discord_snowflake: String,
+    ) -> FieldResult<Option<DiscordUser>> {
+        observe_field("user", async {
+            context.require(Scope::Read)?;
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "user")?;
+            snowflake_lookup(
+                context,
+                &selection,
+                "user",
+                "DiscordUser",
+                discord_snowflake,
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn server(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        discord_snowflake: String,
+    ) -> FieldResult<Option<DiscordServer>> {
+        observe_field("server", async {
+            context.require(Scope::Read)?;
+            let discord_snowflake = valid_snowflake(discord_snowflake, "discordSnowflake")?;
+            context.check_server(Some(&discord_snowflake))?;
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "server")?;
+            snowflake_lookup(
+                context,
+                &selection,
+                "server",
+                "DiscordServer",
+                discord_snowflake,
+            )
+            .await
+        })
+        .await
+    }
+
+    async fn channel(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        discord_snowflake: String,
+    ) -> FieldResult<Option<DiscordChannel>> {
+        observe_field("channel", async {
+            context.require(Scope::Read)?;
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "channel")?;
+            snowflake_lookup(
+                context,
+                &selection,
+                "channel",
+                "DiscordChannel",
+                discord_snowflake,
+            )
+            .await
+        })
+        .await
+    }
+
+    // The user logged in with Discord, or null for requests made with an API key
+    async fn viewer(
+        &self,
+        context: &Context,
+        executor: &Executor,
+    ) -> FieldResult<Option<DiscordUser>> {
+        observe_field("viewer", async {
+            context.require(Scope::Read)?;
+            match &context.viewer {
+                Some(viewer) => {
+                    let selection = executor.look_ahead();
+                    context.check_complexity(&selection, "viewer")?;
+                    snowflake_lookup(
+                        context,
+                        &selection,
+                        "viewer",
+                        "DiscordUser",
+                        viewer.discord_snowflake.clone(),
+                    )
+                    .await
+                }
+                None => Ok(None),
+            }
+        })
+        .await
+    }
+}
+
+pub struct Mutation;
+
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
+impl Mutation {
+    async fn addHaiku(
+        &self,
+        context: &Context,
+        executor: &Executor,
+        input: NewHaiku,
+    ) -> FieldResult<Haiku> {
+        observe_field("addHaiku", async {
+            if !context.mutations_enabled {
+                return Err(mutations_disabled());
+            }
+            context.require(Scope::WriteHaikus)?;
+            let input = input.validate()?;
+            // Checked before adding the haiku, rather than refusing to fetch it afterwards
+            let selection = executor.look_ahead();
+            context.check_complexity(&selection, "addHaiku")?;
+            let haiku_id = context
+                .storage
+                .add_haiku(&input)
+                .await
+                .map_err(storage_error)?;
+            context.events.publish(HaikuAdded {
+                haiku_id: haiku_id.clone(),
+                server_snowflake: input.server_snowflake.clone(),
+                channel_snowflake: input.channel_snowflake.clone(),
+            });
+            match haiku_lookup(context, &selection, haiku_id).await? {
+                Some(haiku) => Ok(haiku),
+                None => {
+                    error!("Newly created haiku could not be found");
+                    Err(not_found("Newly created haiku could not be found"))
+                }
+            }
+        })
+        .await
+    }
+}
+
+pub struct Subscription;
+
+type HaikuStream = Pin<Box<dyn Stream<Item = FieldResult<Haiku>> + Send>>;
+
+#[juniper::graphql_subscription(context = Context, scalar = DefaultScalarValue)]
+impl Subscription {
+    // Haikus as they're added to a server, or to one of its channels
+    async fn haikuAdded(
+        context: &Context,
+        executor: &Executor,
+        server_snowflake: String,
+        channel_snowflake: Option<String>,
+    ) -> FieldResult<HaikuStream> {
+        context.require(Scope::Read)?;
+        let server_snowflake = valid_snowflake(server_snowflake, "serverSnowflake")?;
+        let channel_snowflake = channel_snowflake
+            .map(|snowflake| valid_snowflake(snowflake, "channelSnowflake"))
+            .transpose()?;
+        context.check_server(Some(&server_snowflake))?;
+        let selection = executor.look_ahead();
+        context.check_complexity(&selection, "haikuAdded")?;
+        // The selection doesn't outlive the resolver, so the fields to fetch for each haiku are
+        // worked out up front
+        let fields = Haiku::generate_inner_query(&selection).map_err(query_creation_error)?;
+        let storage = context.storage.clone();
+        let haikus: HaikuStream = Box::pin(
+            context
+                .events
+                .subscribe()
+                .filter(move |event| {
+                    future::ready(
+                        event.server_snowflake == server_snowflake
+                            && channel_snowflake
+                                .as_ref()
+                                .is_none_or(|channel| *channel == event.channel_snowflake),
+                    )
+                })
+                .then(move |event| {
+                    let query = dql::Query::new("haiku").block(
+                        Block::new("haiku", Function::Uid(event.haiku_id.into()))
+                            .filter(Filter::type_of("Haiku"))
+                            .fields(fields.clone()),
+                    );
+                    let storage = storage.clone();
+                    async move { single_result::<Haiku>(storage.query(&query).await, "haiku") }
+                })
+                // Haikus which can't be found are skipped
+                .filter_map(|haiku| future::ready(haiku.transpose())),
+        );
+        Ok(haikus)
+    }
+}
+
+pub type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, Mutation, Subscription)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::dql::{Edge, Field, Pagination, Predicate};
+    use super::super::error::invalid_argument;
+    use super::super::storage::MemoryStorage;
+    use super::*;
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+    use futures::{FutureExt, StreamExt};
+    use juniper::Variables;
+    use rstest::rstest;
+    use std::future::Future;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::task;
+
+    // Records the queries it is asked to run, returning no results for any of them
+    #[derive(Default)]
+    struct RecordingStorage {
+        queries: Arc<Mutex<Vec<dql::Query>>>,
+    }
+
+    #[async_trait]
+    impl Storage for RecordingStorage {
+        async fn query(&self, query: &dql::Query) -> Result<serde_json::Value, StorageError> {
+            self.queries.lock().unwrap().push(query.clone());
+            let mut result = serde_json::Map::new();
+            for block in &query.blocks {
+                result.insert(block.name.clone(), json!([]));
+            }
+            Ok(serde_json::Value::Object(result))
+        }
+
+        async fn add_haiku(&self, _haiku: &NewHaiku) -> Result<String, StorageError> {
+            Err(StorageError::MalformedResponse("read only".to_owned()))
+        }
+    }
+
+    fn context(storage: impl Storage + 'static, scopes: &[Scope]) -> Context {
+        Context {
+            storage: Arc::new(storage),
+            mutations_enabled: true,
+            scopes: scopes.to_vec(),
+            viewer: None,
+            limits: LimitsConfig::default(),
+            spent_cost: Arc::default(),
+            events: Arc::new(HaikuEvents::default()),
+            lookups: LookupBatcher::default(),
+        }
+    }
+
+    fn execute(query: &str, storage: impl Storage + 'static) -> juniper::Value {
+        let context = context(storage, &[Scope::Admin]);
+        let (result, errs) = block_on(juniper::execute(
+            query,
+            None,
+            &schema(),
+            &Variables::new(),
+            &context,
+        ))
+        .unwrap();
+        assert_eq!(errs, vec![]);
+        result
+    }
+
+    fn sample_storage() -> MemoryStorage {
+        MemoryStorage::from_rdf(include_str!("sample_data.dgraph")).unwrap()
+    }
+
+    fn generated_queries(query: &str) -> Vec<dql::Query> {
+        let storage = RecordingStorage::default();
+        let queries = storage.queries.clone();
+        execute(query, storage);
+        let queries = queries.lock().unwrap();
+        queries.clone()
+    }
+
+    #[test]
+    fn generate_haiku_query() {
+        let queries = generated_queries(
+            r#"query { haiku(haikuId: "0x5") { id server { discordSnowflake } } }"#,
+        );
+        assert_eq!(
+            queries,
+            vec![dql::Query::new("haiku").block(
+                Block::new("haiku", Function::Uid("0x5".into()))
+                    .filter(Filter::type_of("Haiku"))
+                    .fields(vec![
+                        Field::uid("id"),
+                        Edge::new(Predicate::forward("channel"))
+                            .alias("serverChannel")
+                            .filter(Filter::type_of("DiscordChannel"))
+                            .fields(vec![Edge::new(Predicate::forward("server"))
+                                .filter(Filter::type_of("DiscordServer"))
+                                .fields(vec![Field::value("discordSnowflake")])
+                                .into()])
+                            .into(),
+                    ])
+            )]
+        );
+    }
+
+    #[test]
+    fn generate_user_query() {
+        let queries = generated_queries(
+            r#"query {
+                user(discordSnowflake: "3") {
+                    discordSnowflake
+                    haikusSearch(searchTerm: "some words", max: 2) { content }
+                }
+            }"#,
+        );
+        assert_eq!(
+            queries,
+            vec![dql::Query::new("user").block(
+                Block::new(
+                    "user",
+                    Function::Eq("discordSnowflake".to_owned(), "3".into())
+                )
+                .filter(Filter::type_of("DiscordUser"))
+                .fields(vec![
+                    Field::value("discordSnowflake"),
+                    Edge::new(Predicate::reverse("author"))
+                        .alias(&format!(
+                            "haikusSearch_{:#x}",
+                            hash!(
+                                "some words",
+                                2,
+                                HaikuOrder::default(),
+                                HaikuFilter::default()
+                            )
+                        ))
+                        .filter(Filter::type_of("Haiku").and(
+                            Function::AnyOfTerms("content".to_owned(), "some words".into()).into()
+                        ))
+                        .pagination(Pagination {
+                            first: Some(2),
+                            ..Pagination::default()
+                        })
+                        .fields(vec![Field::value("content")])
+                        .into(),
+                ])
+            )]
+        );
+    }
+
+    #[test]
+    fn batch_root_lookups() {
+        let query = r#"
+        query {
+            first: user(discordSnowflake: "3") { discordSnowflake }
+            second: user(discordSnowflake: "4") { discordSnowflake }
+            again: user(discordSnowflake: "3") { discordSnowflake }
+            padded: user(discordSnowflake: "0004") { discordSnowflake }
+            haiku(haikuId: "0x05") { content }
+        }"#;
+        assert_eq!(
+            generated_queries(query),
+            vec![dql::Query::new("batch")
+                .block(
+                    Block::new(
+                        "user",
+                        Function::Eq(
+                            "discordSnowflake".to_owned(),
+                            vec!["3".to_owned(), "4".to_owned()].into()
+                        )
+                    )
+                    .filter(Filter::type_of("DiscordUser"))
+                    .fields(vec![Field::value("discordSnowflake")])
+                )
+                .block(
+                    Block::new("haiku", Function::Uid("0x5".into()))
+                        .filter(Filter::type_of("Haiku"))
+                        .fields(vec![Field::uid("id"), Field::value("content")])
+                )]
+        );
+        assert_eq!(
+            execute(query, sample_storage()),
+            graphql_value!({
+                "first": { "discordSnowflake": "3" },
+                "second": { "discordSnowflake": "4" },
+                "again": { "discordSnowflake": "3" },
+                "padded": { "discordSnowflake": "4" },
+                "haiku": { "content": "line 1\nline2\nline3" },
+            })
+        );
+    }
+
+    // Never finishes its first query, then answers any others from the sample data
+    #[derive(Default)]
+    struct StallingStorage {
+        stalled: AtomicBool,
+        inner: MemoryStorage,
+    }
+
+    #[async_trait]
+    impl Storage for StallingStorage {
+        async fn query(&self, query: &dql::Query) -> Result<serde_json::Value, StorageError> {
+            if !self.stalled.swap(true, Ordering::Relaxed) {
+                future::pending::<()>().await;
+            }
+            self.inner.query(query).await
+        }
+
+        async fn add_haiku(&self, haiku: &NewHaiku) -> Result<String, StorageError> {
+            self.inner.add_haiku(haiku).await
+        }
+    }
+
+    #[test]
+    fn retry_lookups_when_the_batch_is_dropped() {
+        let storage = StallingStorage {
+            inner: sample_storage(),
+            ..StallingStorage::default()
+        };
+        let context = context(storage, &[Scope::Read]);
+        let lookup = |snowflake: &str| Lookup {
+            root: "user".to_owned(),
+            type_name: "DiscordUser".to_owned(),
+            key: LookupKey::Snowflake(snowflake.to_owned()),
+            fields: vec![Field::value("discordSnowflake")],
+        };
+        let mut first = Box::pin(context.lookups.load(&context, lookup("3")));
+        let mut second = Box::pin(context.lookups.load(&context, lookup("4")));
+        let mut cx = task::Context::from_waker(futures::task::noop_waker_ref());
+        // Both lookups are queued, then the first field takes them and stalls on the query
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        drop(first);
+        assert_eq!(
+            block_on(second).unwrap(),
+            Some(json!({ "discordSnowflake": "4" }))
+        );
+    }
+
+    #[test]
+    fn resolve_haiku() {
+        let query = r#"
+        query {
+            haiku(haikuId: "0x5") {
+                id
+                authors {
+                    discordSnowflake
+                }
+                content
+                channel {
+                    discordSnowflake
+                }
+                server {
+                    discordSnowflake
+                }
+                rulesVersion
+                timestamp
+            }
+            missing: haiku(haikuId: "0x1") {
+                id
+            }
+        }"#;
+        assert_eq!(
+            execute(query, sample_storage()),
+            graphql_value!({
+                "haiku": {
+                    "id": "0x5",
+                    "authors": [
+                        { "discordSnowflake": "3" },
+                        { "discordSnowflake": "4" },
+                    ],
+                    "content": "line 1\nline2\nline3",
+                    "channel": { "discordSnowflake": "2" },
+                    "server": { "discordSnowflake": "1" },
+                    "rulesVersion": 0,
+                    "timestamp": "1977-02-03T05:00:00+00:00",
+                },
+                "missing": None,
+            })
+        );
+    }
+
+    #[test]
+    fn resolve_snowflake_lookups() {
+        let query = r#"
+        query {
+            user(discordSnowflake: "3") {
+                haikus {
+                    edges { node { id } }
+                }
+                haikusSearch(searchTerm: "LINE", max: 1) {
+                    id
+                }
+            }
+            server(discordSnowflake: "1") {
+                channels {
+                    discordSnowflake
+                }
+                haikus {
+                    edges { node { id } }
+                }
+            }
+            channel(discordSnowflake: "2") {
+                server {
+                    discordSnowflake
+                }
+                haikus {
+                    totalCount
+                }
+            }
+            missing: channel(discordSnowflake: "1") {
+                discordSnowflake
+            }
+        }"#;
+        assert_eq!(
+            execute(query, sample_storage()),
+            graphql_value!({
+                "user": {
+                    "haikus": { "edges": [{ "node": { "id": "0x5" } }] },
+                    "haikusSearch": [{ "id": "0x5" }],
+                },
+                "server": {
+                    "channels": [{ "discordSnowflake": "2" }],
+                    "haikus": { "edges": [{ "node": { "id": "0x5" } }] },
+                },
+                "channel": {
+                    "server": { "discordSnowflake": "1" },
+                    "haikus": { "totalCount": 1 },
+                },
+                "missing": None,
+            })
+        );
+    }
+
+    #[test]
+    fn add_haiku() {
+        let query = r#"
+        mutation {
+            addHaiku(input: {
+                content: "new line 1\nnew line 2\nnew line 3",
+                authorSnowflakes: ["3", "5"],
+                channelSnowflake: "6",
+                serverSnowflake: "1",
+                rulesVersion: 1,
+                timestamp: "2020-01-01T00:00:00Z"
+            }) {
+                id
+                authors {
+                    discordSnowflake
+                    haikus {
+                        edges { node { id } }
+                    }
+                }
+                server {
+                    channels {
+                        discordSnowflake
                     }
-                } else {
-                    error!("Error parsing Dgraph Query result - malformed response");
                 }
+                rulesVersion
             }
-            Err(err) => error!("Dgraph error - {:?}", err),
+        }"#;
+        assert_eq!(
+            execute(query, sample_storage()),
+            graphql_value!({
+                "addHaiku": {
+                    "id": "0x8",
+                    "authors": [
+                        {
+                            "discordSnowflake": "3",
+                            "haikus": {
+                                "edges": [
+                                    { "node": { "id": "0x5" } },
+                                    { "node": { "id": "0x8" } },
+                                ],
+                            },
+                        },
+                        {
+                            "discordSnowflake": "5",
+                            "haikus": { "edges": [{ "node": { "id": "0x8" } }] },
+                        },
+                    ],
+                    "server": {
+                        "channels": [
+                            { "discordSnowflake": "2" },
+                            { "discordSnowflake": "6" },
+                        ],
+                    },
+                    "rulesVersion": 1,
+                }
+            })
+        );
+    }
+
+    // Starts a haikuAdded subscription, returning the stream of haikus
+    async fn subscribe<'a>(
+        query: &'a str,
+        schema: &'a Schema,
+        context: &'a Context,
+    ) -> juniper::ValuesStream<'a> {
+        let (value, errs) =
+            juniper::resolve_into_stream(query, None, schema, &Variables::new(), context)
+                .await
+                .unwrap();
+        assert_eq!(errs.len(), 0);
+        match value {
+            juniper::Value::Object(object) => match object.into_iter().next() {
+                Some((_, juniper::Value::Scalar(haikus))) => haikus,
+                _ => panic!("haikuAdded didn't resolve to a stream"),
+            },
+            _ => panic!("subscription didn't resolve to an object"),
+        }
+    }
+
+    #[test]
+    fn subscribe_to_added_haikus() {
+        let schema = schema();
+        let context = context(sample_storage(), &[Scope::Admin]);
+        block_on(async {
+            let mut server_haikus = subscribe(
+                r#"subscription {
+                    haikuAdded(serverSnowflake: "1") { content channel { discordSnowflake } }
+                }"#,
+                &schema,
+                &context,
+            )
+            .await;
+            let mut other_channel_haikus = subscribe(
+                r#"subscription {
+                    haikuAdded(serverSnowflake: "1", channelSnowflake: "6") { content }
+                }"#,
+                &schema,
+                &context,
+            )
+            .await;
+            let (_, errs) = juniper::execute(ADD_HAIKU, None, &schema, &Variables::new(), &context)
+                .await
+                .unwrap();
+            assert_eq!(errs, vec![]);
+            assert_eq!(
+                server_haikus.next().await,
+                Some(Ok(graphql_value!({
+                    "content": "an old silent pond",
+                    "channel": { "discordSnowflake": "2" },
+                })))
+            );
+            assert_eq!(other_channel_haikus.next().now_or_never(), None);
+        });
+    }
+
+    // Adds haikus to the sample data, alternating between two channels of the same server, with
+    // each one timestamped earlier than the last
+    fn paginated_storage() -> MemoryStorage {
+        let storage = sample_storage();
+        for index in 0..5 {
+            block_on(storage.add_haiku(&NewHaiku {
+                content: format!("haiku {}", index),
+                author_snowflakes: vec!["3".to_owned()],
+                channel_snowflake: if index % 2 == 0 { "2" } else { "6" }.to_owned(),
+                server_snowflake: "1".to_owned(),
+                rules_version: index % 2,
+                timestamp: format!("2020-01-0{}T00:00:00Z", 5 - index).parse().unwrap(),
+            }))
+            .unwrap();
+        }
+        storage
+    }
+
+    // Runs the query against the context, returning its result
+    fn execute_with(query: &str, context: &Context) -> juniper::Value {
+        let (result, errs) = block_on(juniper::execute(
+            query,
+            None,
+            &schema(),
+            &Variables::new(),
+            context,
+        ))
+        .unwrap();
+        assert_eq!(errs, vec![]);
+        result
+    }
+
+    // The cursor of a haiku in the list of every haiku in the given order
+    fn haiku_cursor(context: &Context, order: &str, id: &str) -> String {
+        let result = execute_with(
+            &format!(
+                "query {{ haikus(first: 100, {}) {{ edges {{ cursor node {{ id }} }} }} }}",
+                order
+            ),
+            context,
+        );
+        let edges = result
+            .as_object_value()
+            .and_then(|result| result.get_field_value("haikus"))
+            .and_then(juniper::Value::as_object_value)
+            .and_then(|haikus| haikus.get_field_value("edges"))
+            .and_then(juniper::Value::as_list_value)
+            .unwrap();
+        let edge = edges
+            .iter()
+            .filter_map(juniper::Value::as_object_value)
+            .find(|edge| {
+                edge.get_field_value("node")
+                    .and_then(juniper::Value::as_object_value)
+                    .and_then(|node| node.get_field_value("id"))
+                    .and_then(juniper::Value::as_string_value)
+                    == Some(id)
+            })
+            .unwrap();
+        edge.get_field_value("cursor")
+            .and_then(juniper::Value::as_string_value)
+            .unwrap()
+            .to_owned()
+    }
+
+    // Cursors are given as the id of the haiku they're the cursor of, in place of {}
+    #[rstest(arguments, cursor_id, expected_ids, has_previous_page, has_next_page,
+        case("first: 2", None, vec!["0x5", "0x6"], false, true),
+        case("first: 2, after: {}", Some("0x6"), vec!["0x8", "0x9"], true, true),
+        case("first: 10, after: {}", Some("0x6"), vec!["0x8", "0x9", "0xa", "0xb"], true, false),
+        case("last: 2", None, vec!["0xa", "0xb"], true, false),
+        case("last: 2, before: {}", Some("0x9"), vec!["0x6", "0x8"], true, true),
+        case("first: 2, orderBy: { field: TIMESTAMP, direction: DESC }", None, vec!["0x6", "0x8"], false, true),
+        case("first: 2, orderBy: { field: TIMESTAMP, direction: ASC }", None, vec!["0x5", "0xb"], false, true),
+        case("last: 2, before: {}, orderBy: { field: TIMESTAMP, direction: DESC }", Some("0xa"), vec!["0x8", "0x9"], true, true),
+        case("first: 3, orderBy: { field: RULES_VERSION, direction: DESC }", None, vec!["0x8", "0xa", "0x5"], false, true),
+        case("first: 2, after: {}, orderBy: { field: RULES_VERSION, direction: DESC }", Some("0xa"), vec!["0x5", "0x6"], true, true),
+        case("first: 2, orderBy: { field: ID, direction: DESC }", None, vec!["0xb", "0xa"], false, true),
+        case("first: 2, after: {}, orderBy: { field: ID, direction: DESC }", Some("0xa"), vec!["0x9", "0x8"], true, true),
+        case("last: 2, orderBy: { field: ID, direction: DESC }", None, vec!["0x6", "0x5"], true, false),
+    )]
+    fn paginate_haikus(
+        arguments: &str,
+        cursor_id: Option<&str>,
+        expected_ids: Vec<&str>,
+        has_previous_page: bool,
+        has_next_page: bool,
+    ) {
+        let arguments = match cursor_id {
+            Some(id) => {
+                let context = context(paginated_storage(), &[Scope::Admin]);
+                let order = arguments
+                    .find("orderBy")
+                    .map_or("", |start| &arguments[start..]);
+                let cursor = haiku_cursor(&context, order, id);
+                arguments.replace("{}", &format!("{:?}", cursor))
+            }
+            None => arguments.to_owned(),
         };
-        Err(internal_error())
+        assert_haiku_connections(
+            &arguments,
+            expected_ids,
+            6,
+            has_previous_page,
+            has_next_page,
+        );
+    }
+
+    #[rstest(order, cursor_id, expected_ids,
+        case("orderBy: { field: ID, direction: DESC }", "0xa", vec!["0x9", "0x8"]),
+        case("orderBy: { field: TIMESTAMP, direction: DESC }", "0x8", vec!["0x9", "0xa"]),
+        case("orderBy: { field: RULES_VERSION, direction: DESC }", "0xa", vec!["0x5", "0x6"]),
+    )]
+    fn keep_pages_when_haikus_are_added(order: &str, cursor_id: &str, expected_ids: Vec<&str>) {
+        let context = context(paginated_storage(), &[Scope::Admin]);
+        let cursor = haiku_cursor(&context, order, cursor_id);
+        // Newer than any other haiku and with a later rules version, so ahead of the cursor in
+        // each order
+        block_on(context.storage.add_haiku(&NewHaiku {
+            content: "haiku 5".to_owned(),
+            author_snowflakes: vec!["3".to_owned()],
+            channel_snowflake: "2".to_owned(),
+            server_snowflake: "1".to_owned(),
+            rules_version: 2,
+            timestamp: "2020-01-06T00:00:00Z".parse().unwrap(),
+        }))
+        .unwrap();
+        let result = execute_with(
+            &format!(
+                "query {{ haikus(first: 2, after: {:?}, {}) {{ edges {{ node {{ id }} }} }} }}",
+                cursor, order
+            ),
+            &context,
+        );
+        let edges = expected_ids
+            .into_iter()
+            .map(|id| {
+                let id = id.to_owned();
+                graphql_value!({ "node": { "id": id } })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            result,
+            graphql_value!({ "haikus": { "edges": (juniper::Value::list(edges)) } })
+        );
+    }
+
+    #[rstest(filter, expected_ids, total,
+        case(r#"after: "2020-01-03T00:00:00Z""#, vec!["0x6", "0x8"], 3),
+        case(r#"after: "2020-01-02T00:00:00Z", before: "2020-01-04T00:00:00+00:00""#, vec!["0x8", "0x9"], 3),
+        case("rulesVersion: 1", vec!["0x8", "0xa"], 2),
+        case(r#"channelSnowflakes: ["6"]"#, vec!["0x8", "0xa"], 2),
+        case(r#"authorSnowflakes: "4""#, vec!["0x5"], 1),
+        case(r#"authorSnowflakes: ["4", "3"], rulesVersion: 0"#, vec!["0x5", "0x6"], 4),
+    )]
+    fn filter_haikus(filter: &str, expected_ids: Vec<&str>, total: i32) {
+        assert_haiku_connections(
+            &format!("first: 2, filter: {{ {} }}", filter),
+            expected_ids,
+            total,
+            false,
+            total > 2,
+        );
+    }
+
+    // Every haiku in paginated_storage is by user 3 and in server 1, so the root list and theirs
+    // should match
+    fn assert_haiku_connections(
+        arguments: &str,
+        expected_ids: Vec<&str>,
+        total: i32,
+        has_previous_page: bool,
+        has_next_page: bool,
+    ) {
+        let connection = r#"{
+            totalCount
+            edges { node { id } }
+            pageInfo { hasPreviousPage hasNextPage }
+        }"#;
+        let query = format!(
+            r#"query {{
+                haikus({args}) {connection}
+                user(discordSnowflake: "3") {{ haikus({args}) {connection} }}
+                server(discordSnowflake: "1") {{ haikus({args}) {connection} }}
+            }}"#,
+            args = arguments,
+            connection = connection
+        );
+        let edges = expected_ids
+            .iter()
+            .map(|id| {
+                let id = id.to_string();
+                graphql_value!({ "node": { "id": id } })
+            })
+            .collect::<Vec<_>>();
+        let expected = graphql_value!({
+            "totalCount": total,
+            "edges": (juniper::Value::list(edges)),
+            "pageInfo": {
+                "hasPreviousPage": has_previous_page,
+                "hasNextPage": has_next_page,
+            },
+        });
+        let result = execute(&query, paginated_storage());
+        let result = result.as_object_value().unwrap();
+        assert_eq!(result.get_field_value("haikus"), Some(&expected), "root");
+        for root in ["user", "server"] {
+            let haikus = result
+                .get_field_value(root)
+                .and_then(juniper::Value::as_object_value)
+                .and_then(|root| root.get_field_value("haikus"));
+            assert_eq!(haikus, Some(&expected), "{}", root);
+        }
+    }
+
+    // Adds haikus to the sample data to search through, with each one timestamped earlier than the
+    // last and all but the last in server 1
+    fn search_storage() -> MemoryStorage {
+        let storage = sample_storage();
+        let haikus = [
+            ("an old silent pond", "2", "1"),
+            ("a frog jumps into the pond", "6", "1"),
+            ("silent frog in the old pond", "2", "1"),
+            ("pond frog", "8", "7"),
+        ];
+        for (index, (content, channel, server)) in haikus.iter().enumerate() {
+            block_on(storage.add_haiku(&NewHaiku {
+                content: (*content).to_owned(),
+                author_snowflakes: vec!["3".to_owned()],
+                channel_snowflake: (*channel).to_owned(),
+                server_snowflake: (*server).to_owned(),
+                rules_version: 1,
+                timestamp: format!("2020-01-0{}T00:00:00Z", 5 - index).parse().unwrap(),
+            }))
+            .unwrap();
+        }
+        storage
+    }
+
+    fn haiku_ids(ids: Vec<&str>) -> juniper::Value {
+        juniper::Value::list(
+            ids.into_iter()
+                .map(|id| {
+                    let id = id.to_owned();
+                    graphql_value!({ "id": id })
+                })
+                .collect(),
+        )
+    }
+
+    #[rstest(arguments, expected_ids,
+        case(r#"terms: "pond""#, vec!["0x6", "0x8", "0x9", "0xc"]),
+        case(r#"terms: "pond", max: 2"#, vec!["0x6", "0x8"]),
+        case(r#"terms: "old frog", mode: ANY"#, vec!["0x6", "0x8", "0x9", "0xc"]),
+        case(r#"terms: "old frog", mode: ALL"#, vec!["0x9"]),
+        case(r#"terms: "old pond", mode: ALL"#, vec!["0x6", "0x9"]),
+        case(r#"terms: "old pond", mode: PHRASE"#, vec!["0x9"]),
+        case(r#"terms: "frog", mode: PHRASE, max: 1"#, vec!["0x8"]),
+        case(r#"terms: "frog", scope: { serverSnowflake: "7" }"#, vec!["0xc"]),
+        case(r#"terms: "pond", scope: { serverSnowflake: "1" }"#, vec!["0x6", "0x8", "0x9"]),
+        case(r#"terms: "pond", scope: { channelSnowflake: "6" }"#, vec!["0x8"]),
+        case(r#"terms: "pond", scope: { serverSnowflake: "7", channelSnowflake: "6" }"#, vec![]),
+    )]
+    fn search_haikus(arguments: &str, expected_ids: Vec<&str>) {
+        let query = format!("query {{ search({}) {{ results {{ id }} }} }}", arguments);
+        assert_eq!(
+            execute(&query, search_storage()),
+            graphql_value!({ "search": { "results": (haiku_ids(expected_ids)) } })
+        );
+    }
+
+    #[test]
+    fn search_fields() {
+        let query = r#"
+        query {
+            search(terms: "pond", max: 2) { results { id } }
+            frogs: search(terms: "pond frog", mode: ALL) { results { id } }
+            server(discordSnowflake: "1") {
+                search(terms: "frog") { results { id } }
+            }
+            channel(discordSnowflake: "2") {
+                search(terms: "old pond", mode: PHRASE) { results { id } incomplete }
+            }
+        }"#;
+        assert_eq!(
+            execute(query, search_storage()),
+            graphql_value!({
+                "search": { "results": (haiku_ids(vec!["0x6", "0x8"])) },
+                "frogs": { "results": (haiku_ids(vec!["0x8", "0x9", "0xc"])) },
+                "server": {
+                    "search": { "results": (haiku_ids(vec!["0x8", "0x9"])) },
+                },
+                "channel": {
+                    "search": { "results": (haiku_ids(vec!["0x9"])), "incomplete": false },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn check_haiku() {
+        let query = r#"
+        query {
+            haiku: checkHaiku(text: "An old silent pond... A frog jumps into the pond, splash! Silence again.") {
+                isHaiku
+                rulesVersion
+                lines
+                rejection
+            }
+            notHaiku: checkHaiku(text: "An mp3 pond", rulesVersion: 0) {
+                isHaiku
+                rulesVersion
+                lines
+                words { word syllables }
+                rejection
+            }
+        }"#;
+        assert_eq!(
+            execute(query, sample_storage()),
+            graphql_value!({
+                "haiku": {
+                    "isHaiku": true,
+                    "rulesVersion": 1,
+                    "lines": [
+                        "An old silent pond...",
+                        "A frog jumps into the pond,",
+                        "splash! Silence again.",
+                    ],
+                    "rejection": None,
+                },
+                "notHaiku": {
+                    "isHaiku": false,
+                    "rulesVersion": 0,
+                    "lines": None,
+                    "words": [
+                        { "word": "An", "syllables": 1 },
+                        { "word": "mp3", "syllables": None },
+                        { "word": "pond", "syllables": 1 },
+                    ],
+                    "rejection": "UNCOUNTABLE_WORD",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn check_haiku_with_unknown_rules() {
+        assert_single_error(
+            r#"query { checkHaiku(text: "pond", rulesVersion: 99) { isHaiku } }"#,
+            context(sample_storage(), &[Scope::Read]),
+            invalid_argument("rulesVersion", "Invalid rulesVersion: unknown version 99"),
+        );
+    }
+
+    #[test]
+    fn nested_argument_error_path() {
+        let (_, errs) = block_on(juniper::execute(
+            r#"query { user(discordSnowflake: "3") { haikus(first: -1) { totalCount } } }"#,
+            None,
+            &schema(),
+            &Variables::new(),
+            &context(sample_storage(), &[Scope::Read]),
+        ))
+        .unwrap();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(
+            errs[0].error(),
+            &juniper::FieldError::new(
+                "Invalid argument: first - must be between 0 and 100",
+                graphql_value!({
+                    "code": "INVALID_ARGUMENT",
+                    "path": ["user", "haikus"],
+                    "argument": "first",
+                }),
+            )
+        );
+    }
+
+    #[rstest(max, case(-1), case(101), case(2147483647))]
+    fn reject_out_of_range_search_max(max: i32) {
+        let query = format!(
+            r#"query {{ user(discordSnowflake: "3") {{ haikusSearch(searchTerm: "pond", max: {}) {{ id }} }} }}"#,
+            max
+        );
+        let (_, errs) = block_on(juniper::execute(
+            &query,
+            None,
+            &schema(),
+            &Variables::new(),
+            &context(sample_storage(), &[Scope::Read]),
+        ))
+        .unwrap();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(
+            errs[0].error(),
+            &juniper::FieldError::new(
+                "Invalid argument: max - must be between 0 and 100",
+                graphql_value!({
+                    "code": "INVALID_ARGUMENT",
+                    "path": ["user", "haikusSearch"],
+                    "argument": "max",
+                }),
+            )
+        );
     }
-}
 
-pub type Schema = juniper::RootNode<'static, Query, EmptyMutation<Context>>;
+    const ADD_HAIKU: &str = r#"
+        mutation {
+            addHaiku(input: {
+                authorSnowflakes: ["3"],
+                channelSnowflake: "2",
+                serverSnowflake: "1",
+                content: "an old silent pond",
+                rulesVersion: 1,
+                timestamp: "2020-01-01T00:00:00Z",
+            }) {
+                id
+            }
+        }"#;
+
+    #[test]
+    fn add_haiku_with_mutations_disabled() {
+        let context = Context {
+            mutations_enabled: false,
+            ..context(sample_storage(), &[Scope::Admin])
+        };
+        assert_single_error(ADD_HAIKU, context, mutations_disabled());
+    }
+
+    #[rstest(
+        query,
+        scopes,
+        missing,
+        case(r#"query { haikus { totalCount } }"#, &[], Scope::Read),
+        case(r#"query { checkHaiku(text: "pond") { isHaiku } }"#, &[], Scope::Read),
+        case(ADD_HAIKU, &[Scope::Read], Scope::WriteHaikus)
+    )]
+    fn reject_missing_scopes(query: &str, scopes: &[Scope], missing: Scope) {
+        assert_single_error(query, context(sample_storage(), scopes), forbidden(missing));
+    }
+
+    const NESTED_HAIKUS: &str = r#"
+        query {
+            haikus {
+                edges { node { channel { haikus { edges { node { id } } } } } }
+            }
+        }"#;
+
+    // Each result is picked out of ten haikus checked for the phrase
+    const PHRASE_SEARCH: &str = r#"
+        query {
+            search(terms: "old pond", mode: PHRASE, max: 10) { results { id } }
+        }"#;
+
+    const SERVER_CHANNELS: &str = r#"
+        query {
+            haikus(first: 1) { edges { node { server { channels { discordSnowflake } } } } }
+        }"#;
+
+    const ALIASED_HAIKUS: &str = r#"
+        query {
+            a: haikus(first: 100) { edges { node { id content } } }
+            b: haikus(first: 100) { edges { node { id content } } }
+        }"#;
+
+    // The aliased fields are only over the limit together, so the first is still fetched
+    #[rstest(
+        query,
+        limits,
+        expected,
+        fetched,
+        case(NESTED_HAIKUS, LimitsConfig { max_depth: 6, ..LimitsConfig::default() },
+            ComplexityError::TooDeep { depth: 8, max_depth: 6 }, 0),
+        case(NESTED_HAIKUS, LimitsConfig::default(),
+            ComplexityError::TooCostly { cost: 30401, max_cost: 10_000 }, 0),
+        case(ALIASED_HAIKUS, LimitsConfig { max_cost: 500, ..LimitsConfig::default() },
+            ComplexityError::TooCostly { cost: 802, max_cost: 500 }, 1),
+        case(PHRASE_SEARCH, LimitsConfig { max_cost: 200, ..LimitsConfig::default() },
+            ComplexityError::TooCostly { cost: 201, max_cost: 200 }, 0),
+        case(SERVER_CHANNELS, LimitsConfig { max_cost: 500, ..LimitsConfig::default() },
+            ComplexityError::TooCostly { cost: 505, max_cost: 500 }, 0)
+    )]
+    fn reject_complex_queries(
+        query: &str,
+        limits: LimitsConfig,
+        expected: ComplexityError,
+        fetched: usize,
+    ) {
+        let storage = RecordingStorage::default();
+        let queries = storage.queries.clone();
+        let context = Context {
+            limits,
+            ..context(storage, &[Scope::Read])
+        };
+        assert_single_error(query, context, query_too_complex(expected));
+        assert_eq!(queries.lock().unwrap().len(), fetched);
+    }
+
+    // Nested fields are costed by the name they're given, which for an alias isn't a field, so the
+    // query is rejected before anything is fetched
+    #[test]
+    fn reject_nested_aliases() {
+        let storage = RecordingStorage::default();
+        let queries = storage.queries.clone();
+        let (_, errs) = block_on(juniper::execute(
+            r#"query {
+                haikus(first: 10) {
+                    edges { node { channel { more: haikus { edges { node { id } } } } } }
+                }
+            }"#,
+            None,
+            &schema(),
+            &Variables::new(),
+            &context(storage, &[Scope::Read]),
+        ))
+        .unwrap();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(
+            errs[0].error().message(),
+            "Unsupported alias: more - only top level fields can be aliased"
+        );
+        assert_eq!(queries.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn allow_paginated_nested_queries() {
+        let queries = generated_queries(
+            r#"query {
+                haikus(first: 10) {
+                    edges { node { channel { haikus(first: 10) { edges { node { id } } } } } }
+                }
+            }"#,
+        );
+        assert_eq!(queries.len(), 1);
+    }
+
+    // Haikus in server 7 can't be reached from any root by a viewer who's only a member of server 1
+    #[rstest(query, expected,
+        case(r#"{ haiku(haikuId: "0xc") { id } }"#, graphql_value!({ "haiku": None })),
+        case(
+            r#"{ haikus(first: 2, filter: { after: "2020-01-01T00:00:00Z" }) { totalCount edges { node { id server { discordSnowflake } } } } }"#,
+            graphql_value!({ "haikus": {
+                "totalCount": 3,
+                "edges": [
+                    { "node": { "id": "0x6", "server": { "discordSnowflake": "1" } } },
+                    { "node": { "id": "0x8", "server": { "discordSnowflake": "1" } } },
+                ],
+            } })
+        ),
+        case(
+            r#"{ search(terms: "frog", scope: { serverSnowflake: "7" }) { results { id } } }"#,
+            graphql_value!({ "search": { "results": [] } })
+        ),
+        case(
+            r#"{ channel(discordSnowflake: "8") { haikus(first: 10) { totalCount } } }"#,
+            graphql_value!({ "channel": None })
+        ),
+        case(
+            r#"{ user(discordSnowflake: "3") { haikus(first: 10, filter: { after: "2020-01-01T00:00:00Z" }) { totalCount edges { node { id channel { discordSnowflake } } } } } }"#,
+            graphql_value!({ "user": { "haikus": {
+                "totalCount": 3,
+                "edges": [
+                    { "node": { "id": "0x6", "channel": { "discordSnowflake": "2" } } },
+                    { "node": { "id": "0x8", "channel": { "discordSnowflake": "6" } } },
+                    { "node": { "id": "0x9", "channel": { "discordSnowflake": "2" } } },
+                ],
+            } } })
+        ),
+    )]
+    fn hide_servers_from_non_members(query: &str, expected: juniper::Value) {
+        let context = Context {
+            viewer: Some(Viewer {
+                discord_snowflake: "3".to_owned(),
+                guilds: vec!["1".to_owned()],
+            }),
+            ..context(search_storage(), &[Scope::Read])
+        };
+        assert_eq!(execute_with(query, &context), expected);
+    }
+
+    fn assert_single_error(query: &str, context: Context, expected: juniper::FieldError) {
+        let (result, errs) = block_on(juniper::execute(
+            query,
+            None,
+            &schema(),
+            &Variables::new(),
+            &context,
+        ))
+        .unwrap();
+        assert_eq!(result, juniper::Value::Null);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].error(), &expected);
+    }
+}
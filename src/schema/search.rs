@@ -0,0 +1,421 @@
+use super::super::dql::{Field, Filter, Function, Pagination};
+use super::super::error::QueryCreationError;
+use super::connection::{
+    int_argument, string_argument, valid_page_size, HaikuOrder, HaikuOrderField, OrderDirection,
+    MAX_PAGE_SIZE,
+};
+use super::haiku::Haiku;
+use super::util::{parse_snowflake, MapsToDgraphQuery};
+use super::Context;
+use juniper::{
+    DefaultScalarValue, LookAheadArgument, LookAheadMethods, LookAheadSelection, LookAheadValue,
+};
+use regex::Regex;
+
+// Search results are always listed newest first
+const SEARCH_ORDER: HaikuOrder = HaikuOrder {
+    field: HaikuOrderField::Timestamp,
+    direction: OrderDirection::Desc,
+};
+// How many haikus with every word of a phrase are checked for the phrase itself, per result
+const PHRASE_CANDIDATES: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash, juniper::GraphQLEnum)]
+pub enum SearchMode {
+    // Haikus containing any of the terms
+    Any,
+    // Haikus containing every one of the terms
+    All,
+    // Haikus containing the terms next to each other, in order
+    Phrase,
+}
+
+// Limits a search to the haikus in a server and/or channel
+#[derive(Debug, Clone, Default, PartialEq, Hash, juniper::GraphQLInputObject)]
+pub struct SearchScope {
+    pub server_snowflake: Option<String>,
+    pub channel_snowflake: Option<String>,
+}
+
+impl SearchScope {
+    fn from_selection(
+        selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Self, QueryCreationError> {
+        let fields = match selection.argument("scope").map(LookAheadArgument::value) {
+            None | Some(LookAheadValue::Null) => return Ok(Self::default()),
+            Some(LookAheadValue::Object(fields)) => fields,
+            _ => return Err(invalid_scope("must be an object")),
+        };
+        let mut scope = Self::default();
+        for (name, value) in fields {
+            match (*name, value) {
+                (_, LookAheadValue::Null) => (),
+                ("serverSnowflake", LookAheadValue::Scalar(DefaultScalarValue::String(value))) => {
+                    scope.server_snowflake = Some(value.clone())
+                }
+                ("channelSnowflake", LookAheadValue::Scalar(DefaultScalarValue::String(value))) => {
+                    scope.channel_snowflake = Some(value.clone())
+                }
+                (name, _) => return Err(invalid_scope(&format!("invalid value for {}", name))),
+            }
+        }
+        Ok(scope)
+    }
+
+    fn validate(self) -> Result<Self, QueryCreationError> {
+        let valid = |snowflake: Option<String>| {
+            snowflake
+                .map(|snowflake| {
+                    parse_snowflake(&snowflake)
+                        .ok_or_else(|| invalid_scope("snowflakes must be discord snowflakes"))
+                })
+                .transpose()
+        };
+        Ok(Self {
+            server_snowflake: valid(self.server_snowflake)?,
+            channel_snowflake: valid(self.channel_snowflake)?,
+        })
+    }
+
+    fn to_dql(&self) -> Filter {
+        let mut filter = Filter::type_of("Haiku");
+        if let Some(snowflake) = &self.server_snowflake {
+            filter = filter.and(Filter::path(
+                &["channel", "server"],
+                Function::Eq("discordSnowflake".to_owned(), snowflake.clone().into()),
+                Filter::type_of("DiscordServer"),
+            ));
+        }
+        if let Some(snowflake) = &self.channel_snowflake {
+            filter = filter.and(Filter::edge(
+                "channel",
+                Function::Eq("discordSnowflake".to_owned(), snowflake.clone().into()),
+                Filter::type_of("DiscordChannel"),
+            ));
+        }
+        filter
+    }
+}
+
+fn invalid_scope(msg: &str) -> QueryCreationError {
+    QueryCreationError::InvalidArgument("scope".to_owned(), msg.to_owned())
+}
+
+#[derive(Debug, Clone, PartialEq, Hash)]
+pub struct SearchArguments {
+    pub terms: String,
+    pub mode: SearchMode,
+    pub scope: SearchScope,
+    pub max: i32,
+}
+
+impl SearchArguments {
+    pub fn new(
+        terms: String,
+        mode: Option<SearchMode>,
+        scope: Option<SearchScope>,
+        max: Option<i32>,
+    ) -> Result<Self, QueryCreationError> {
+        valid_page_size(max, "max")?;
+        Ok(Self {
+            terms: valid_search_terms(terms, "terms")?,
+            mode: mode.unwrap_or(SearchMode::Any),
+            scope: scope.unwrap_or_default().validate()?,
+            max: max.unwrap_or(MAX_PAGE_SIZE),
+        })
+    }
+
+    pub fn from_selection(
+        selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Self, QueryCreationError> {
+        let terms = string_argument(selection, "terms")?
+            .ok_or_else(|| QueryCreationError::MissingArgument("terms".to_owned()))?;
+        let mode = match selection.argument("mode").map(LookAheadArgument::value) {
+            None | Some(LookAheadValue::Null) => None,
+            Some(LookAheadValue::Enum("ANY")) => Some(SearchMode::Any),
+            Some(LookAheadValue::Enum("ALL")) => Some(SearchMode::All),
+            Some(LookAheadValue::Enum("PHRASE")) => Some(SearchMode::Phrase),
+            _ => {
+                return Err(QueryCreationError::InvalidArgument(
+                    "mode".to_owned(),
+                    "must be one of ANY, ALL or PHRASE".to_owned(),
+                ))
+            }
+        };
+        let scope = SearchScope::from_selection(selection)?;
+        Self::new(terms, mode, Some(scope), int_argument(selection, "max")?)
+    }
+
+    // The key a search's results are stored under, allowing several searches to be made at once
+    pub fn alias(&self, field: &str) -> String {
+        format!("{}_{:#x}", field, hash!(self))
+    }
+
+    pub fn function(&self) -> Function {
+        let terms = self.terms.clone().into();
+        match self.mode {
+            SearchMode::Any => Function::AnyOfTerms("content".to_owned(), terms),
+            SearchMode::All => Function::AllOfTerms("content".to_owned(), terms),
+            // Dgraph can't match phrases, so haikus with all of the words are fetched using the
+            // fulltext index and then checked for the phrase by `results`
+            SearchMode::Phrase => Function::AllOfText("content".to_owned(), terms),
+        }
+    }
+
+    // Matches haikus within the scope of the search, without searching their content
+    pub fn scope_filter(&self) -> Filter {
+        self.scope.to_dql()
+    }
+
+    pub fn dql_filter(&self) -> Filter {
+        self.scope_filter().and(self.function().into())
+    }
+
+    // Not every haiku fetched by a phrase search contains the phrase, so more are fetched than
+    // are needed. Searches for common words may still run out of haikus to check, which is
+    // reported by the results being incomplete.
+    pub fn pagination(&self) -> Pagination {
+        let max = i64::from(self.max);
+        Pagination {
+            order: SEARCH_ORDER.dql_order(),
+            first: match self.mode {
+                SearchMode::Phrase => Some(max * PHRASE_CANDIDATES),
+                _ => Some(max),
+            },
+            ..Pagination::default()
+        }
+    }
+
+    // The fields to fetch for each result, along with those needed to sort and check them
+    pub fn fields(
+        &self,
+        selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Vec<Field>, QueryCreationError> {
+        let mut fields = SearchResults::generate_inner_query(selection)?;
+        let mut required = vec![Field::uid("id"), Field::value("timestamp")];
+        if self.mode == SearchMode::Phrase {
+            required.push(Field::value("content"));
+        }
+        for field in required {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        Ok(fields)
+    }
+
+    // Turns the haikus fetched by a search into its results
+    pub fn results(&self, haikus: Vec<serde_json::Value>) -> SearchResults {
+        let fetched = haikus.len();
+        let haikus = haikus
+            .into_iter()
+            .filter(|haiku| {
+                self.mode != SearchMode::Phrase
+                    || haiku
+                        .get("content")
+                        .and_then(serde_json::Value::as_str)
+                        .is_some_and(|content| contains_phrase(content, &self.terms))
+            })
+            .take(self.max as usize)
+            .collect::<Vec<_>>();
+        // Haikus which weren't fetched are older than those that were, so they'd only be
+        // results if there's room for more
+        let incomplete = self.mode == SearchMode::Phrase
+            && Some(fetched as i64) == self.pagination().first
+            && haikus.len() < self.max as usize;
+        SearchResults { haikus, incomplete }
+    }
+}
+
+// The haikus found by a search, newest first
+#[derive(Debug)]
+pub struct SearchResults {
+    haikus: Vec<serde_json::Value>,
+    incomplete: bool,
+}
+
+#[juniper::graphql_object(context = Context, scalar = DefaultScalarValue)]
+impl SearchResults {
+    fn results(&self) -> Vec<Haiku> {
+        self.haikus.iter().cloned().map(Haiku::from).collect()
+    }
+
+    // Whether a phrase search ran out of haikus to check for the phrase, so that some which
+    // contain it may be missing from the results
+    fn incomplete(&self) -> bool {
+        self.incomplete
+    }
+}
+
+impl MapsToDgraphQuery for SearchResults {
+    fn generate_inner_query_for_field(
+        child_selection: &LookAheadSelection<DefaultScalarValue>,
+    ) -> Result<Vec<Field>, QueryCreationError> {
+        match child_selection.field_name() {
+            "results" => Haiku::generate_inner_query(child_selection),
+            "incomplete" => Ok(Vec::new()),
+            unknown_field => Err(QueryCreationError::UnknownField(unknown_field.to_owned())),
+        }
+    }
+}
+
+// Whether the words of the phrase appear consecutively in the content, ignoring case and
+// punctuation
+fn contains_phrase(content: &str, phrase: &str) -> bool {
+    let words = content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let phrase = phrase
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    words
+        .windows(phrase.len())
+        .any(|window| window == &phrase[..])
+}
+
+pub fn valid_search_terms(terms: String, arg: &str) -> Result<String, QueryCreationError> {
+    lazy_static! {
+        static ref SEARCH_TERM_REGEX: Regex =
+            Regex::new(r"^([[:alpha:]]+ )*[[:alpha:]]+$").unwrap();
+    }
+    if SEARCH_TERM_REGEX.is_match(&terms) {
+        Ok(terms)
+    } else {
+        Err(QueryCreationError::InvalidArgument(
+            arg.to_owned(),
+            "must be a set of words made up of only alphabetic characters separated by spaces"
+                .to_owned(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        content,
+        phrase,
+        expected,
+        case("An old silent pond", "silent pond", true),
+        case("An old silent pond", "SILENT  Pond", true),
+        case("An old silent pond...\nA frog jumps in", "pond a frog", true),
+        case("An old silent pond", "pond silent", false),
+        case("An old silent pond", "old pond", false)
+    )]
+    fn match_phrase(content: &str, phrase: &str, expected: bool) {
+        assert_eq!(contains_phrase(content, phrase), expected);
+    }
+
+    #[rstest(terms, mode, scope, max, valid,
+        case("frog", None, None, None, true),
+        case("old pond", Some(SearchMode::Phrase), Some((Some("1"), Some("2"))), Some(5), true),
+        case("", None, None, None, false),
+        case("frog!", None, None, None, false),
+        case("frog", None, Some((Some("0x1"), None)), None, false),
+        case("frog", None, None, Some(MAX_PAGE_SIZE + 1), false),
+    )]
+    fn create_search_arguments(
+        terms: &str,
+        mode: Option<SearchMode>,
+        scope: Option<(Option<&str>, Option<&str>)>,
+        max: Option<i32>,
+        valid: bool,
+    ) {
+        let scope = scope.map(|(server, channel)| SearchScope {
+            server_snowflake: server.map(str::to_owned),
+            channel_snowflake: channel.map(str::to_owned),
+        });
+        assert_eq!(
+            SearchArguments::new(terms.to_owned(), mode, scope, max).is_ok(),
+            valid
+        );
+    }
+
+    #[test]
+    fn generate_search_filter() {
+        let arguments = SearchArguments::new(
+            "old pond".to_owned(),
+            Some(SearchMode::All),
+            Some(SearchScope {
+                server_snowflake: Some("1".to_owned()),
+                channel_snowflake: None,
+            }),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            arguments.dql_filter(),
+            Filter::type_of("Haiku")
+                .and(Filter::path(
+                    &["channel", "server"],
+                    Function::Eq("discordSnowflake".to_owned(), "1".into()),
+                    Filter::type_of("DiscordServer"),
+                ))
+                .and(Function::AllOfTerms("content".to_owned(), "old pond".into()).into())
+        );
+    }
+
+    #[rstest(
+        mode,
+        expected_first,
+        case(SearchMode::Any, 5),
+        case(SearchMode::All, 5),
+        case(SearchMode::Phrase, 50)
+    )]
+    fn limit_searches(mode: SearchMode, expected_first: i64) {
+        let arguments =
+            SearchArguments::new("old pond".to_owned(), Some(mode), None, Some(5)).unwrap();
+        assert_eq!(arguments.pagination().first, Some(expected_first));
+    }
+
+    // Haikus with the given uids, each one older than the last, which contain the phrase "old
+    // pond" if their uid is in the matching list
+    fn candidates(uids: std::ops::RangeInclusive<u64>, matching: &[u64]) -> Vec<serde_json::Value> {
+        uids.map(|uid| {
+            let content = if matching.contains(&uid) {
+                "an old pond"
+            } else {
+                "a pond that is old"
+            };
+            serde_json::json!({
+                "id": format!("{:#x}", uid),
+                "timestamp": format!("2020-01-{:02}T00:00:00Z", 30 - uid),
+                "content": content,
+            })
+        })
+        .collect()
+    }
+
+    #[rstest(haikus, expected_ids, incomplete,
+        case(candidates(1..=10, &[]), vec![], true),
+        case(candidates(1..=3, &[]), vec![], false),
+        case(candidates(1..=10, &[2]), vec!["0x2"], false),
+        case(candidates(1..=10, &[2, 3]), vec!["0x2"], false),
+    )]
+    fn report_incomplete_phrase_searches(
+        haikus: Vec<serde_json::Value>,
+        expected_ids: Vec<&str>,
+        incomplete: bool,
+    ) {
+        let arguments = SearchArguments::new(
+            "old pond".to_owned(),
+            Some(SearchMode::Phrase),
+            None,
+            Some(1),
+        )
+        .unwrap();
+        let results = arguments.results(haikus);
+        let ids = results
+            .haikus
+            .iter()
+            .map(|haiku| haiku["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, expected_ids);
+        assert_eq!(results.incomplete, incomplete);
+    }
+}
@@ -1,23 +1,45 @@
-use super::super::error::{internal_error, CompositeQueryCreationError, QueryCreationError};
+use super::super::dql::Field;
+use super::super::error::{
+    data_corrupt, invalid_argument, CompositeQueryCreationError, QueryCreationError,
+};
 use juniper::{
-    DefaultScalarValue, EmptyMutation, GraphQLType, LookAheadMethods, LookAheadSelection, RootNode,
-    Variables,
+    DefaultScalarValue, EmptyMutation, EmptySubscription, FieldError, GraphQLType, GraphQLValue,
+    LookAheadMethods, LookAheadSelection, RootNode, Variables,
 };
 use serde_json::json;
 
+// A context for resolving objects on their own in tests, which is allowed to see everything
+#[cfg(test)]
+pub fn test_context() -> super::Context {
+    super::Context {
+        storage: std::sync::Arc::new(super::super::storage::MemoryStorage::new()),
+        mutations_enabled: true,
+        scopes: vec![super::super::auth::Scope::Admin],
+        viewer: None,
+        limits: super::super::config::LimitsConfig::default(),
+        spent_cost: std::sync::Arc::default(),
+        events: std::sync::Arc::new(super::super::events::HaikuEvents::default()),
+        lookups: Default::default(),
+    }
+}
+
 #[allow(dead_code)]
 pub fn resolve_missing_field<T>(
     query: &str,
-    context: <T as GraphQLType>::Context,
+    context: <T as GraphQLValue>::Context,
     expected_result: Result<juniper::Value, Vec<&str>>,
 ) where
     T: From<serde_json::Value> + GraphQLType<TypeInfo = ()>,
 {
     let query = format!(r#"query {{ {} }}"#, query);
-    let (result, errs) = juniper::execute(
+    let (result, errs) = juniper::execute_sync(
         &query,
         None,
-        &RootNode::new(T::from(json!({})), EmptyMutation::new()),
+        &RootNode::new(
+            T::from(json!({})),
+            EmptyMutation::new(),
+            EmptySubscription::new(),
+        ),
         &Variables::new(),
         &context,
     )
@@ -32,7 +54,7 @@ pub fn resolve_missing_field<T>(
             assert_eq!(errs.len(), 1);
             let err = &errs[0];
             assert_eq!(err.path(), &error_path[..]);
-            assert_eq!(err.error(), &internal_error());
+            assert_eq!(err.error(), &data_corrupt());
         }
     };
 }
@@ -40,26 +62,41 @@ pub fn resolve_missing_field<T>(
 pub trait MapsToDgraphQuery {
     fn generate_inner_query_for_field(
         child_selection: &LookAheadSelection<DefaultScalarValue>,
-    ) -> Result<String, QueryCreationError>;
+    ) -> Result<Vec<Field>, QueryCreationError>;
 
     fn generate_inner_query(
         selection: &LookAheadSelection<DefaultScalarValue>,
-    ) -> Result<String, QueryCreationError> {
+    ) -> Result<Vec<Field>, QueryCreationError> {
         let (query_sections, errs): (Vec<_>, Vec<_>) = selection
-            .child_names()
-            .iter()
-            .map(|field_name| selection.select_child(field_name).unwrap())
-            .map(|child_selection| Self::generate_inner_query_for_field(child_selection))
+            .children()
+            .into_iter()
+            // Juniper only gives the alias of an aliased child rather than its field name, so
+            // a child which doesn't match any field must have been aliased. Argument errors are
+            // placed under the child they belong to, so that their path leads to it.
+            .map(|child| {
+                Self::generate_inner_query_for_field(child).map_err(|err| match err {
+                    QueryCreationError::UnknownField(alias) => {
+                        QueryCreationError::UnsupportedAlias(alias)
+                    }
+                    err @ QueryCreationError::MissingArgument(_)
+                    | err @ QueryCreationError::InvalidArgument(_, _) => {
+                        QueryCreationError::Composite(CompositeQueryCreationError {
+                            at_field: child.field_name().to_owned(),
+                            children: vec![err],
+                        })
+                    }
+                    err => err,
+                })
+            })
             .partition(Result::is_ok);
         if errs.is_empty() {
-            // Extract Vec<Result<String, QueryCreationError>> into Vec<String> and join
+            // Extract Vec<Result<Vec<Field>, QueryCreationError>> into Vec<Field>
             Ok(query_sections
                 .into_iter()
-                .map(Result::unwrap)
-                .collect::<Vec<String>>()
-                .join("\n"))
+                .flat_map(Result::unwrap)
+                .collect())
         } else {
-            // Extract Vec<Result<String, QueryCreationError>> into Vec<QueryCreationError>
+            // Extract Vec<Result<Vec<Field>, QueryCreationError>> into Vec<QueryCreationError>
             // Gather errors into composite error
             Err(QueryCreationError::Composite(CompositeQueryCreationError {
                 at_field: selection.field_name().to_owned(),
@@ -69,6 +106,30 @@ pub trait MapsToDgraphQuery {
     }
 }
 
+// Snowflakes are numbers, so one written with leading zeros is trimmed to the form Discord gives
+// and they're stored in, eg. "0042" is "42"
+pub fn parse_snowflake(snowflake: &str) -> Option<String> {
+    if snowflake.is_empty()
+        || snowflake.len() > 20
+        || !snowflake.bytes().all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+    match snowflake.trim_start_matches('0') {
+        "" => Some("0".to_owned()),
+        trimmed => Some(trimmed.to_owned()),
+    }
+}
+
+pub fn valid_snowflake(snowflake: String, argument: &str) -> Result<String, FieldError> {
+    parse_snowflake(&snowflake).ok_or_else(|| {
+        invalid_argument(
+            argument,
+            "Invalid discord snowflake: must be a string of up to 20 digits",
+        )
+    })
+}
+
 #[macro_export]
 macro_rules! hash {
     ( $( $x:expr ),* ) => {
@@ -84,3 +145,26 @@ macro_rules! hash {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        snowflake,
+        expected,
+        case("0000000000000000001", Some("1")),
+        case("1", Some("1")),
+        case("000", Some("0")),
+        case("18446744073709551615", Some("18446744073709551615")),
+        case("", None),
+        case("184467440737095516150", None),
+        case("0x1", None),
+        case("1 OR 1", None),
+        case("١٢٣", None)
+    )]
+    fn parse_snowflakes(snowflake: &str, expected: Option<&str>) {
+        assert_eq!(parse_snowflake(snowflake).as_deref(), expected);
+    }
+}
@@ -0,0 +1,132 @@
+use super::error::SessionError;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// Session tokens are given as bearer tokens like API keys, so they're marked to tell them apart
+const TOKEN_PREFIX: &str = "session.";
+
+// Someone logged in with Discord, along with the servers they were a member of when they logged in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Viewer {
+    pub discord_snowflake: String,
+    pub guilds: Vec<String>,
+}
+
+impl Viewer {
+    pub fn is_member(&self, server_snowflake: &str) -> bool {
+        self.guilds.iter().any(|guild| guild == server_snowflake)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    #[serde(flatten)]
+    viewer: Viewer,
+    // Unix timestamp after which the token is no longer accepted
+    exp: i64,
+}
+
+pub fn is_token(key: &str) -> bool {
+    key.starts_with(TOKEN_PREFIX)
+}
+
+fn mac(secret: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.update(payload.as_bytes());
+    mac
+}
+
+// Makes a token of the form "session.<payload>.<signature>", where the payload is the claims as
+// base64 encoded JSON and the signature is its HMAC-SHA256. Sessions are stateless, so the token
+// holds everything needed to check a request and can't be revoked before it expires.
+pub fn issue(secret: &str, viewer: &Viewer, expires_at: i64) -> String {
+    let claims = Claims {
+        viewer: viewer.clone(),
+        exp: expires_at,
+    };
+    let payload = base64::encode_config(
+        &serde_json::to_vec(&claims).unwrap(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let signature = base64::encode_config(
+        &mac(secret, &payload).finalize().into_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    format!("{}{}.{}", TOKEN_PREFIX, payload, signature)
+}
+
+pub fn verify(secret: &str, token: &str, now: i64) -> Result<Viewer, SessionError> {
+    let (payload, signature) = token
+        .strip_prefix(TOKEN_PREFIX)
+        .and_then(|token| token.split_once('.'))
+        .ok_or(SessionError::Malformed)?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| SessionError::Malformed)?;
+    mac(secret, payload)
+        .verify(&signature)
+        .map_err(|_| SessionError::InvalidSignature)?;
+    let claims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice::<Claims>(&json).ok())
+        .ok_or(SessionError::Malformed)?;
+    if claims.exp <= now {
+        return Err(SessionError::Expired);
+    }
+    Ok(claims.viewer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn viewer() -> Viewer {
+        Viewer {
+            discord_snowflake: "3".to_owned(),
+            guilds: vec!["1".to_owned()],
+        }
+    }
+
+    #[test]
+    fn verify_issued_token() {
+        let token = issue(SECRET, &viewer(), 1000);
+        assert!(is_token(&token));
+        assert_eq!(verify(SECRET, &token, 999), Ok(viewer()));
+    }
+
+    #[rstest(
+        secret,
+        now,
+        tamper,
+        expected,
+        case(SECRET, 1000, false, SessionError::Expired),
+        case("another secret", 0, false, SessionError::InvalidSignature),
+        case(SECRET, 0, true, SessionError::InvalidSignature)
+    )]
+    fn reject_invalid_tokens(secret: &str, now: i64, tamper: bool, expected: SessionError) {
+        let mut token = issue(secret, &viewer(), 1000);
+        if tamper {
+            let other = issue(
+                secret,
+                &Viewer {
+                    guilds: vec!["2".to_owned()],
+                    ..viewer()
+                },
+                1000,
+            );
+            let payload = other.split('.').nth(1).unwrap();
+            let signature = token.rsplit('.').next().unwrap().to_owned();
+            token = format!("{}{}.{}", TOKEN_PREFIX, payload, signature);
+        }
+        assert_eq!(verify(SECRET, &token, now), Err(expected));
+    }
+
+    #[rstest(token, case("session.e30"), case("session.e30.!!"), case("bot-key"))]
+    fn reject_malformed_tokens(token: &str) {
+        assert_eq!(verify(SECRET, token, 0), Err(SessionError::Malformed));
+    }
+}
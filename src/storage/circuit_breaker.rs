@@ -0,0 +1,81 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Stops requests being sent to a backend which keeps failing, so that callers fail fast rather than
+// each waiting out their own timeouts and retries. Once enough failures happen in a row the breaker
+// opens, and after the cooldown requests are let through again to find out whether the backend has
+// recovered, with the first failure opening it straight back up.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    // Whether a request may be made
+    pub fn allow(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.open_until.is_none_or(|open_until| now >= open_until)
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            info!("Dgraph has recovered, closing circuit breaker");
+        }
+        *state = BreakerState::default();
+    }
+
+    pub fn record_failure(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            if state.failures == self.threshold {
+                warn!(
+                    "Dgraph failed {} times in a row, opening circuit breaker",
+                    state.failures
+                );
+            }
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn open_after_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        breaker.record_failure(start);
+        assert!(breaker.allow(start));
+        breaker.record_failure(start);
+        assert!(!breaker.allow(start));
+        assert!(!breaker.allow(start + Duration::from_secs(9)));
+        // Half open, so a single failure reopens it
+        assert!(breaker.allow(start + Duration::from_secs(10)));
+        breaker.record_failure(start + Duration::from_secs(10));
+        assert!(!breaker.allow(start + Duration::from_secs(19)));
+        assert!(breaker.allow(start + Duration::from_secs(20)));
+        breaker.record_success();
+        breaker.record_failure(start + Duration::from_secs(20));
+        assert!(breaker.allow(start + Duration::from_secs(20)));
+    }
+}
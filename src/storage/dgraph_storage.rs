@@ -0,0 +1,323 @@
+use super::super::config::StorageConfig;
+use super::super::dql::{self, Query};
+use super::super::error::{DgraphQueryError, StorageError};
+use super::super::metrics::observe_dgraph;
+use super::super::schema::{NewHaiku, NEW_HAIKU_BLANK_NODE};
+use super::circuit_breaker::CircuitBreaker;
+use super::query_cache::{CacheKey, QueryCache};
+use super::Storage;
+use async_trait::async_trait;
+use dgraph::grpcio::CallOption;
+use futures::compat::Future01CompatExt;
+use futures_timer::Delay;
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+pub struct DgraphStorage {
+    clients: Vec<dgraph::DgraphClient>,
+    next_client: AtomicUsize,
+    timeout: Duration,
+    retries: u32,
+    retry_backoff: Duration,
+    breaker: CircuitBreaker,
+    cache: Option<QueryCache>,
+}
+
+impl DgraphStorage {
+    pub fn new(clients: Vec<dgraph::DgraphClient>, config: &StorageConfig) -> Self {
+        assert!(
+            !clients.is_empty(),
+            "Dgraph storage needs at least one client"
+        );
+        Self {
+            clients,
+            next_client: AtomicUsize::new(0),
+            timeout: Duration::from_millis(config.dgraph_timeout_ms),
+            retries: config.dgraph_retries,
+            retry_backoff: Duration::from_millis(config.dgraph_retry_backoff_ms),
+            breaker: CircuitBreaker::new(
+                config.dgraph_breaker_failures,
+                Duration::from_millis(config.dgraph_breaker_cooldown_ms),
+            ),
+            cache: NonZeroUsize::new(config.cache_entries).map(|entries| {
+                QueryCache::new(entries, Duration::from_millis(config.cache_ttl_ms))
+            }),
+        }
+    }
+
+    // Takes each alpha in turn, so a retried request goes to a different alpha than last time
+    fn next_client(&self) -> &dgraph::DgraphClient {
+        let index = self.next_client.fetch_add(1, Ordering::Relaxed);
+        &self.clients[index % self.clients.len()]
+    }
+
+    // Sends a request to an alpha without blocking the calling thread
+    async fn send(&self, request: &dgraph::Request) -> Result<dgraph::Response, DgraphQueryError> {
+        let option = CallOption::default().timeout(self.timeout);
+        Ok(self
+            .next_client()
+            .query_async_opt(request, option)?
+            .compat()
+            .await?)
+    }
+
+    // Sends a request, retrying it if asked to. Each request stands alone, being either read only
+    // or committed straight away, so no transaction state needs to be kept between requests.
+    async fn request(
+        &self,
+        request: &dgraph::Request,
+        retry: bool,
+    ) -> Result<dgraph::Response, DgraphQueryError> {
+        if !self.breaker.allow(Instant::now()) {
+            return Err(DgraphQueryError::CircuitOpen);
+        }
+        let retries = if retry { self.retries } else { 0 };
+        let result = with_retries(retries, self.retry_backoff, || self.send(request)).await;
+        match &result {
+            Err(err) if err.is_transient() => self.breaker.record_failure(Instant::now()),
+            _ => self.breaker.record_success(),
+        }
+        result
+    }
+
+    async fn perform_query(
+        &self,
+        query: &str,
+        vars: HashMap<String, String>,
+        retry: bool,
+    ) -> Result<serde_json::Value, DgraphQueryError> {
+        let mut request = dgraph::Request::new();
+        request.set_query(query.to_owned());
+        request.set_vars(vars);
+        request.set_read_only(true);
+        let response = self.request(&request, retry).await?;
+        let response = String::from_utf8(response.json)?;
+        let response = serde_json::from_str::<serde_json::Value>(&response)?;
+        Ok(response)
+    }
+
+    async fn perform_upsert(
+        &self,
+        query: &str,
+        vars: HashMap<String, String>,
+        set_json: &serde_json::Value,
+    ) -> Result<HashMap<String, String>, DgraphQueryError> {
+        let mut mutation = dgraph::Mutation::new();
+        mutation.set_set_json(serde_json::to_vec(set_json)?);
+        let mut request = dgraph::Request::new();
+        request.set_query(query.to_owned());
+        request.set_vars(vars);
+        request.set_mutations(vec![mutation].into());
+        request.set_commit_now(true);
+        let response = self.request(&request, false).await?;
+        Ok(response.uids)
+    }
+}
+
+#[async_trait]
+impl Storage for DgraphStorage {
+    async fn query(&self, query: &Query) -> Result<serde_json::Value, StorageError> {
+        let (text, vars) = dql::render(query);
+        let cached = self.cache.as_ref().map(|cache| {
+            let key = CacheKey::new(&text, &vars);
+            let (result, generation) = cache.get(&key, Instant::now());
+            (cache, key, result, generation)
+        });
+        if let Some((_, _, Some(result), _)) = cached {
+            return Ok(result);
+        }
+        let span = tracing::info_span!("dgraph", request = "query");
+        tracing::debug!(parent: &span, dql = %text, vars = ?vars, "Sending Dgraph query");
+        let response = observe_dgraph("query", self.perform_query(&text, vars, true))
+            .instrument(span)
+            .await?;
+        if let Some((cache, key, _, generation)) = cached {
+            cache.insert(key, query, &response, generation, Instant::now());
+        }
+        Ok(response)
+    }
+
+    async fn add_haiku(&self, haiku: &NewHaiku) -> Result<String, StorageError> {
+        let (query, set_json) = haiku.generate_upsert();
+        let (text, vars) = dql::render(&query);
+        let span = tracing::info_span!("dgraph", request = "mutation");
+        tracing::debug!(
+            parent: &span,
+            dql = %text,
+            vars = ?vars,
+            set_json = %set_json,
+            "Sending Dgraph upsert"
+        );
+        let result = observe_dgraph("mutation", self.perform_upsert(&text, vars, &set_json))
+            .instrument(span)
+            .await;
+        // Invalidated even if the upsert failed, as it may still have been applied
+        if let Some(cache) = &self.cache {
+            let mut snowflakes = vec![
+                haiku.server_snowflake.as_str(),
+                haiku.channel_snowflake.as_str(),
+            ];
+            snowflakes.extend(haiku.author_snowflakes.iter().map(String::as_str));
+            cache.invalidate(&snowflakes);
+        }
+        let mut uids = result?;
+        uids.remove(NEW_HAIKU_BLANK_NODE)
+            .ok_or_else(|| StorageError::MalformedResponse("haiku uid not assigned".to_owned()))
+    }
+
+    // Asks Dgraph for the predicates the API relies on, which is cheap and fails if no alpha is
+    // reachable. Not retried, as the caller will ask again soon anyway.
+    async fn check_ready(&self) -> Result<(), StorageError> {
+        let query = format!(
+            "schema(pred: [{}]) {{ predicate }}",
+            SCHEMA_PREDICATES.join(", ")
+        );
+        let response = self.perform_query(&query, HashMap::new(), false);
+        let response = observe_dgraph("schema", response)
+            .instrument(tracing::info_span!("dgraph", request = "schema"))
+            .await?;
+        match missing_predicates(&response) {
+            missing if missing.is_empty() => Ok(()),
+            missing => Err(StorageError::MissingPredicates(missing)),
+        }
+    }
+}
+
+// The predicates which schema.dgraph defines
+fn schema_predicates(schema: &'static str) -> Vec<&'static str> {
+    schema
+        .lines()
+        .filter_map(|line| Some(line.split_once(':')?.0))
+        .filter(|predicate| !predicate.is_empty() && !predicate.contains(char::is_whitespace))
+        .collect()
+}
+
+lazy_static! {
+    static ref SCHEMA_PREDICATES: Vec<&'static str> =
+        schema_predicates(include_str!("../schema/schema.dgraph"));
+}
+
+fn missing_predicates(response: &serde_json::Value) -> Vec<String> {
+    let present = response
+        .get("schema")
+        .and_then(serde_json::Value::as_array)
+        .map(|schema| {
+            schema
+                .iter()
+                .filter_map(|predicate| predicate.get("predicate")?.as_str())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    SCHEMA_PREDICATES
+        .iter()
+        .filter(|predicate| !present.contains(predicate))
+        .map(|predicate| (*predicate).to_owned())
+        .collect()
+}
+
+// Makes an attempt, retrying it after transient failures with a backoff that doubles each time
+async fn with_retries<T, F, Fut>(
+    retries: u32,
+    backoff: Duration,
+    mut attempt: F,
+) -> Result<T, DgraphQueryError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DgraphQueryError>>,
+{
+    let mut backoff = backoff;
+    for _ in 0..retries {
+        match attempt().await {
+            Err(err) if err.is_transient() => {
+                warn!("Retrying Dgraph request in {:?} - {}", backoff, err);
+                Delay::new(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    attempt().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dgraph::{grpcio, DgraphError};
+    use futures::executor::block_on;
+    use rstest::rstest;
+
+    #[rstest(
+        retries,
+        failures,
+        attempts,
+        succeeded,
+        case(3, 0, 1, true),
+        case(3, 2, 3, true),
+        case(3, 3, 4, true),
+        case(3, 4, 4, false),
+        case(0, 1, 1, false)
+    )]
+    fn retry_transient_errors(retries: u32, failures: u32, attempts: u32, succeeded: bool) {
+        let mut made = 0;
+        let result = block_on(with_retries(retries, Duration::from_millis(1), || {
+            made += 1;
+            let result = if made > failures {
+                Ok(made)
+            } else {
+                Err(DgraphQueryError::Transient(grpcio::Error::RemoteStopped))
+            };
+            async move { result }
+        }));
+        assert_eq!(made, attempts);
+        assert_eq!(result.is_ok(), succeeded);
+    }
+
+    #[test]
+    fn read_schema_predicates() {
+        assert_eq!(
+            *SCHEMA_PREDICATES,
+            vec![
+                "author",
+                "channel",
+                "discordSnowflake",
+                "content",
+                "rulesVersion",
+                "server",
+                "timestamp"
+            ]
+        );
+    }
+
+    #[test]
+    fn find_missing_predicates() {
+        let response = json!({
+            "schema": [
+                { "predicate": "author" },
+                { "predicate": "channel" },
+                { "predicate": "content" },
+                { "predicate": "rulesVersion" },
+                { "predicate": "server" },
+            ]
+        });
+        assert_eq!(
+            missing_predicates(&response),
+            vec!["discordSnowflake".to_owned(), "timestamp".to_owned()]
+        );
+        assert_eq!(missing_predicates(&json!({})).len(), 7);
+    }
+
+    #[test]
+    fn fail_fast_on_other_errors() {
+        let mut made = 0;
+        let result: Result<(), _> = block_on(with_retries(3, Duration::from_millis(1), || {
+            made += 1;
+            async { Err(DgraphQueryError::Dgraph(DgraphError::EmptyTxn)) }
+        }));
+        assert_eq!(made, 1);
+        assert!(result.is_err());
+    }
+}
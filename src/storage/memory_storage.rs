@@ -0,0 +1,676 @@
+use super::super::dql::{
+    compare_values, Block, Edge, Field, Filter, Function, Pagination, Predicate, Query, Value,
+};
+use super::super::error::{RdfParseError, StorageError};
+use super::super::schema::NewHaiku;
+use super::Storage;
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+// Predicates which are typed as int in schema.dgraph, all other literals are kept as strings
+const INT_PREDICATES: &[&str] = &["rulesVersion"];
+// Predicates which are typed as [uid] in schema.dgraph, all other edges hold a single node
+const LIST_PREDICATES: &[&str] = &["author"];
+
+#[derive(Debug, Default)]
+struct Node {
+    types: Vec<String>,
+    values: HashMap<String, serde_json::Value>,
+    edges: HashMap<String, Vec<u64>>,
+}
+
+#[derive(Debug, Default)]
+struct Graph {
+    nodes: BTreeMap<u64, Node>,
+}
+
+impl Graph {
+    fn insert_node(&mut self) -> u64 {
+        let uid = self.nodes.keys().next_back().map_or(1, |uid| uid + 1);
+        self.nodes.insert(uid, Node::default());
+        uid
+    }
+
+    fn node_mut(&mut self, uid: u64) -> &mut Node {
+        self.nodes.entry(uid).or_default()
+    }
+
+    fn has_type(&self, uid: u64, type_name: &str) -> bool {
+        self.nodes
+            .get(&uid)
+            .is_some_and(|node| node.types.iter().any(|t| t == type_name))
+    }
+
+    fn value(&self, uid: u64, predicate: &str) -> Option<&serde_json::Value> {
+        self.nodes
+            .get(&uid)
+            .and_then(|node| node.values.get(predicate))
+    }
+
+    fn edges(&self, uid: u64, predicate: &Predicate) -> Vec<u64> {
+        match predicate {
+            Predicate::Forward(predicate) => self
+                .nodes
+                .get(&uid)
+                .and_then(|node| node.edges.get(predicate))
+                .cloned()
+                .unwrap_or_default(),
+            Predicate::Reverse(predicate) => self
+                .nodes
+                .iter()
+                .filter(|(_, node)| {
+                    node.edges
+                        .get(predicate)
+                        .is_some_and(|targets| targets.contains(&uid))
+                })
+                .map(|(source, _)| *source)
+                .collect(),
+        }
+    }
+
+    fn find_by_snowflake(&self, snowflake: &str, type_name: &str) -> Option<u64> {
+        self.nodes.keys().cloned().find(|uid| {
+            self.has_type(*uid, type_name)
+                && self.value(*uid, "discordSnowflake") == Some(&json!(snowflake))
+        })
+    }
+
+    fn upsert_by_snowflake(&mut self, snowflake: &str, type_name: &str) -> u64 {
+        if let Some(uid) = self.find_by_snowflake(snowflake, type_name) {
+            return uid;
+        }
+        let uid = self.insert_node();
+        let node = self.node_mut(uid);
+        node.types.push(type_name.to_owned());
+        node.values
+            .insert("discordSnowflake".to_owned(), json!(snowflake));
+        uid
+    }
+
+    fn matches_value(&self, uid: u64, predicate: &str, value: &Value) -> bool {
+        match (self.value(uid, predicate), value) {
+            (_, Value::List(values)) => values
+                .iter()
+                .any(|value| self.matches_value(uid, predicate, value)),
+            (Some(serde_json::Value::String(stored)), Value::String(value)) => stored == value,
+            (Some(serde_json::Value::Number(stored)), Value::Int(value)) => {
+                stored.as_i64() == Some(*value)
+            }
+            (Some(serde_json::Value::Number(stored)), Value::String(value)) => {
+                stored.to_string() == *value
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_function(&self, uid: u64, function: &Function) -> bool {
+        match function {
+            Function::Uid(Value::List(values)) => {
+                values.iter().any(|value| parse_uid(value) == Some(uid))
+            }
+            Function::Uid(value) => parse_uid(value) == Some(uid),
+            Function::Type(type_name) => self.has_type(uid, type_name),
+            Function::Eq(predicate, value) => self.matches_value(uid, predicate, value),
+            Function::Gt(predicate, value) => self
+                .value(uid, predicate)
+                .is_some_and(|stored| compare_values(stored, &value.to_json()).is_gt()),
+            Function::Ge(predicate, value) => self
+                .value(uid, predicate)
+                .is_some_and(|stored| compare_values(stored, &value.to_json()).is_ge()),
+            Function::Lt(predicate, value) => self
+                .value(uid, predicate)
+                .is_some_and(|stored| compare_values(stored, &value.to_json()).is_lt()),
+            Function::Le(predicate, value) => self
+                .value(uid, predicate)
+                .is_some_and(|stored| compare_values(stored, &value.to_json()).is_le()),
+            Function::AnyOfTerms(predicate, Value::String(terms)) => {
+                self.matches_terms(uid, predicate, terms, false)
+            }
+            // Without stemming or stop words, text matching is the same as matching terms
+            Function::AllOfTerms(predicate, Value::String(terms))
+            | Function::AllOfText(predicate, Value::String(terms)) => {
+                self.matches_terms(uid, predicate, terms, true)
+            }
+            Function::AnyOfTerms(_, _) | Function::AllOfTerms(_, _) | Function::AllOfText(_, _) => {
+                false
+            }
+        }
+    }
+
+    fn matches_terms(&self, uid: u64, predicate: &str, terms: &str, all: bool) -> bool {
+        match self.value(uid, predicate) {
+            Some(serde_json::Value::String(content)) => match_terms(content, terms, all),
+            _ => false,
+        }
+    }
+
+    fn matches_filter(&self, uid: u64, filter: &Filter) -> bool {
+        match filter {
+            Filter::Type(type_name) => self.has_type(uid, type_name),
+            Filter::Function(function) => self.matches_function(uid, function),
+            Filter::And(filters) => filters
+                .iter()
+                .all(|filter| self.matches_filter(uid, filter)),
+            Filter::Or(filters) => filters
+                .iter()
+                .any(|filter| self.matches_filter(uid, filter)),
+            Filter::Not(filter) => !self.matches_filter(uid, filter),
+            Filter::Var(block) => self.block_uids(block).contains(&uid),
+            Filter::Edge { path, func, filter } => path
+                .iter()
+                .fold(vec![uid], |uids, predicate| {
+                    uids.into_iter()
+                        .flat_map(|uid| self.edges(uid, &Predicate::forward(predicate)))
+                        .collect()
+                })
+                .into_iter()
+                .any(|target| {
+                    self.matches_function(target, func) && self.matches_filter(target, filter)
+                }),
+        }
+    }
+
+    fn filtered(&self, uids: Vec<u64>, filter: &Option<Filter>) -> Vec<u64> {
+        uids.into_iter()
+            .filter(|uid| {
+                filter
+                    .as_ref()
+                    .is_none_or(|filter| self.matches_filter(*uid, filter))
+            })
+            .collect()
+    }
+
+    fn paginated(
+        &self,
+        uids: Vec<u64>,
+        filter: &Option<Filter>,
+        pagination: &Pagination,
+    ) -> Vec<u64> {
+        let mut uids = self.filtered(uids, filter);
+        if let Some(order) = &pagination.order {
+            // Sorting is stable, so nodes with equal values stay in uid order as they do in Dgraph
+            uids.sort_by(|a, b| {
+                order.compare(
+                    self.value(*a, &order.predicate),
+                    self.value(*b, &order.predicate),
+                )
+            });
+        }
+        if let Some(after) = pagination.after {
+            uids.retain(|uid| *uid > after);
+        }
+        let offset = pagination.offset.map_or(0, |offset| offset.max(0) as usize);
+        let uids = uids.get(offset..).unwrap_or_default();
+        // Like Dgraph, a negative first counts back from the end of the list
+        let uids = match pagination.first {
+            Some(first) if first < 0 => {
+                &uids[uids.len().saturating_sub(first.unsigned_abs() as usize)..]
+            }
+            Some(first) => &uids[..uids.len().min(first as usize)],
+            None => uids,
+        };
+        uids.to_vec()
+    }
+
+    fn select(
+        &self,
+        uids: Vec<u64>,
+        filter: &Option<Filter>,
+        pagination: &Pagination,
+        fields: &[Field],
+    ) -> Vec<serde_json::Value> {
+        self.paginated(uids, filter, pagination)
+            .into_iter()
+            .map(|uid| self.node_json(uid, fields))
+            // Like Dgraph, nodes with none of the requested fields are left out of the result
+            .filter(|json| json.as_object().is_some_and(|json| !json.is_empty()))
+            .collect()
+    }
+
+    fn node_json(&self, uid: u64, fields: &[Field]) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        for field in fields {
+            match field {
+                Field::Uid { alias } => {
+                    json.insert(alias.clone(), json!(format!("{:#x}", uid)));
+                }
+                Field::Value { alias, predicate } => {
+                    if let Some(value) = self.value(uid, predicate) {
+                        json.insert(alias.as_ref().unwrap_or(predicate).clone(), value.clone());
+                    }
+                }
+                Field::Edge(edge) => {
+                    if let Some(value) = self.edge_json(uid, edge) {
+                        json.insert(edge_key(edge).to_owned(), value);
+                    }
+                }
+                Field::Count {
+                    alias,
+                    predicate,
+                    filter,
+                } => {
+                    let count = self.filtered(self.edges(uid, predicate), filter).len();
+                    json.insert(alias.clone(), json!(count));
+                }
+                // Only valid in a top level block, where it's handled by block_json
+                Field::CountUid { .. } => (),
+            }
+        }
+        serde_json::Value::Object(json)
+    }
+
+    fn edge_json(&self, uid: u64, edge: &Edge) -> Option<serde_json::Value> {
+        let nodes = self.select(
+            self.edges(uid, &edge.predicate),
+            &edge.filter,
+            &edge.pagination,
+            &edge.fields,
+        );
+        match &edge.predicate {
+            _ if nodes.is_empty() => None,
+            Predicate::Forward(predicate) if !LIST_PREDICATES.contains(&predicate.as_str()) => {
+                nodes.into_iter().next()
+            }
+            _ => Some(serde_json::Value::Array(nodes)),
+        }
+    }
+
+    fn block_uids(&self, block: &Block) -> Vec<u64> {
+        let uids = self.function_uids(&block.func);
+        self.paginated(uids, &block.filter, &block.pagination)
+    }
+
+    fn function_uids(&self, function: &Function) -> Vec<u64> {
+        self.nodes
+            .keys()
+            .cloned()
+            .filter(|uid| self.matches_function(*uid, function))
+            .collect()
+    }
+
+    fn block_json(&self, block: &Block) -> serde_json::Value {
+        let uids = self.function_uids(&block.func);
+        // Like Dgraph, a block counting its nodes returns just the count
+        let count_alias = block.fields.iter().find_map(|field| match field {
+            Field::CountUid { alias } => Some(alias),
+            _ => None,
+        });
+        if let Some(alias) = count_alias {
+            let count = self.filtered(uids, &block.filter).len();
+            return json!([{ alias.clone(): count }]);
+        }
+        serde_json::Value::Array(self.select(uids, &block.filter, &block.pagination, &block.fields))
+    }
+}
+
+fn edge_key(edge: &Edge) -> &str {
+    match (&edge.alias, &edge.predicate) {
+        (Some(alias), _) => alias,
+        (None, Predicate::Forward(predicate)) => predicate,
+        (None, Predicate::Reverse(predicate)) => predicate,
+    }
+}
+
+fn parse_uid(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(uid) => u64::from_str_radix(uid.strip_prefix("0x")?, 16).ok(),
+        Value::Int(uid) => Some(*uid as u64),
+        Value::List(_) => None,
+    }
+}
+
+// Mirrors Dgraph's anyofterms and allofterms on a term index, matching case-insensitively on
+// whole words
+fn match_terms(content: &str, search_terms: &str, all: bool) -> bool {
+    let words = content
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let mut terms = search_terms.split_whitespace().map(str::to_lowercase);
+    if all {
+        terms.all(|term| words.contains(&term))
+    } else {
+        terms.any(|term| words.contains(&term))
+    }
+}
+
+fn unescape_literal(literal: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(escaped) => unescaped.push(escaped),
+                None => (),
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+// Storage kept entirely in memory, for running the API without a Dgraph instance. Queries are
+// evaluated directly against an in-memory graph, following Dgraph's semantics for the subset of
+// DQL that the API generates.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    graph: RwLock<Graph>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Loads the `set` block of an RDF mutation such as sample_data.dgraph. Only blank node
+    // subjects with blank node or string literal objects are supported.
+    pub fn from_rdf(rdf: &str) -> Result<Self, RdfParseError> {
+        lazy_static! {
+            static ref TRIPLE_REGEX: Regex = Regex::new(
+                r#"^_:(?P<subject>\w+)\s+<(?P<predicate>[\w.]+)>\s+(?:_:(?P<node>\w+)|"(?P<literal>(?:[^"\\]|\\.)*)")\s*\.$"#
+            )
+            .unwrap();
+        }
+        let mut graph = Graph::default();
+        let mut blank_nodes = HashMap::new();
+        for (index, line) in rdf.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line == "{" || line == "}" || line == "set {" {
+                continue;
+            }
+            let captures = TRIPLE_REGEX.captures(line).ok_or_else(|| RdfParseError {
+                line: index + 1,
+                msg: "expected a triple of the form `_:subject <predicate> object .`".to_owned(),
+            })?;
+            let mut blank_node = |name: &str, graph: &mut Graph| {
+                *blank_nodes
+                    .entry(name.to_owned())
+                    .or_insert_with(|| graph.insert_node())
+            };
+            let subject = blank_node(&captures["subject"], &mut graph);
+            let predicate = &captures["predicate"];
+            if let Some(object) = captures.name("node") {
+                let object = blank_node(object.as_str(), &mut graph);
+                graph
+                    .node_mut(subject)
+                    .edges
+                    .entry(predicate.to_owned())
+                    .or_default()
+                    .push(object);
+            } else {
+                let literal = unescape_literal(&captures["literal"]);
+                let node = graph.node_mut(subject);
+                if predicate == "dgraph.type" {
+                    node.types.push(literal);
+                } else if INT_PREDICATES.contains(&predicate) {
+                    let value = literal.parse::<i64>().map_err(|_| RdfParseError {
+                        line: index + 1,
+                        msg: format!("{} must be an integer", predicate),
+                    })?;
+                    node.values.insert(predicate.to_owned(), json!(value));
+                } else {
+                    node.values.insert(predicate.to_owned(), json!(literal));
+                }
+            }
+        }
+        Ok(Self {
+            graph: RwLock::new(graph),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn query(&self, query: &Query) -> Result<serde_json::Value, StorageError> {
+        let graph = self.graph.read().unwrap();
+        let mut json = serde_json::Map::new();
+        for block in &query.blocks {
+            json.insert(block.name.clone(), graph.block_json(block));
+        }
+        Ok(serde_json::Value::Object(json))
+    }
+
+    async fn add_haiku(&self, haiku: &NewHaiku) -> Result<String, StorageError> {
+        let mut graph = self.graph.write().unwrap();
+        let server = graph.upsert_by_snowflake(&haiku.server_snowflake, "DiscordServer");
+        let channel = graph.upsert_by_snowflake(&haiku.channel_snowflake, "DiscordChannel");
+        graph
+            .node_mut(channel)
+            .edges
+            .insert("server".to_owned(), vec![server]);
+        let authors = haiku
+            .author_snowflakes
+            .iter()
+            .map(|snowflake| graph.upsert_by_snowflake(snowflake, "DiscordUser"))
+            .collect();
+
+        let uid = graph.insert_node();
+        let node = graph.node_mut(uid);
+        node.types.push("Haiku".to_owned());
+        node.edges.insert("author".to_owned(), authors);
+        node.edges.insert("channel".to_owned(), vec![channel]);
+        node.values
+            .insert("content".to_owned(), json!(haiku.content));
+        node.values
+            .insert("rulesVersion".to_owned(), json!(haiku.rules_version));
+        node.values
+            .insert("timestamp".to_owned(), json!(haiku.timestamp));
+        Ok(format!("{:#x}", uid))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::dql::Order;
+    use super::*;
+    use futures::executor::block_on;
+    use rstest::rstest;
+
+    const RDF: &str = r#"{
+      set {
+        _:server <discordSnowflake> "1" .
+        _:server <dgraph.type> "DiscordServer" .
+        _:channel <discordSnowflake> "2" .
+        _:channel <server> _:server .
+        _:channel <dgraph.type> "DiscordChannel" .
+        _:user <discordSnowflake> "3" .
+        _:user <dgraph.type> "DiscordUser" .
+        _:first <content> "an old silent pond" .
+        _:first <rulesVersion> "1" .
+        _:first <timestamp> "2020-01-02T00:00:00+00:00" .
+        _:first <channel> _:channel .
+        _:first <author> _:user .
+        _:first <dgraph.type> "Haiku" .
+        _:second <content> "a frog jumps into \"the pond\"" .
+        _:second <rulesVersion> "2" .
+        _:second <timestamp> "2020-01-01T10:00:00+10:00" .
+        _:second <dgraph.type> "Haiku" .
+        _:third <content> "splash! silence again" .
+        _:third <rulesVersion> "2" .
+        _:third <timestamp> "2020-01-03T00:00:00+00:00" .
+        _:third <channel> _:channel .
+        _:third <dgraph.type> "Haiku" .
+      }
+    }"#;
+
+    fn query(block: Block) -> serde_json::Value {
+        let storage = MemoryStorage::from_rdf(RDF).unwrap();
+        let result = block_on(storage.query(&Query::new("test").block(block))).unwrap();
+        result["test"].clone()
+    }
+
+    fn haikus() -> Block {
+        Block::new("test", Function::Type("Haiku".to_owned())).fields(vec![Field::uid("id")])
+    }
+
+    fn ids(result: serde_json::Value) -> Vec<String> {
+        result
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["id"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[rstest(
+        filter,
+        expected,
+        case(Function::Eq("rulesVersion".to_owned(), 2.into()).into(), &["0x5", "0x6"]),
+        case(Function::Ge("timestamp".to_owned(), "2020-01-02T00:00:00+00:00".into()).into(), &["0x4", "0x6"]),
+        case(Function::Le("timestamp".to_owned(), "2020-01-01T00:00:00+00:00".into()).into(), &["0x5"]),
+        case(Function::AnyOfTerms("content".to_owned(), "POND splash".into()).into(), &["0x4", "0x5", "0x6"]),
+        case(Function::AllOfTerms("content".to_owned(), "silent pond".into()).into(), &["0x4"]),
+        case(Function::Uid(vec!["0x4".to_owned(), "0x6".to_owned()].into()).into(), &["0x4", "0x6"]),
+        case(
+            Filter::edge(
+                "channel",
+                Function::Eq("discordSnowflake".to_owned(), vec!["2".to_owned(), "9".to_owned()].into()),
+                Filter::type_of("DiscordChannel"),
+            ),
+            &["0x4", "0x6"]
+        ),
+        case(
+            Filter::path(
+                &["channel", "server"],
+                Function::Eq("discordSnowflake".to_owned(), "1".into()),
+                Filter::type_of("DiscordServer"),
+            )
+            .and(Function::Eq("rulesVersion".to_owned(), 1.into()).into()),
+            &["0x4"]
+        ),
+        case(
+            Filter::edge(
+                "channel",
+                Function::Eq("discordSnowflake".to_owned(), "2".into()),
+                Filter::type_of("DiscordServer"),
+            ),
+            &[]
+        )
+    )]
+    fn filter_nodes(filter: Filter, expected: &[&str]) {
+        assert_eq!(ids(query(haikus().filter(filter))), expected);
+    }
+
+    #[rstest(
+        pagination,
+        expected,
+        case(Pagination::default(), &["0x4", "0x5", "0x6"]),
+        case(Pagination { first: Some(2), ..Pagination::default() }, &["0x4", "0x5"]),
+        case(Pagination { first: Some(-2), ..Pagination::default() }, &["0x5", "0x6"]),
+        case(Pagination { first: Some(1), offset: Some(1), ..Pagination::default() }, &["0x5"]),
+        case(Pagination { offset: Some(5), ..Pagination::default() }, &[]),
+        // Timestamps are compared as instants, so the second haiku comes first despite its text
+        case(
+            Pagination {
+                order: Some(Order { predicate: "timestamp".to_owned(), descending: false }),
+                ..Pagination::default()
+            },
+            &["0x5", "0x4", "0x6"]
+        ),
+        // Ties keep uid order
+        case(
+            Pagination {
+                order: Some(Order { predicate: "rulesVersion".to_owned(), descending: true }),
+                first: Some(2),
+                ..Pagination::default()
+            },
+            &["0x5", "0x6"]
+        )
+    )]
+    fn paginate_and_order_nodes(pagination: Pagination, expected: &[&str]) {
+        assert_eq!(ids(query(haikus().pagination(pagination))), expected);
+    }
+
+    #[test]
+    fn follow_edges() {
+        let block = Block::new("test", Function::Uid("0x4".into())).fields(vec![
+            Field::value("content"),
+            Edge::new(Predicate::forward("channel"))
+                .fields(vec![Edge::new(Predicate::forward("server"))
+                    .fields(vec![Field::value("discordSnowflake")])
+                    .into()])
+                .into(),
+            Edge::new(Predicate::forward("author"))
+                .alias("authors")
+                .fields(vec![Field::value("discordSnowflake")])
+                .into(),
+            Field::count(
+                "haikuCount",
+                Predicate::reverse("channel"),
+                Filter::type_of("Haiku"),
+            ),
+            // Edges with no nodes are left out
+            Edge::new(Predicate::forward("missing"))
+                .fields(vec![Field::uid("id")])
+                .into(),
+        ]);
+        assert_eq!(
+            query(block),
+            json!([{
+                "content": "an old silent pond",
+                "channel": { "server": { "discordSnowflake": "1" } },
+                "authors": [{ "discordSnowflake": "3" }],
+                "haikuCount": 0,
+            }])
+        );
+        let block = Block::new(
+            "test",
+            Function::Eq("discordSnowflake".to_owned(), "2".into()),
+        )
+        .fields(vec![
+            Edge::new(Predicate::reverse("channel"))
+                .alias("haikus")
+                .pagination(Pagination {
+                    first: Some(1),
+                    ..Pagination::default()
+                })
+                .fields(vec![Field::uid("id")])
+                .into(),
+            Field::count(
+                "haikuCount",
+                Predicate::reverse("channel"),
+                Filter::type_of("Haiku"),
+            ),
+        ]);
+        assert_eq!(
+            query(block),
+            json!([{ "haikus": [{ "id": "0x4" }], "haikuCount": 2 }])
+        );
+    }
+
+    #[test]
+    fn count_block_nodes() {
+        let block = Block::new("test", Function::Type("Haiku".to_owned()))
+            .filter(Function::Eq("rulesVersion".to_owned(), 2.into()).into())
+            .fields(vec![Field::count_uid("count")]);
+        assert_eq!(query(block), json!([{ "count": 2 }]));
+    }
+
+    #[test]
+    fn parse_literals() {
+        let block = Block::new("test", Function::Uid("0x5".into()))
+            .fields(vec![Field::value("content"), Field::value("rulesVersion")]);
+        assert_eq!(
+            query(block),
+            json!([{ "content": "a frog jumps into \"the pond\"", "rulesVersion": 2 }])
+        );
+    }
+
+    #[rstest(
+        rdf,
+        line,
+        case("_:a <content> \"unterminated .", 1),
+        case("{\n  set {\n    <0x1> <content> \"not a blank node\" .\n  }\n}", 3),
+        case("_:a <rulesVersion> \"two\" .", 1),
+        case("_:a <content> \"no full stop\"", 1)
+    )]
+    fn reject_invalid_rdf(rdf: &str, line: usize) {
+        assert_eq!(MemoryStorage::from_rdf(rdf).unwrap_err().line, line);
+    }
+}
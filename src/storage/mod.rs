@@ -0,0 +1,29 @@
+mod circuit_breaker;
+mod dgraph_storage;
+mod memory_storage;
+mod query_cache;
+
+pub use dgraph_storage::DgraphStorage;
+pub use memory_storage::MemoryStorage;
+
+use super::dql::Query;
+use super::error::StorageError;
+use super::schema::NewHaiku;
+use async_trait::async_trait;
+
+// A backend which the GraphQL resolvers read from and write to. Reads are expressed as DQL queries
+// and results are returned as JSON in the shape Dgraph gives them, so the schema types can wrap
+// the result regardless of which backend produced it.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn query(&self, query: &Query) -> Result<serde_json::Value, StorageError>;
+
+    // Stores a validated haiku, creating any users, channel or server not yet known, and returns
+    // the id of the new haiku
+    async fn add_haiku(&self, haiku: &NewHaiku) -> Result<String, StorageError>;
+
+    // Checks the backend can serve requests, for backends which rely on something external
+    async fn check_ready(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
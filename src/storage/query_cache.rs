@@ -0,0 +1,369 @@
+use super::super::dql::{Field, Filter, Function, Predicate, Query, Value};
+use super::super::metrics::count_cache_lookup;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A rendered query with its whitespace collapsed, along with its variables in order
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    query: String,
+    vars: Vec<(String, String)>,
+}
+
+impl CacheKey {
+    pub fn new(query: &str, vars: &HashMap<String, String>) -> Self {
+        let mut vars: Vec<_> = vars
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        vars.sort();
+        Self {
+            query: query.split_whitespace().collect::<Vec<_>>().join(" "),
+            vars,
+        }
+    }
+}
+
+struct Entry {
+    result: serde_json::Value,
+    expires: Instant,
+    // The servers, channels and users the query's nodes are all narrowed down to, or None if it
+    // could reach nodes with no link to any of them, eg. when listing every haiku
+    snowflakes: Option<Vec<String>>,
+}
+
+struct CacheState {
+    entries: LruCache<CacheKey, Entry>,
+    // Bumped by every invalidation, so that results of queries which were already being made
+    // when something was written aren't cached
+    generation: u64,
+}
+
+// Keeps the results of recent queries, which are dropped after the TTL or once a haiku is added
+// which could change them
+pub struct QueryCache {
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl QueryCache {
+    pub fn new(max_entries: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(CacheState {
+                entries: LruCache::new(max_entries),
+                generation: 0,
+            }),
+        }
+    }
+
+    // The cached result if there is one, along with the generation to insert a fresh result with
+    pub fn get(&self, key: &CacheKey, now: Instant) -> (Option<serde_json::Value>, u64) {
+        let mut state = self.state.lock().unwrap();
+        let result = match state.entries.get(key) {
+            Some(entry) if entry.expires > now => Some(entry.result.clone()),
+            Some(_) => {
+                state.entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        count_cache_lookup(result.is_some());
+        (result, state.generation)
+    }
+
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        query: &Query,
+        result: &serde_json::Value,
+        generation: u64,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        let entry = Entry {
+            result: result.clone(),
+            expires: now + self.ttl,
+            snowflakes: scope(query),
+        };
+        state.entries.put(key, entry);
+    }
+
+    // Drops every result which could involve any of the given servers, channels or users
+    pub fn invalidate(&self, snowflakes: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        let stale: Vec<CacheKey> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.snowflakes.as_ref().is_none_or(|scope| {
+                    scope
+                        .iter()
+                        .any(|snowflake| snowflakes.contains(&snowflake.as_str()))
+                })
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            state.entries.pop(&key);
+        }
+    }
+}
+
+// The snowflakes every node the query fetches or counts is narrowed down to. Each block has to be
+// narrowed down itself, which also covers the lists along reverse edges from its nodes, eg. a
+// user's haikus. Lists further away, eg. the haikus in a user's haikus' channels, have to be
+// narrowed down by their own filters.
+fn scope(query: &Query) -> Option<Vec<String>> {
+    let mut snowflakes = Vec::new();
+    for block in &query.blocks {
+        let mut block_snowflakes = Vec::new();
+        function_snowflakes(&block.func, &mut block_snowflakes);
+        if let Some(filter) = &block.filter {
+            filter_snowflakes(filter, &mut block_snowflakes);
+        }
+        if block_snowflakes.is_empty() {
+            return None;
+        }
+        snowflakes.append(&mut block_snowflakes);
+        fields_snowflakes(&block.fields, true, &mut snowflakes)?;
+    }
+    snowflakes.sort();
+    snowflakes.dedup();
+    Some(snowflakes)
+}
+
+fn fields_snowflakes(
+    fields: &[Field],
+    top_level: bool,
+    snowflakes: &mut Vec<String>,
+) -> Option<()> {
+    for field in fields {
+        match field {
+            Field::Edge(edge) => {
+                if let Predicate::Reverse(_) = edge.predicate {
+                    if !top_level {
+                        list_snowflakes(&edge.filter, snowflakes)?;
+                    }
+                }
+                fields_snowflakes(&edge.fields, false, snowflakes)?;
+            }
+            Field::Count {
+                predicate: Predicate::Reverse(_),
+                filter,
+                ..
+            } if !top_level => list_snowflakes(filter, snowflakes)?,
+            _ => (),
+        }
+    }
+    Some(())
+}
+
+fn list_snowflakes(filter: &Option<Filter>, snowflakes: &mut Vec<String>) -> Option<()> {
+    let mut list_snowflakes = Vec::new();
+    if let Some(filter) = filter {
+        filter_snowflakes(filter, &mut list_snowflakes);
+    }
+    if list_snowflakes.is_empty() {
+        return None;
+    }
+    snowflakes.append(&mut list_snowflakes);
+    Some(())
+}
+
+fn function_snowflakes(function: &Function, snowflakes: &mut Vec<String>) {
+    if let Function::Eq(predicate, value) = function {
+        if predicate == "discordSnowflake" {
+            value_strings(value, snowflakes);
+        }
+    }
+}
+
+// Only filters which every node has to pass narrow it down, so those under OR or NOT are skipped
+fn filter_snowflakes(filter: &Filter, snowflakes: &mut Vec<String>) {
+    match filter {
+        Filter::Type(_) => (),
+        Filter::Function(function) => function_snowflakes(function, snowflakes),
+        Filter::And(filters) => filters
+            .iter()
+            .for_each(|filter| filter_snowflakes(filter, snowflakes)),
+        Filter::Edge { func, .. } => function_snowflakes(func, snowflakes),
+        Filter::Or(_) | Filter::Not(_) | Filter::Var(_) => (),
+    }
+}
+
+fn value_strings(value: &Value, strings: &mut Vec<String>) {
+    match value {
+        Value::String(string) => strings.push(string.clone()),
+        Value::Int(int) => strings.push(int.to_string()),
+        Value::List(values) => values
+            .iter()
+            .for_each(|value| value_strings(value, strings)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::dql::{render, Block, Edge};
+    use super::*;
+
+    fn cache(max_entries: usize) -> QueryCache {
+        QueryCache::new(
+            NonZeroUsize::new(max_entries).unwrap(),
+            Duration::from_secs(30),
+        )
+    }
+
+    fn server_query(snowflake: &str) -> Query {
+        Query::new("server").block(
+            Block::new(
+                "server",
+                Function::Eq("discordSnowflake".to_owned(), snowflake.into()),
+            )
+            .filter(Filter::type_of("DiscordServer")),
+        )
+    }
+
+    // Caches an empty result for the query, returning the key it was cached under
+    fn insert(cache: &QueryCache, query: &Query, generation: u64, now: Instant) -> CacheKey {
+        let (text, vars) = render(query);
+        let key = CacheKey::new(&text, &vars);
+        cache.insert(key.clone(), query, &json!({}), generation, now);
+        key
+    }
+
+    #[test]
+    fn expire_and_evict_entries() {
+        let cache = cache(2);
+        let now = Instant::now();
+        let vars = HashMap::new();
+        let key = CacheKey::new(
+            "query haikus {\n  haikus(func: type(Haiku)) {\n  }\n}",
+            &vars,
+        );
+        let (result, generation) = cache.get(&key, now);
+        assert_eq!(result, None);
+        cache.insert(
+            key,
+            &Query::new("haikus"),
+            &json!({ "haikus": [] }),
+            generation,
+            now,
+        );
+        let normalized = CacheKey::new("query haikus { haikus(func: type(Haiku)) { } }", &vars);
+        assert_eq!(cache.get(&normalized, now).0, Some(json!({ "haikus": [] })));
+        assert_eq!(
+            cache.get(&normalized, now + Duration::from_secs(30)).0,
+            None
+        );
+
+        let keys: Vec<_> = ["1", "2", "3"]
+            .iter()
+            .map(|snowflake| insert(&cache, &server_query(snowflake), generation, now))
+            .collect();
+        assert_eq!(cache.get(&keys[0], now).0, None);
+        assert!(cache.get(&keys[2], now).0.is_some());
+    }
+
+    fn user_haikus(snowflake: &str) -> Query {
+        Query::new("haikus").block(
+            Block::new("haikus", Function::Type("Haiku".to_owned())).filter(
+                Filter::type_of("Haiku").and(Filter::edge(
+                    "author",
+                    Function::Eq("discordSnowflake".to_owned(), snowflake.into()),
+                    Filter::type_of("DiscordUser"),
+                )),
+            ),
+        )
+    }
+
+    #[test]
+    fn invalidate_affected_entries() {
+        let cache = cache(10);
+        let now = Instant::now();
+        let haikus = insert(
+            &cache,
+            &Query::new("haikus").block(
+                Block::new("haikus", Function::Type("Haiku".to_owned()))
+                    .filter(Function::Ge("rulesVersion".to_owned(), 5.into()).into()),
+            ),
+            0,
+            now,
+        );
+        let server = insert(&cache, &server_query("1"), 0, now);
+        let other_server = insert(&cache, &server_query("5"), 0, now);
+        let user_haikus = insert(&cache, &user_haikus("3"), 0, now);
+
+        cache.invalidate(&["1", "2", "4"]);
+        assert_eq!(cache.get(&haikus, now).0, None);
+        assert_eq!(cache.get(&server, now).0, None);
+        assert!(cache.get(&other_server, now).0.is_some());
+        assert!(cache.get(&user_haikus, now).0.is_some());
+        cache.invalidate(&["3"]);
+        assert_eq!(cache.get(&user_haikus, now).0, None);
+        assert!(cache.get(&other_server, now).0.is_some());
+
+        // Results of queries made before a write are stale
+        let server = insert(&cache, &server_query("1"), 1, now);
+        assert_eq!(cache.get(&server, now).0, None);
+        let server = insert(&cache, &server_query("1"), 2, now);
+        assert!(cache.get(&server, now).0.is_some());
+    }
+
+    #[test]
+    fn scope_queries() {
+        let batch = server_query("1").block(
+            Block::new(
+                "user",
+                Function::Eq(
+                    "discordSnowflake".to_owned(),
+                    vec!["4".to_owned(), "3".to_owned()].into(),
+                ),
+            )
+            .filter(Filter::type_of("DiscordUser"))
+            .fields(vec![Edge::new(Predicate::reverse("author"))
+                .filter(Filter::type_of("Haiku"))
+                .into()]),
+        );
+        assert_eq!(
+            scope(&batch),
+            Some(vec!["1".to_owned(), "3".to_owned(), "4".to_owned()])
+        );
+        let unscoped = batch
+            .clone()
+            .block(Block::new("haiku", Function::Uid("0x5".into())));
+        assert_eq!(scope(&unscoped), None);
+    }
+
+    // Lists reached through other nodes can only be scoped by their own filters, eg. the haikus in
+    // the channels a user's haikus are in
+    #[test]
+    fn scope_nested_lists() {
+        let channel_haikus = |filter: Filter| {
+            let mut query = user_haikus("3");
+            query.blocks[0].fields = vec![Edge::new(Predicate::forward("channel"))
+                .fields(vec![Edge::new(Predicate::reverse("channel"))
+                    .filter(filter)
+                    .into()])
+                .into()];
+            query
+        };
+        assert_eq!(scope(&channel_haikus(Filter::type_of("Haiku"))), None);
+        let member_of = Filter::type_of("Haiku").and(Filter::path(
+            &["channel", "server"],
+            Function::Eq("discordSnowflake".to_owned(), "1".into()),
+            Filter::type_of("DiscordServer"),
+        ));
+        assert_eq!(
+            scope(&channel_haikus(member_of)),
+            Some(vec!["1".to_owned(), "3".to_owned()])
+        );
+    }
+}
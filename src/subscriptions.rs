@@ -0,0 +1,234 @@
+use super::auth;
+use super::config::Config;
+use super::events::HaikuEvents;
+use super::schema::{Context, Schema};
+use super::storage::Storage;
+use actix::{Actor, ActorContext, ActorFuture, AsyncContext, StreamHandler, WrapFuture};
+use actix_web::http::{header, HeaderValue};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use juniper::{DefaultScalarValue, InputValue, Variables};
+use juniper_graphql_ws::{
+    ClientMessage, Connection, ConnectionConfig, ConnectionErrorPayload, ServerMessage,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// The subprotocol of Apollo's subscriptions-transport-ws, which clients ask for when connecting
+const GRAPHQL_WS: &str = "graphql-ws";
+// Pings keep idle sockets from being closed by proxies, in place of graphql-ws keep alives
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+type Message = ClientMessage<DefaultScalarValue>;
+
+// Browsers can't set headers on WebSockets, so clients authenticate by putting the Authorization
+// header in the connection_init payload instead, falling back to the header of the request
+fn authorization(params: &Variables, header: Option<HeaderValue>) -> Option<HeaderValue> {
+    match params
+        .get("Authorization")
+        .or_else(|| params.get("authorization"))
+        .and_then(InputValue::as_string_value)
+    {
+        Some(authorization) => HeaderValue::from_str(authorization).ok(),
+        None => header,
+    }
+}
+
+// Serves GraphQL over the graphql-ws protocol, which subscriptions are made through
+pub async fn subscriptions(
+    st: web::Data<Arc<Schema>>,
+    storage: web::Data<Arc<dyn Storage>>,
+    events: web::Data<Arc<HaikuEvents>>,
+    config: web::Data<Arc<Config>>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let header = req.headers().get(header::AUTHORIZATION).cloned();
+    let storage = storage.get_ref().clone();
+    let events = events.get_ref().clone();
+    let config = config.get_ref().clone();
+    let spent_cost = Arc::new(AtomicU64::default());
+    let context_cost = spent_cost.clone();
+    let init = move |params: Variables| {
+        let result = auth::authenticate(&config.auth, authorization(&params, header).as_ref()).map(
+            |caller| {
+                let context = Context {
+                    spent_cost: context_cost,
+                    ..super::request_context(storage, events, &config, caller)
+                };
+                // Keep alives need a timer from a newer version of tokio than actix runs on
+                ConnectionConfig::new(context).with_keep_alive_interval(Duration::from_secs(0))
+            },
+        );
+        if let Err(err) = &result {
+            warn!("Rejected subscription connection - {}", err);
+        }
+        future::ready(result)
+    };
+    let connection = Connection::new(st.get_ref().clone(), init);
+    ws::start_with_protocols(
+        SubscriptionSocket::new(connection, spent_cost),
+        &[GRAPHQL_WS],
+        &req,
+        payload,
+    )
+}
+
+// The connection's context is shared by all of its operations, so the cost spent on it is reset
+// as each one starts, giving every operation its own budget
+fn start_operation(message: &Message, spent_cost: &AtomicU64) {
+    if let ClientMessage::Start { .. } = message {
+        spent_cost.store(0, Ordering::Relaxed);
+    }
+}
+
+// Passes messages between the WebSocket and the graphql-ws connection
+struct SubscriptionSocket<C: Sink<Message>> {
+    // Taken while a message is being sent, so that messages are handled in order
+    sink: Option<SplitSink<C, Message>>,
+    replies: Option<SplitStream<C>>,
+    spent_cost: Arc<AtomicU64>,
+}
+
+impl<C> SubscriptionSocket<C>
+where
+    C: Sink<Message> + Stream<Item = ServerMessage<DefaultScalarValue>>,
+{
+    fn new(connection: C, spent_cost: Arc<AtomicU64>) -> Self {
+        let (sink, replies) = connection.split();
+        Self {
+            sink: Some(sink),
+            replies: Some(replies),
+            spent_cost,
+        }
+    }
+}
+
+impl<C> Actor for SubscriptionSocket<C>
+where
+    C: Sink<Message> + Stream<Item = ServerMessage<DefaultScalarValue>> + Unpin + 'static,
+{
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(replies) = self.replies.take() {
+            ctx.add_stream(replies);
+        }
+        ctx.run_interval(PING_INTERVAL, |_, ctx| ctx.ping(b""));
+    }
+}
+
+impl<C> StreamHandler<Result<ws::Message, ws::ProtocolError>> for SubscriptionSocket<C>
+where
+    C: Sink<Message> + Stream<Item = ServerMessage<DefaultScalarValue>> + Unpin + 'static,
+{
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => {
+                let message = match serde_json::from_str::<Message>(&text) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        let reply = ServerMessage::<DefaultScalarValue>::ConnectionError {
+                            payload: ConnectionErrorPayload {
+                                message: err.to_string(),
+                            },
+                        };
+                        ctx.text(serde_json::to_string(&reply).unwrap());
+                        return;
+                    }
+                };
+                start_operation(&message, &self.spent_cost);
+                if let Some(mut sink) = self.sink.take() {
+                    // Other messages wait until this one has been handled
+                    ctx.wait(
+                        async move {
+                            let _ = sink.send(message).await;
+                            sink
+                        }
+                        .into_actor(self)
+                        .map(|sink, socket, _| socket.sink = Some(sink)),
+                    );
+                }
+            }
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Pong(_)) => (),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {
+                ctx.close(Some(ws::CloseCode::Unsupported.into()));
+                ctx.stop();
+            }
+            Err(err) => {
+                warn!("WebSocket error - {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl<C> StreamHandler<ServerMessage<DefaultScalarValue>> for SubscriptionSocket<C>
+where
+    C: Sink<Message> + Stream<Item = ServerMessage<DefaultScalarValue>> + Unpin + 'static,
+{
+    fn handle(&mut self, msg: ServerMessage<DefaultScalarValue>, ctx: &mut Self::Context) {
+        match serde_json::to_string(&msg) {
+            Ok(text) => ctx.text(text),
+            Err(err) => error!("Unable to serialize subscription message - {}", err),
+        }
+    }
+
+    // The connection ends when the client terminates it or fails to authenticate
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(None);
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        payload,
+        header,
+        expected,
+        case(json!({ "Authorization": "Bearer payload-key" }), None, Some("Bearer payload-key")),
+        case(json!({ "authorization": "Bearer payload-key" }), Some("Bearer header-key"), Some("Bearer payload-key")),
+        case(json!({}), Some("Bearer header-key"), Some("Bearer header-key")),
+        case(json!({ "Authorization": 42 }), None, None)
+    )]
+    fn choose_authorization(
+        payload: serde_json::Value,
+        header: Option<&str>,
+        expected: Option<&str>,
+    ) {
+        let params: Variables = serde_json::from_value(payload).unwrap();
+        let header = header.map(|header| HeaderValue::from_str(header).unwrap());
+        assert_eq!(
+            authorization(&params, header),
+            expected.map(|expected| HeaderValue::from_str(expected).unwrap())
+        );
+    }
+
+    #[test]
+    fn reset_cost_for_each_operation() {
+        let spent_cost = AtomicU64::new(500);
+        let stop = serde_json::from_value(json!({ "type": "stop", "id": "1" })).unwrap();
+        start_operation(&stop, &spent_cost);
+        assert_eq!(spent_cost.load(Ordering::Relaxed), 500);
+        let start = serde_json::from_value(json!({
+            "type": "start",
+            "id": "2",
+            "payload": { "query": "subscription { haikuAdded(serverSnowflake: \"1\") { id } }" },
+        }))
+        .unwrap();
+        start_operation(&start, &spent_cost);
+        assert_eq!(spent_cost.load(Ordering::Relaxed), 0);
+    }
+}